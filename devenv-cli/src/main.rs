@@ -27,10 +27,15 @@ mod options;
 extern crate log;
 extern crate simple_logger;
 
-use std::fs;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use devenv_core::configuration::Configuration;
-use devenv_core::devenv::DevEnv;
-use crate::options::{Options,SubCommand};
+use devenv_core::copy::CopyLocation;
+use devenv_core::devenv::{changes_to_json, ArchiveKind, Change, Compression, DevEnv, DiskUsage, EntryDetails, EntryKind, ServiceStatus, Snapshot, TaskOutput};
+use crate::options::{ImageCommand, ImageName, Options, ServicesCommand, SnapshotCommand, SubCommand};
 use clap::derive::Clap;
 
 fn main() {
@@ -43,20 +48,204 @@ fn main() {
 
     debug!("{:?}", options);
 
+    // The images and layers of the user do not depend on the configuration
+    if let SubCommand::Image(image) = &options.subcmd {
+        if !matches!(image.command, ImageCommand::Build(_)) {
+            std::process::exit(manage_images(&image.command));
+        }
+    }
+
     let contents = fs::read_to_string(options.file).expect("Cannot read the contents of the file");
-    let config: Configuration = toml::from_str(contents.as_str()).unwrap();
+    let config: Configuration = match toml::from_str(contents.as_str()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
     
     debug!("{:?}", config);
+
+    // Images are built before the DevEnv resolves its image
+    if let SubCommand::Image(image) = &options.subcmd {
+        match &image.command {
+            ImageCommand::Build(build) => {
+                match DevEnv::build_image(&config, build.rebuild) {
+                    Ok(image) => println!("Built the image {} in {:?}", image.name, image.path),
+                    Err(e) => {
+                        error!("Could not build the image: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => unreachable!()
+        }
+        std::process::exit(0);
+    }
     
     let mut devenv = DevEnv::from(config);
     info!("devenv location: {}", devenv.location().unwrap());
-    devenv.create().unwrap();
+    let attached = devenv.attach().expect("Could not attach to the DevEnv");
+    if !matches!(options.subcmd, SubCommand::Stop) {
+        if let Err(e) = devenv.resolve_image() {
+            error!("Could not resolve the image of the DevEnv: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    devenv.resolve_dependencies().expect("Could not resolve dependencies");
+    // Commands that do not need the DevEnv to be running
+    match &options.subcmd {
+        SubCommand::Stop => {
+            if !attached {
+                error!("The DevEnv is not running");
+                std::process::exit(1);
+            }
+            devenv.exit().unwrap();
+            std::process::exit(0);
+        }
+        SubCommand::Cp(cp) => {
+            let source: CopyLocation = cp.source.parse().expect("Invalid source");
+            let destination: CopyLocation = cp.destination.parse().expect("Invalid destination");
+            if let Err(e) = devenv.copy(&source, &destination) {
+                error!("Could not copy {} to {}: {}", cp.source, cp.destination, e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Services(services) => {
+            if let ServicesCommand::Logs(service) = &services.command {
+                let log = match devenv.service_log(&service.name) {
+                    Ok(log) => log,
+                    Err(e) => {
+                        error!("Could not read the log of service {}: {}", service.name, e);
+                        std::process::exit(1);
+                    }
+                };
+                match fs::read(&log) {
+                    Ok(contents) => io::stdout().write_all(&contents).unwrap(),
+                    Err(e) => {
+                        error!("Could not read {:?}: {}", log, e);
+                        std::process::exit(1);
+                    }
+                }
+                std::process::exit(0);
+            }
+            if !attached {
+                error!("The DevEnv is not running");
+                std::process::exit(1);
+            }
+            let statuses = match &services.command {
+                ServicesCommand::Restart(service) => devenv.restart_service(&service.name),
+                _ => devenv.service_status()
+            };
+            match statuses {
+                Ok(statuses) => print_services(&statuses),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Snapshot(snapshot) => {
+            let result = match &snapshot.command {
+                SnapshotCommand::Create(snapshot) => devenv.create_snapshot(&snapshot.name).map(|_| ()),
+                SnapshotCommand::List => devenv.list_snapshots().map(|snapshots| print_snapshots(&snapshots)),
+                SnapshotCommand::Restore(snapshot) => devenv.restore_snapshot(&snapshot.name),
+                SnapshotCommand::Delete(snapshot) => devenv.delete_snapshot(&snapshot.name)
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Commit(commit) => {
+            match devenv.commit(&commit.name, &contents) {
+                Ok(layer) => println!("Committed the layer {} to {:?}", layer.name, layer.path),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Export(export) => {
+            let kind = if export.upper { ArchiveKind::Upper } else { ArchiveKind::Rootfs };
+            let compression: Compression = export.compress.parse().expect("Invalid compression");
+            let output = Some(Path::new(&export.output)).filter(|output| *output != Path::new("-"));
+            if let Err(e) = devenv.export(kind, output, compression, &contents) {
+                error!("Could not export the DevEnv: {}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Diff(diff) => {
+            let changes = match devenv.diff(diff.contents) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    error!("Could not diff the DevEnv: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if diff.json {
+                match changes_to_json(&changes) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            else {
+                print_changes(&changes);
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Du => {
+            match devenv.disk_usage() {
+                Ok(usage) => print_disk_usage(&usage),
+                Err(e) => {
+                    error!("Could not measure the DevEnv: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            std::process::exit(0);
+        }
+        SubCommand::Import(import) => {
+            if let Err(e) = devenv.import(Path::new(&import.archive), import.layer.as_deref()) {
+                error!("Could not import {}: {}", import.archive, e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        _ => {}
+    }
+
+    if attached {
+        info!("Attached to the running DevEnv");
+    }
+    else {
+        if let Err(e) = devenv.create() {
+            error!("Could not create the DevEnv: {}", e);
+            std::process::exit(1);
+        }
+        devenv.resolve_dependencies().expect("Could not resolve dependencies");
+        if options.boot {
+            // The services would not be supervised once the init is replaced
+            if devenv.has_services() {
+                warn!("Services are not started when booting the DevEnv");
+            }
+        }
+        else {
+            devenv.start_services().expect("Could not start the services");
+        }
+    }
 
-    match options.subcmd {
+    let status = match options.subcmd {
         SubCommand::Delete => {
-            devenv.destroy().unwrap()
+            devenv.destroy().unwrap();
+            0
         }
         SubCommand::Run(run) => {
             if options.boot {
@@ -64,15 +253,213 @@ fn main() {
             }
             let args = run.command;
             let command = args[0].clone();
-            devenv.run(command, args).unwrap()
+            if run.log {
+                run_logged(&devenv, command, args)
+            }
+            else {
+                exit_status(devenv.run(command, args))
+            }
+        }
+        SubCommand::Task(task) => {
+            if options.boot {
+                devenv.boot().expect("Could not boot the container");
+            }
+            exit_status(devenv.run_task(&task.name))
         }
         SubCommand::Shell => {
             if options.boot {
                 devenv.boot().expect("Could not boot the container");
             }
-            devenv.open_shell().unwrap()
+            exit_status(devenv.open_shell())
         }
+        SubCommand::Start => {
+            if attached {
+                warn!("The DevEnv is already running");
+                std::process::exit(0);
+            }
+            if options.boot {
+                devenv.boot().expect("Could not boot the container");
+            }
+            // Runs until stopped through the control socket or by a signal
+            devenv.wait_for_container().unwrap();
+            std::process::exit(0);
+        }
+        SubCommand::Stop | SubCommand::Cp(_) | SubCommand::Services(_) | SubCommand::Snapshot(_)
+            | SubCommand::Commit(_) | SubCommand::Export(_) | SubCommand::Import(_) | SubCommand::Image(_)
+            | SubCommand::Diff(_) | SubCommand::Du => unreachable!()
+    };
+
+    // The commands only see that writes fail
+    if status != 0 && devenv.is_full() {
+        error!("The DevEnv ran out of space, free some or raise its max_size. See what uses it with `devenv du`");
+    }
+
+    // A DevEnv created by another process keeps running
+    if !attached {
+        // The container is already gone if it was killed after a signal
+        if let Err(e) = devenv.exit() {
+            debug!("Could not stop the container: {}", e);
+        }
+        devenv.wait_for_container().unwrap();
+    }
+    std::process::exit(status);
+}
+
+/// Run a command, writing its output both to the terminal and to the log file of the DevEnv
+fn run_logged(devenv: &DevEnv, command: String, args: Vec<String>) -> i32 {
+    let mut log = OpenOptions::new().create(true).append(true).open(devenv.log_file()).expect("Cannot open the log file");
+    let task = match devenv.run_captured(command, args) {
+        Ok(task) => task,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+    let result = devenv.wait_for_task(task, |output| {
+        let data = match output {
+            TaskOutput::Stdout(data) => {
+                io::stdout().write_all(&data).unwrap();
+                data
+            }
+            TaskOutput::Stderr(data) => {
+                io::stderr().write_all(&data).unwrap();
+                data
+            }
+        };
+        if let Err(e) = log.write_all(&data) {
+            warn!("Could not write to the log file: {}", e);
+        }
+    });
+    exit_status(result.and_then(|result| result.exit_status()))
+}
+
+/// Exit status of a command run in the DevEnv, or 1 after reporting why it could not run
+fn exit_status<E: Display>(result: Result<i32, E>) -> i32 {
+    match result {
+        Ok(status) => status,
+        Err(e) => {
+            error!("{}", e);
+            1
+        }
+    }
+}
+
+fn print_changes(changes: &[Change]) {
+    for change in changes {
+        println!("{} {}", change.kind.symbol(), change.path.display());
+        if let Some(diff) = &change.diff {
+            print!("{}", diff);
+        }
+    }
+}
+
+fn print_disk_usage(usage: &DiskUsage) {
+    for (path, size) in &usage.entries {
+        println!("{:>10}  {}", human_size(*size), path.display());
+    }
+    println!("{:>10}  total", human_size(usage.total));
+    if let Some(storage) = &usage.storage {
+        println!("{} of {} used in the filesystem of the DevEnv, {} available", human_size(storage.used), human_size(storage.size), human_size(storage.available));
+    }
+}
+
+fn manage_images(command: &ImageCommand) -> i32 {
+    let kind = |image: &ImageName| if image.layer { Some(EntryKind::Layer) } else { None };
+    let result = match command {
+        ImageCommand::List => DevEnv::list_images().map(|images| print_images(&images)),
+        ImageCommand::Inspect(image) => DevEnv::inspect_image(&image.name, kind(image)).map(|details| print_image(&details)),
+        ImageCommand::Rm(remove) => DevEnv::remove_image(&remove.image.name, kind(&remove.image), remove.force)
+            .map(|entry| println!("Deleted the {} {}", entry.kind, entry.name)),
+        ImageCommand::Prune(prune) => DevEnv::prune_images(prune.dry_run).map(|entries| {
+            let verb = if prune.dry_run { "Would delete" } else { "Deleted" };
+            for entry in &entries {
+                println!("{} the {} {}", verb, entry.kind, entry.name);
+            }
+        }),
+        ImageCommand::Build(_) => unreachable!()
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            1
+        }
+    }
+}
+
+fn print_images(images: &[EntryDetails]) {
+    println!("{:<30} {:<10} {:>10} {:>12}  USED BY", "NAME", "TYPE", "SIZE", "CREATED");
+    for details in images {
+        let entry = &details.entry;
+        // Digests are too long for the column
+        let name = match entry.kind {
+            EntryKind::OciLayer => entry.name.chars().take(19).collect(),
+            _ => entry.name.clone()
+        };
+        println!("{:<30} {:<10} {:>10} {:>12}  {}", name, entry.kind.to_string(), human_size(details.size), age(entry.created), details.environments.len());
+    }
+}
+
+fn print_image(details: &EntryDetails) {
+    let entry = &details.entry;
+    println!("Name:      {}", entry.name);
+    println!("Type:      {}", entry.kind);
+    println!("Path:      {}", entry.path.display());
+    println!("Created:   {}", age(entry.created));
+    println!("OS:        {}", details.os_release.as_deref().unwrap_or("unknown"));
+    println!("Size:      {} ({} with the layers below)", human_size(details.size), human_size(details.total_size));
+    if let Some(image) = &details.image {
+        println!("Suite:     {} ({})", image.suite, image.architecture);
+        println!("Mirror:    {}", image.mirror);
+        println!("Packages:  {}", image.packages.len());
+    }
+    println!("Layers:");
+    for layer in entry.parents.iter().chain(std::iter::once(&entry.path)) {
+        println!("  {}", layer.display());
+    }
+    println!("Used by:");
+    for environment in &details.environments {
+        println!("  {}", environment.display());
     }
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn age(created: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    let age = now.saturating_sub(created);
+    match age {
+        0..=59 => format!("{}s ago", age),
+        60..=3599 => format!("{}m ago", age / 60),
+        3600..=86399 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400)
+    }
+}
+
+fn print_services(statuses: &[ServiceStatus]) {
+    println!("{:<20} {:<12} {:>8} {:>9} {:>10}", "NAME", "STATE", "PID", "RESTARTS", "LAST EXIT");
+    for status in statuses {
+        let pid = status.pid.map(|pid| pid.to_string()).unwrap_or_else(|| "-".to_owned());
+        let last_status = status.last_status.map(|status| status.to_string()).unwrap_or_else(|| "-".to_owned());
+        println!("{:<20} {:<12} {:>8} {:>9} {:>10}", status.name, status.state.to_string(), pid, status.restarts, last_status);
+    }
+}
 
-    devenv.wait_for_container().unwrap();
+fn print_snapshots(snapshots: &[Snapshot]) {
+    println!("{:<30} {:>12}", "NAME", "CREATED");
+    for snapshot in snapshots {
+        println!("{:<30} {:>12}", snapshot.name, age(snapshot.created));
+    }
 }
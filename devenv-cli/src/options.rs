@@ -45,11 +45,194 @@ pub enum SubCommand {
     #[clap(about = "Run a command inside the DevEnv")]
     Run(Run),
     #[clap(about = "Open a shell inside the DevEnv")]
-    Shell
+    Shell,
+    #[clap(about = "Start the DevEnv and keep it running until it is stopped")]
+    Start,
+    #[clap(about = "Stop a running DevEnv")]
+    Stop,
+    #[clap(about = "Copy files between the host and the DevEnv")]
+    Cp(Cp),
+    #[clap(about = "Run a task declared in the configuration, after the tasks it depends on")]
+    Task(Task),
+    #[clap(about = "Manage the services of a running DevEnv")]
+    Services(Services),
+    #[clap(about = "Save and restore the changes made to the DevEnv")]
+    Snapshot(SnapshotOptions),
+    #[clap(about = "Turn the changes made to the DevEnv into a layer that other DevEnvs can use")]
+    Commit(Commit),
+    #[clap(about = "Write the DevEnv to a tar archive")]
+    Export(Export),
+    #[clap(about = "Import a DevEnv from an archive made with export")]
+    Import(Import),
+    #[clap(about = "Manage the images of the user")]
+    Image(ImageOptions),
+    #[clap(about = "Show the files changed in the DevEnv, relative to its image")]
+    Diff(Diff),
+    #[clap(about = "Show the space used by the changes made to the DevEnv")]
+    Du
 }
 
 #[derive(Debug)]
 #[derive(Clap)]
 pub struct Run {
+    #[clap(long, short, about = "Also write the output of the command to the DevEnv's log file")]
+    pub log: bool,
     pub command: Vec<String>
-}
\ No newline at end of file
+}
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Cp {
+    #[clap(about = "Path to copy from: env:PATH, host:PATH or - to read a tar archive from stdin")]
+    pub source: String,
+    #[clap(about = "Path to copy to: env:PATH, host:PATH or - to write a tar archive to stdout")]
+    pub destination: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Task {
+    #[clap(about = "Name of the task")]
+    pub name: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Services {
+    #[clap(subcommand)]
+    pub command: ServicesCommand
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub enum ServicesCommand {
+    #[clap(about = "Show the state of the services")]
+    Status,
+    #[clap(about = "Restart a service")]
+    Restart(Service),
+    #[clap(about = "Show the log of a service")]
+    Logs(Service)
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Service {
+    #[clap(about = "Name of the service")]
+    pub name: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct SnapshotOptions {
+    #[clap(subcommand)]
+    pub command: SnapshotCommand
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub enum SnapshotCommand {
+    #[clap(about = "Save the changes made to the DevEnv")]
+    Create(Snapshot),
+    #[clap(about = "List the snapshots")]
+    List,
+    #[clap(about = "Discard the changes made since a snapshot. The DevEnv must be stopped")]
+    Restore(Snapshot),
+    #[clap(about = "Delete a snapshot")]
+    Delete(Snapshot)
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Snapshot {
+    #[clap(about = "Name of the snapshot")]
+    pub name: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Commit {
+    #[clap(about = "Name of the layer, to reference it from the image of a configuration")]
+    pub name: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Export {
+    #[clap(long, about = "Only export the changes made to the image, instead of the whole root filesystem")]
+    pub upper: bool,
+    #[clap(long, short, default_value = "none", possible_values = &["none", "gzip", "zstd"], about = "Compression of the archive")]
+    pub compress: String,
+    #[clap(about = "Archive to write, or - to write it to stdout")]
+    pub output: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Import {
+    #[clap(long, about = "Name of the layer a root filesystem is imported as")]
+    pub layer: Option<String>,
+    #[clap(about = "Archive to import, it may be compressed with gzip or zstd")]
+    pub archive: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Diff {
+    #[clap(long, short = "p", about = "Show the changes of the contents of text files")]
+    pub contents: bool,
+    #[clap(long, about = "Write the changes as JSON")]
+    pub json: bool
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct ImageOptions {
+    #[clap(subcommand)]
+    pub command: ImageCommand
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub enum ImageCommand {
+    #[clap(about = "Bootstrap the image named in the configuration from its mirror")]
+    Build(Build),
+    #[clap(about = "List the images and layers")]
+    List,
+    #[clap(about = "Show the details of an image or a layer")]
+    Inspect(ImageName),
+    #[clap(about = "Delete an image or a layer")]
+    Rm(Remove),
+    #[clap(about = "Delete the images and layers not used by any DevEnv")]
+    Prune(Prune)
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Build {
+    #[clap(long, about = "Build the image again if it already exists")]
+    pub rebuild: bool
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct ImageName {
+    #[clap(long, about = "Look for a layer instead of an image with the same name")]
+    pub layer: bool,
+    #[clap(about = "Name of the image or the layer, or the digest of a layer of an OCI image")]
+    pub name: String
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Remove {
+    #[clap(long, short, about = "Delete it even if a DevEnv or another layer uses it")]
+    pub force: bool,
+    #[clap(flatten)]
+    pub image: ImageName
+}
+
+#[derive(Debug)]
+#[derive(Clap)]
+pub struct Prune {
+    #[clap(long, about = "Only show what would be deleted")]
+    pub dry_run: bool
+}
@@ -0,0 +1,71 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Unmount the DevEnv of the test, which stays mounted after devenv exits
+fn unmount(dir: &Path) {
+    let _ = Command::new("umount").arg("-R").arg("-l").arg(dir.join(".devenv/merge")).status();
+}
+
+#[test]
+fn sigterm_reaches_a_running_command() {
+    // Creating a DevEnv needs root
+    if fs::metadata("/proc/self").map(|metadata| metadata.uid() != 0).unwrap_or(true) {
+        eprintln!("Skipping, not running as root");
+        return;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("devenv.toml"), "dependencies = []\n").unwrap();
+    let mut devenv = Command::new(env!("CARGO_BIN_EXE_devenv"))
+        .args(["run", "sh", "--", "-c", "trap 'exit 7' TERM; echo ready; sleep 60 & wait"])
+        .current_dir(dir.path())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut ready = String::new();
+    BufReader::new(devenv.stdout.take().unwrap()).read_line(&mut ready).unwrap();
+    assert_eq!(ready, "ready\n");
+
+    Command::new("kill").arg("-TERM").arg(devenv.id().to_string()).status().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let status = loop {
+        if let Some(status) = devenv.try_wait().unwrap() {
+            break Some(status);
+        }
+        if Instant::now() > deadline {
+            let _ = devenv.kill();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    unmount(dir.path());
+    assert_eq!(status.and_then(|status| status.code()), Some(7));
+}
@@ -0,0 +1,688 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use nix::libc;
+use nix::sys::stat::{major, makedev, minor, mknod, Mode, SFlag};
+use nix::unistd::geteuid;
+use serde_derive::{Serialize, Deserialize};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+use xz2::read::XzDecoder;
+use zstd::stream::{read::Decoder as ZstdDecoder, write::Encoder as ZstdEncoder};
+use log::{debug, warn};
+use devenv_common::error::Error;
+
+use crate::layers::{read_portable_xattrs, read_xattrs, write_xattr, Xattrs};
+
+// Archives made by `devenv export` contain the manifest, then the files under the root directory
+const MANIFEST_FILE: &str = "manifest.toml";
+
+const ROOT_DIR: &str = "rootfs";
+
+// Prefix of the PAX records with the extended attributes of a file. Their values are hex encoded,
+// as the tar crate cannot read records with newlines.
+const XATTR_PAX_PREFIX: &str = "DEVENV.xattr.";
+
+/// What an archive of a DevEnv contains
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveKind {
+    /// The merged root filesystem, which can be used as an image on its own
+    Rootfs,
+    /// Only the upper layer, which needs the image it was made on
+    Upper
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    /// Only read, as Debian packages and indexes use it
+    Xz
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(Error::new(format!("Unknown compression {}, use none, gzip or zstd", s).as_str()))
+        }
+    }
+}
+
+impl Compression {
+
+    const GZIP_MAGIC: &'static [u8] = &[0x1f, 0x8b];
+
+    const ZSTD_MAGIC: &'static [u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+    const XZ_MAGIC: &'static [u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+    /// Compression of a file starting with `magic`
+    fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(Compression::GZIP_MAGIC) {
+            Compression::Gzip
+        }
+        else if magic.starts_with(Compression::ZSTD_MAGIC) {
+            Compression::Zstd
+        }
+        else if magic.starts_with(Compression::XZ_MAGIC) {
+            Compression::Xz
+        }
+        else {
+            Compression::None
+        }
+    }
+
+}
+
+/// Description of an archive of a DevEnv, its first entry
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub kind: ArchiveKind,
+    /// Image of the DevEnv as referenced in its configuration
+    pub image: Vec<String>,
+    /// Layers of the image, from the bottom one
+    pub layers: Vec<PathBuf>,
+    pub config_hash: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    /// Hash of the content of the regular files, by their path relative to the root
+    pub files: BTreeMap<String, String>
+}
+
+/// Write the directory `root` as an archive. The hashes of its files are added to `manifest`.
+pub fn export(root: &Path, mut manifest: Manifest, output: Option<&Path>, compression: Compression) -> Result<(), Error> {
+    let mut files = vec![];
+    walk(root, Path::new(""), &mut files)?;
+    // Files with several hard links are stored once, the other paths link to them
+    let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut linked: Vec<Option<PathBuf>> = vec![];
+    for (path, metadata) in &files {
+        let mut link = None;
+        if !metadata.is_dir() && metadata.nlink() > 1 {
+            link = links.get(&(metadata.dev(), metadata.ino())).cloned();
+            if link.is_none() {
+                links.insert((metadata.dev(), metadata.ino()), path.clone());
+            }
+        }
+        if link.is_none() && metadata.is_file() {
+            manifest.files.insert(path.to_string_lossy().into_owned(), hash(&mut File::open(root.join(path))?)?);
+        }
+        linked.push(link);
+    }
+    let contents = match toml::to_string(&manifest) {
+        Ok(contents) => contents,
+        Err(e) => return Err(Error::new_error("Could not serialize the manifest", Box::from(e)))
+    };
+    let mut builder = Builder::new(Output::new(output, compression)?);
+    builder.follow_symlinks(false);
+    let mut header = Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created);
+    builder.append_data(&mut header, MANIFEST_FILE, contents.as_bytes())?;
+    for ((path, metadata), link) in files.iter().zip(linked) {
+        let source = root.join(path);
+        let name = Path::new(ROOT_DIR).join(path);
+        let mut xattrs: Vec<(String, String)> = vec![];
+        let source_xattrs = match manifest.kind {
+            ArchiveKind::Upper => read_portable_xattrs(&source)?,
+            ArchiveKind::Rootfs => read_xattrs(&source)?
+        };
+        for (key, value) in source_xattrs {
+            match key.to_str() {
+                Ok(key) => xattrs.push((format!("{}{}", XATTR_PAX_PREFIX, key), to_hex(&value))),
+                Err(_) => warn!("Skipping the extended attribute {:?} of {:?}", key, source)
+            }
+        }
+        builder.append_pax_extensions(xattrs.iter().map(|(key, value)| (key.as_str(), value.as_bytes())))?;
+        match link {
+            Some(link) => {
+                let mut header = Header::new_gnu();
+                header.set_metadata(metadata);
+                header.set_entry_type(EntryType::Link);
+                header.set_size(0);
+                builder.append_link(&mut header, &name, Path::new(ROOT_DIR).join(link))?;
+            }
+            None if is_special(metadata) => {
+                // The tar crate would name them after their path on the host
+                let mut header = Header::new_gnu();
+                header.set_metadata(metadata);
+                header.set_entry_type(match metadata.file_type() {
+                    file_type if file_type.is_char_device() => EntryType::Char,
+                    file_type if file_type.is_block_device() => EntryType::Block,
+                    _ => EntryType::Fifo
+                });
+                header.set_device_major(major(metadata.rdev()) as u32)?;
+                header.set_device_minor(minor(metadata.rdev()) as u32)?;
+                header.set_size(0);
+                builder.append_data(&mut header, &name, io::empty())?;
+            }
+            None => builder.append_path_with_name(&source, &name)?
+        }
+    }
+    builder.into_inner()?.finish()
+}
+
+/// Paths under `root`, sorted, along with their metadata. Sockets are left out.
+fn walk(root: &Path, path: &Path, files: &mut Vec<(PathBuf, Metadata)>) -> Result<(), Error> {
+    let metadata = fs::symlink_metadata(root.join(path))?;
+    if metadata.file_type().is_socket() {
+        warn!("Skipping socket {:?}", path);
+        return Ok(());
+    }
+    let is_dir = metadata.is_dir();
+    files.push((path.to_path_buf(), metadata));
+    if is_dir {
+        let mut names: Vec<_> = fs::read_dir(root.join(path))?.map(|entry| entry.map(|entry| entry.file_name())).collect::<Result<_, _>>()?;
+        names.sort();
+        for name in names {
+            walk(root, &path.join(name), files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file is a device or a fifo, including the whiteouts of overlayfs
+fn is_special(metadata: &Metadata) -> bool {
+    let file_type = metadata.file_type();
+    file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo()
+}
+
+fn hash(reader: &mut impl Read) -> Result<String, Error> {
+    let mut state = blake2b_simd::State::new();
+    io::copy(reader, &mut state)?;
+    Ok(state.finalize().to_hex().to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect()
+}
+
+/// Read the manifest of an archive
+pub fn read_manifest(path: &Path) -> Result<Manifest, Error> {
+    let mut input = Input::open(path)?;
+    let manifest = {
+        let mut archive = Archive::new(&mut input);
+        let mut entry = match archive.entries()?.next() {
+            Some(entry) => entry?,
+            None => return Err(Error::new("The archive is empty"))
+        };
+        if entry.path()?.as_ref() != Path::new(MANIFEST_FILE) {
+            return Err(Error::new("The archive was not made by devenv export, it has no manifest"));
+        }
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents)?;
+        match toml::from_str(contents.as_str()) {
+            Ok(manifest) => manifest,
+            Err(e) => return Err(Error::new_error("Invalid manifest", Box::from(e)))
+        }
+    };
+    // The rest of the archive is not needed
+    input.abort();
+    Ok(manifest)
+}
+
+/// Extract the files of an archive to `target`, which must not exist. They are extracted next to
+/// it and only moved to `target` once they match the hashes of the manifest.
+pub fn extract(path: &Path, manifest: &Manifest, target: &Path) -> Result<(), Error> {
+    let partial = match target.file_name() {
+        Some(name) => target.with_file_name(format!(".{}.partial", name.to_string_lossy())),
+        None => return Err(Error::new(format!("Invalid destination {:?}", target).as_str()))
+    };
+    if fs::symlink_metadata(&partial).is_ok() {
+        fs::remove_dir_all(&partial)?;
+    }
+    match extract_files(path, manifest, &partial) {
+        Ok(()) => {
+            fs::rename(&partial, target)?;
+            Ok(())
+        }
+        Err(e) => {
+            if fs::symlink_metadata(&partial).is_ok() {
+                if let Err(e) = fs::remove_dir_all(&partial) {
+                    warn!("Could not remove {:?}: {}", partial, e);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+fn extract_files(path: &Path, manifest: &Manifest, target: &Path) -> Result<(), Error> {
+    let mut input = Input::open(path)?;
+    let mut hashes: BTreeMap<String, String> = BTreeMap::new();
+    {
+        let mut archive = Archive::new(&mut input);
+        // Directories are updated once their content is written, as they might be read-only
+        let mut directories: Vec<(PathBuf, Header, Xattrs)> = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.into_owned();
+            if name == Path::new(MANIFEST_FILE) {
+                continue;
+            }
+            let path = match relative_path(&name) {
+                Some(path) => path,
+                None => return Err(Error::new(format!("Unexpected entry {:?} in the archive", name).as_str()))
+            };
+            let destination = entry_destination(target, &path)?;
+            let header = entry.header().clone();
+            let mut xattrs = vec![];
+            if let Some(extensions) = entry.pax_extensions()? {
+                for extension in extensions {
+                    let extension = extension?;
+                    if let Some(key) = extension.key().ok().and_then(|key| key.strip_prefix(XATTR_PAX_PREFIX)) {
+                        match extension.value().ok().and_then(from_hex) {
+                            Some(value) => xattrs.push((CString::new(key).unwrap(), value)),
+                            None => return Err(Error::new(format!("Invalid extended attribute {} of {:?}", key, name).as_str()))
+                        }
+                    }
+                }
+            }
+            let link = match entry.header().entry_type() {
+                EntryType::Link => match entry.link_name()?.and_then(|link| relative_path(&link)) {
+                    Some(link) => Some(entry_destination(target, &link)?),
+                    None => None
+                },
+                _ => None
+            };
+            match unpack_entry(&mut entry, &destination, link.as_deref(), &xattrs)? {
+                Unpacked::Directory => directories.push((destination, header, xattrs)),
+                Unpacked::File(hash) => {
+                    hashes.insert(path.to_string_lossy().into_owned(), hash);
+                }
+                Unpacked::Other => {}
+            }
+        }
+        for (destination, header, xattrs) in directories.iter().rev() {
+            // A later entry may have replaced the directory with a symlink
+            if fs::symlink_metadata(destination)?.is_dir() {
+                set_attributes(destination, header, xattrs)?;
+            }
+        }
+    }
+    input.finish()?;
+    if hashes != manifest.files {
+        let changed = manifest.files.iter().find(|(path, hash)| hashes.get(*path) != Some(hash)).map(|(path, _)| path);
+        let added = hashes.keys().find(|path| !manifest.files.contains_key(*path));
+        let path = changed.or(added).cloned().unwrap_or_default();
+        return Err(Error::new(format!("The archive is corrupted, the content of {:?} does not match its manifest", path).as_str()));
+    }
+    Ok(())
+}
+
+/// Where the entry at `path`, relative and without `..`, is extracted in `root`. Fails if one of
+/// the directories leading to it is a symlink, as following it could write outside of `root`.
+pub fn entry_destination(root: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let mut destination = root.to_path_buf();
+    if let Some(parent) = path.parent() {
+        for component in parent.components() {
+            destination.push(component);
+            match fs::symlink_metadata(&destination) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return Err(Error::new(format!("{:?} is inside the symlink {:?}", path, destination).as_str()));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(root.join(path))
+}
+
+/// What `unpack_entry` created
+pub enum Unpacked {
+    /// A directory. Its attributes are left to the caller, to set them once its content is
+    /// written, as it might be read-only.
+    Directory,
+    /// A regular file, with the hash of its content
+    File(String),
+    Other
+}
+
+/// Create the file of an archive entry at `destination`, replacing the file there unless both are
+/// directories, and set its attributes. Hard links point to `link`.
+pub fn unpack_entry<R: Read>(entry: &mut Entry<R>, destination: &Path, link: Option<&Path>, xattrs: &Xattrs) -> Result<Unpacked, Error> {
+    let header = entry.header().clone();
+    let entry_type = header.entry_type();
+    debug!("Extracting {:?}", destination);
+    if let Ok(metadata) = fs::symlink_metadata(destination) {
+        if metadata.is_dir() && entry_type.is_dir() {
+            return Ok(Unpacked::Directory);
+        }
+        if metadata.is_dir() {
+            fs::remove_dir_all(destination)?;
+        }
+        else {
+            fs::remove_file(destination)?;
+        }
+    }
+    let mut unpacked = Unpacked::Other;
+    match entry_type {
+        EntryType::Directory => {
+            fs::create_dir(destination)?;
+            return Ok(Unpacked::Directory);
+        }
+        EntryType::Regular | EntryType::Continuous => {
+            // Fails instead of following a symlink at the destination
+            let mut file = OpenOptions::new().write(true).create_new(true).open(destination)?;
+            let mut state = blake2b_simd::State::new();
+            let mut buffer = [0u8; 64 * 1024];
+            loop {
+                let read = entry.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                state.update(&buffer[..read]);
+                file.write_all(&buffer[..read])?;
+            }
+            unpacked = Unpacked::File(state.finalize().to_hex().to_string());
+        }
+        EntryType::Symlink => {
+            match entry.link_name()? {
+                Some(target) => symlink(target, destination)?,
+                None => return Err(Error::new(format!("The symlink {:?} has no target", destination).as_str()))
+            }
+        }
+        EntryType::Link => {
+            // The attributes are the ones of the file it links to
+            match link {
+                Some(link) => fs::hard_link(link, destination)?,
+                None => return Err(Error::new(format!("The hard link {:?} points outside the archive", destination).as_str()))
+            }
+            return Ok(unpacked);
+        }
+        EntryType::Char | EntryType::Block | EntryType::Fifo => {
+            // Whiteouts are character devices
+            let kind = match entry_type {
+                EntryType::Char => SFlag::S_IFCHR,
+                EntryType::Block => SFlag::S_IFBLK,
+                _ => SFlag::S_IFIFO
+            };
+            let device = makedev(header.device_major()?.unwrap_or(0) as u64, header.device_minor()?.unwrap_or(0) as u64);
+            mknod(destination, kind, Mode::from_bits_truncate(header.mode()?), device)?;
+        }
+        _ => {
+            warn!("Skipping {:?}, its type is not supported", destination);
+            return Ok(unpacked);
+        }
+    }
+    set_attributes(destination, &header, xattrs)?;
+    Ok(unpacked)
+}
+
+/// Path of an archive entry relative to the root directory, or `None` if it is not inside it
+fn relative_path(name: &Path) -> Option<PathBuf> {
+    let mut components = name.components().filter(|component| *component != Component::CurDir);
+    if components.next() != Some(Component::Normal(ROOT_DIR.as_ref())) {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(component) => path.push(component),
+            _ => return None
+        }
+    }
+    Some(path)
+}
+
+/// Set the owner, the permissions, the extended attributes and the modification time of an
+/// extracted file, without following symlinks
+pub fn set_attributes(path: &Path, header: &Header, xattrs: &Xattrs) -> Result<(), Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    // Before the permissions, as changing the owner clears the setuid and setgid bits
+    if geteuid().is_root() && unsafe { libc::lchown(c_path.as_ptr(), header.uid()? as libc::uid_t, header.gid()? as libc::gid_t) } != 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    if header.entry_type() != EntryType::Symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(header.mode()? & 0o7777))?;
+    }
+    for (name, value) in xattrs {
+        write_xattr(path, name, value)?;
+    }
+    let mtime = libc::timespec { tv_sec: header.mtime()? as libc::time_t, tv_nsec: 0 };
+    let times = [mtime, mtime];
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    Ok(())
+}
+
+/// Where an archive is written: a file or stdout, through the compressor if there is one
+enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+    Zstd(ZstdEncoder<'static, Box<dyn Write>>)
+}
+
+impl Output {
+
+    fn new(path: Option<&Path>, compression: Compression) -> Result<Output, Error> {
+        let writer: Box<dyn Write> = match path {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout())
+        };
+        match compression {
+            Compression::None => Ok(Output::Plain(writer)),
+            Compression::Gzip => Ok(Output::Gzip(GzEncoder::new(writer, flate2::Compression::default()))),
+            Compression::Zstd => Ok(Output::Zstd(ZstdEncoder::new(writer, 0)?)),
+            Compression::Xz => Err(Error::new("Archives cannot be compressed with xz"))
+        }
+    }
+
+    /// Write the end of the compressed stream and flush the archive
+    fn finish(self) -> Result<(), Error> {
+        let mut writer = match self {
+            Output::Plain(writer) => writer,
+            Output::Gzip(encoder) => encoder.finish()?,
+            Output::Zstd(encoder) => encoder.finish()?
+        };
+        writer.flush()?;
+        Ok(())
+    }
+
+}
+
+impl Write for Output {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+            Output::Zstd(encoder) => encoder.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Zstd(encoder) => encoder.flush()
+        }
+    }
+
+}
+
+/// An archive being read, through the decompressor if it is compressed
+pub struct Input {
+    reader: Box<dyn Read>
+}
+
+impl Input {
+
+    pub fn open(path: &Path) -> Result<Input, Error> {
+        Input::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Read an archive from `reader`, through the decompressor if it is compressed
+    pub fn from_reader(mut reader: impl Read + 'static) -> Result<Input, Error> {
+        let mut magic = vec![0u8; 6];
+        let mut read = 0;
+        while read < magic.len() {
+            match reader.read(&mut magic[read..])? {
+                0 => break,
+                count => read += count
+            }
+        }
+        magic.truncate(read);
+        let compression = Compression::detect(&magic);
+        // The bytes read to detect the compression are still part of the archive
+        let source = io::Cursor::new(magic).chain(reader);
+        let reader: Box<dyn Read> = match compression {
+            Compression::None => Box::new(source),
+            // Like gzip -d, read every member of the file
+            Compression::Gzip => Box::new(MultiGzDecoder::new(source)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(source)?),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(source))
+        };
+        Ok(Input { reader })
+    }
+
+    /// Read the rest of the archive, which must be intact
+    pub fn finish(mut self) -> Result<(), Error> {
+        // The end of an archive may be followed by padding, and the compressed stream by its checksum
+        io::copy(&mut self.reader, &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Stop reading the archive
+    pub fn abort(self) {
+        drop(self.reader);
+    }
+
+}
+
+impl Read for Input {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+        header.set_size(size);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    fn manifest(files: BTreeMap<String, String>) -> Manifest {
+        Manifest { kind: ArchiveKind::Rootfs, image: vec![], layers: vec![], config_hash: String::new(), created: 0, files }
+    }
+
+    /// Write an archive with a manifest listing `files`, followed by the entries added by `add`
+    fn write_archive(path: &Path, files: BTreeMap<String, String>, add: impl FnOnce(&mut Builder<File>)) {
+        let contents = toml::to_string(&manifest(files)).unwrap();
+        let mut builder = Builder::new(File::create(path).unwrap());
+        builder.append_data(&mut header(EntryType::Regular, contents.len() as u64), MANIFEST_FILE, contents.as_bytes()).unwrap();
+        builder.append_data(&mut header(EntryType::Directory, 0), ROOT_DIR, io::empty()).unwrap();
+        add(&mut builder);
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn extract_checks_the_files_before_moving_them_to_the_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive.tar");
+        let target = dir.path().join("target");
+        let files = BTreeMap::from([("file".to_owned(), hash(&mut &b"expected"[..]).unwrap())]);
+        write_archive(&archive, files.clone(), |builder| {
+            builder.append_data(&mut header(EntryType::Regular, 8), "rootfs/file", &b"modified"[..]).unwrap();
+        });
+
+        let error = extract(&archive, &manifest(files), &target).unwrap_err();
+        assert!(error.message().contains("does not match its manifest"), "{}", error);
+        assert!(!target.exists());
+        assert!(!dir.path().join(".target.partial").exists());
+    }
+
+    #[test]
+    fn extract_does_not_write_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("archive.tar");
+        let target = dir.path().join("target");
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        write_archive(&archive, BTreeMap::new(), |builder| {
+            let mut link = header(EntryType::Symlink, 0);
+            builder.append_link(&mut link, "rootfs/escape", &outside).unwrap();
+            builder.append_data(&mut header(EntryType::Regular, 4), "rootfs/escape/file", &b"data"[..]).unwrap();
+        });
+
+        let error = extract(&archive, &manifest(BTreeMap::new()), &target).unwrap_err();
+        assert!(error.message().contains("inside the symlink"), "{}", error);
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn compressed_archives_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("file"), b"data").unwrap();
+        for compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let archive = dir.path().join("archive.tar");
+            let target = dir.path().join("target");
+            export(&root, manifest(BTreeMap::new()), Some(&archive), *compression).unwrap();
+            let manifest = read_manifest(&archive).unwrap();
+            extract(&archive, &manifest, &target).unwrap();
+            assert_eq!(fs::read(target.join("file")).unwrap(), b"data");
+            fs::remove_dir_all(&target).unwrap();
+        }
+    }
+}
@@ -0,0 +1,728 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::unistd::{chdir, chroot, geteuid};
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType, Header};
+use log::{debug, info, warn};
+use devenv_common::error::Error;
+
+use crate::archive::{self, Input, Unpacked};
+use crate::configuration::Bootstrap;
+use crate::layers::RootView;
+use crate::store::ImageInfo;
+
+/// A binary package of the archive, from its `Packages` index
+#[derive(Debug)]
+struct Package {
+    name: String,
+    version: String,
+    /// Part of every system, as its priority is `required` or it is essential
+    required: bool,
+    /// Pre-Depends and Depends, each with its alternatives
+    depends: Vec<Vec<String>>,
+    /// Virtual packages it provides
+    provides: Vec<String>,
+    /// Path of the .deb in the archive
+    filename: String,
+    size: u64,
+    sha256: String
+}
+
+impl Package {
+
+    fn parse(fields: &HashMap<String, String>) -> Option<Package> {
+        let field = |name: &str| fields.get(name).map(|value| value.trim().to_owned());
+        let mut depends = vec![];
+        for name in &["Pre-Depends", "Depends"] {
+            if let Some(relations) = fields.get(*name) {
+                depends.extend(Package::relations(relations));
+            }
+        }
+        let provides = match fields.get("Provides") {
+            Some(relations) => Package::relations(relations).into_iter().flatten().collect(),
+            None => vec![]
+        };
+        Some(Package {
+            name: field("Package")?,
+            version: field("Version")?,
+            required: field("Priority").as_deref() == Some("required") || field("Essential").as_deref() == Some("yes"),
+            depends,
+            provides,
+            filename: field("Filename")?,
+            size: field("Size")?.parse().ok()?,
+            sha256: field("SHA256")?
+        })
+    }
+
+    /// Names of the packages of relations like `libc6 (>= 2.34), mawk | awk`, each with its
+    /// alternatives. Versions and architecture qualifiers are ignored.
+    fn relations(relations: &str) -> Vec<Vec<String>> {
+        relations.split(',')
+            .map(|alternatives| {
+                alternatives.split('|')
+                    .filter_map(|relation| relation.trim().split(|c: char| c.is_whitespace() || c == '(' || c == ':').next())
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_owned())
+                    .collect::<Vec<String>>()
+            })
+            .filter(|alternatives| !alternatives.is_empty())
+            .collect()
+    }
+
+    /// Name of the downloaded .deb
+    fn file_name(&self) -> &str {
+        self.filename.rsplit('/').next().unwrap_or(&self.filename)
+    }
+
+}
+
+/// Bootstraps a minimal Debian or Ubuntu root filesystem from an archive mirror, like
+/// `debootstrap --variant=minbase`.
+///
+/// The packages are extracted into the root first, so it has the tools to install them. Then
+/// they are installed with the dpkg of the root, chrooted into it, which runs their maintainer
+/// scripts.
+pub struct Bootstrapper<'a> {
+    config: &'a Bootstrap,
+    mirror: String,
+    architecture: String
+}
+
+impl<'a> Bootstrapper<'a> {
+
+    // Indexes of the packages looked for in the release, from the preferred one
+    const INDEXES: &'static [&'static str] = &["Packages.xz", "Packages.gz", "Packages"];
+
+    // Installed along with the required packages, as in the minbase variant of debootstrap
+    const BASE_PACKAGES: &'static [&'static str] = &["apt"];
+
+    // Installed first and in this order, so the maintainer scripts of the others can run
+    const CORE_PACKAGES: &'static [&'static str] = &["base-passwd", "base-files", "dpkg", "libc6", "perl-base"];
+
+    // Where the packages are downloaded to, inside the root
+    const ARCHIVES_DIR: &'static str = "var/cache/apt/archives";
+
+    const DPKG_DIR: &'static str = "var/lib/dpkg";
+
+    // Keeps the maintainer scripts from starting services while bootstrapping
+    const POLICY_RC: &'static str = "usr/sbin/policy-rc.d";
+
+    const ENV: &'static [(&'static str, &'static str)] = &[
+        ("PATH", "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"),
+        ("HOME", "/root"),
+        ("LC_ALL", "C"),
+        ("DEBIAN_FRONTEND", "noninteractive"),
+        ("DEBCONF_NONINTERACTIVE_SEEN", "true")
+    ];
+
+    // Devices created in /dev while dpkg runs, with their major and minor numbers
+    const DEVICES: &'static [(&'static str, u64, u64)] = &[
+        ("null", 1, 3),
+        ("zero", 1, 5),
+        ("full", 1, 7),
+        ("random", 1, 8),
+        ("urandom", 1, 9),
+        ("tty", 5, 0)
+    ];
+
+    pub fn new(config: &'a Bootstrap) -> Result<Bootstrapper<'a>, Error> {
+        let mirror = config.mirror.trim_end_matches('/').to_owned();
+        if !["file://", "http://", "https://"].iter().any(|scheme| mirror.starts_with(scheme)) {
+            return Err(Error::new(format!("Unsupported mirror {}, use a file://, http:// or https:// URL", config.mirror).as_str()));
+        }
+        // Anyone between devenv and a remote mirror could change an unsigned release
+        if config.keyring.is_none() && !mirror.starts_with("file://") && !config.insecure.unwrap_or(false) {
+            return Err(Error::new(format!("The mirror {} needs a keyring to verify its release, or set insecure = true", config.mirror).as_str()));
+        }
+        let host = Bootstrapper::host_architecture();
+        let architecture = config.architecture.clone().unwrap_or_else(|| host.to_owned());
+        // The maintainer scripts of the packages run on the host
+        if architecture != host {
+            return Err(Error::new(format!("Cannot bootstrap an image for {} on a {} host", architecture, host).as_str()));
+        }
+        Ok(Bootstrapper {
+            config,
+            mirror,
+            architecture
+        })
+    }
+
+    /// Bootstrap the root filesystem into `root`, which does not exist yet
+    pub fn run(&self, root: &Path) -> Result<ImageInfo, Error> {
+        if !geteuid().is_root() {
+            return Err(Error::new("Bootstrapping an image needs root"));
+        }
+        if self.config.keyring.is_none() && !self.mirror.starts_with("file://") {
+            warn!("No keyring is configured, the release of {} is not verified", self.mirror);
+        }
+        self.prepare(root)?;
+        let packages = self.read_indexes(root)?;
+        let (required, base) = self.select(&packages)?;
+        info!("Bootstrapping {} for {} with {} packages", self.config.suite, self.architecture, required.len() + base.len());
+        for package in required.iter().chain(&base) {
+            debug!("Downloading {} {}", package.name, package.version);
+            self.download(package, root)?;
+        }
+        for package in &required {
+            debug!("Extracting {}", package.name);
+            Bootstrapper::extract(&self.deb_path(root, package), root)?;
+        }
+        self.install(root, &required, &base)?;
+        for package in required.iter().chain(&base) {
+            fs::remove_file(self.deb_path(root, package))?;
+        }
+        let mut installed: Vec<String> = required.iter().chain(&base).map(|package| format!("{}={}", package.name, package.version)).collect();
+        installed.sort();
+        Ok(ImageInfo {
+            suite: self.config.suite.clone(),
+            mirror: self.config.mirror.clone(),
+            architecture: self.architecture.clone(),
+            packages: installed,
+            created: 0
+        })
+    }
+
+    /// Create the directories of the root, with /usr merged, the database of dpkg and the sources
+    /// of apt
+    fn prepare(&self, root: &Path) -> Result<(), Error> {
+        fs::create_dir(root)?;
+        for dir in self.merged_usr_dirs() {
+            fs::create_dir_all(root.join("usr").join(dir))?;
+            symlink(Path::new("usr").join(dir), root.join(dir))?;
+        }
+        for dir in &["dev", "proc", "sys", "etc/apt"] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        fs::create_dir_all(root.join(Bootstrapper::ARCHIVES_DIR).join("partial"))?;
+        let dpkg = root.join(Bootstrapper::DPKG_DIR);
+        fs::create_dir_all(dpkg.join("info"))?;
+        fs::create_dir_all(dpkg.join("updates"))?;
+        fs::write(dpkg.join("status"), "")?;
+        fs::write(dpkg.join("available"), "")?;
+        fs::write(dpkg.join("arch"), format!("{}\n", self.architecture))?;
+        let sources = format!("deb {} {} {}\n", self.config.mirror, self.config.suite, self.components().join(" "));
+        fs::write(root.join("etc/apt/sources.list"), sources)?;
+        Ok(())
+    }
+
+    /// Read the packages of the components from the release, by name. Packages in several
+    /// components are taken from the first one.
+    fn read_indexes(&self, root: &Path) -> Result<HashMap<String, Package>, Error> {
+        let release = self.fetch(&format!("dists/{}/Release", self.config.suite))?;
+        if let Some(keyring) = &self.config.keyring {
+            self.verify_release(root, &release, keyring)?;
+        }
+        let release = paragraphs(&String::from_utf8_lossy(&release)).into_iter().next().unwrap_or_default();
+        let checksums = match release.get("SHA256") {
+            Some(checksums) => checksums,
+            None => return Err(Error::new(format!("The release {} has no SHA256 checksums", self.config.suite).as_str()))
+        };
+        // Lines of `<sha256> <size> <path>`
+        let checksums: HashMap<&str, (&str, u64)> = checksums.lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next().and_then(|size| size.parse().ok()), fields.next()) {
+                    (Some(sha256), Some(size), Some(path)) => Some((path, (sha256, size))),
+                    _ => None
+                }
+            })
+            .collect();
+        let mut packages: HashMap<String, Package> = HashMap::new();
+        for component in self.components() {
+            let index = Bootstrapper::INDEXES.iter()
+                .map(|name| format!("{}/binary-{}/{}", component, self.architecture, name))
+                .find(|index| checksums.contains_key(index.as_str()));
+            let index = match index {
+                Some(index) => index,
+                None => return Err(Error::new(format!("The release {} has no packages for {} in {}", self.config.suite, self.architecture, component).as_str()))
+            };
+            let (sha256, size) = checksums[index.as_str()];
+            let data = self.fetch(&format!("dists/{}/{}", self.config.suite, index))?;
+            Bootstrapper::verify(&index, &data, sha256, size)?;
+            let mut input = Input::from_reader(io::Cursor::new(data))?;
+            let mut text = String::new();
+            if let Err(e) = input.read_to_string(&mut text) {
+                input.abort();
+                return Err(Error::from(e));
+            }
+            input.finish()?;
+            for fields in paragraphs(&text) {
+                match Package::parse(&fields) {
+                    Some(package) => {
+                        packages.entry(package.name.clone()).or_insert(package);
+                    }
+                    None => debug!("Skipping an incomplete package of {}", index)
+                }
+            }
+        }
+        Ok(packages)
+    }
+
+    /// Check the signature of the release with the keyring
+    fn verify_release(&self, root: &Path, release: &[u8], keyring: &str) -> Result<(), Error> {
+        // gpgv looks for keyrings given by a relative path in its home directory
+        let keyring = match fs::canonicalize(keyring) {
+            Ok(keyring) => keyring,
+            Err(e) => return Err(Error::new_error(format!("Could not find the keyring {}", keyring).as_str(), Box::from(e)))
+        };
+        let signature = self.fetch(&format!("dists/{}/Release.gpg", self.config.suite))?;
+        let partial = root.join(Bootstrapper::ARCHIVES_DIR).join("partial");
+        let release_path = partial.join("Release");
+        let signature_path = partial.join("Release.gpg");
+        fs::write(&release_path, release)?;
+        fs::write(&signature_path, signature)?;
+        let output = Command::new("gpgv").arg("--keyring").arg(&keyring).arg(&signature_path).arg(&release_path).output();
+        fs::remove_file(&release_path)?;
+        fs::remove_file(&signature_path)?;
+        match output {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => Err(Error::new(format!("The signature of the release {} is not valid: {}", self.config.suite, String::from_utf8_lossy(&output.stderr).trim()).as_str())),
+            Err(e) => Err(Error::new_error("Could not run gpgv, is it installed?", Box::from(e)))
+        }
+    }
+
+    /// Packages to install: the required ones, then apt and the included ones, each along with
+    /// the dependencies that are not installed before
+    fn select<'p>(&self, packages: &'p HashMap<String, Package>) -> Result<(Vec<&'p Package>, Vec<&'p Package>), Error> {
+        let mut providers: HashMap<&str, Vec<&Package>> = HashMap::new();
+        for package in packages.values() {
+            for name in &package.provides {
+                providers.entry(name.as_str()).or_default().push(package);
+            }
+        }
+        for candidates in providers.values_mut() {
+            candidates.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        let mut required: Vec<&str> = packages.values().filter(|package| package.required).map(|package| package.name.as_str()).collect();
+        if required.is_empty() {
+            return Err(Error::new(format!("The release {} has no required packages", self.config.suite).as_str()));
+        }
+        required.sort_unstable();
+        let mut base: Vec<&str> = vec![];
+        for name in Bootstrapper::BASE_PACKAGES {
+            if packages.contains_key(*name) {
+                base.push(name);
+            }
+            else {
+                warn!("The release {} has no package {}", self.config.suite, name);
+            }
+        }
+        for name in self.config.include.iter().flatten() {
+            if !packages.contains_key(name) {
+                return Err(Error::new(format!("The release {} has no package {}", self.config.suite, name).as_str()));
+            }
+            base.push(name);
+        }
+        let mut selected: HashSet<String> = HashSet::new();
+        let required = Bootstrapper::with_dependencies(packages, &providers, required, &mut selected);
+        let base = Bootstrapper::with_dependencies(packages, &providers, base, &mut selected);
+        Ok((required, base))
+    }
+
+    /// The packages `names` and their dependencies, leaving out the ones in `selected`, which
+    /// they are added to. The first alternative of a dependency is chosen, unless another one is
+    /// already selected.
+    fn with_dependencies<'p>(packages: &'p HashMap<String, Package>, providers: &HashMap<&str, Vec<&'p Package>>, names: Vec<&str>, selected: &mut HashSet<String>) -> Vec<&'p Package> {
+        let mut added: Vec<&Package> = vec![];
+        let mut pending: VecDeque<&Package> = names.iter().filter_map(|name| packages.get(*name)).collect();
+        while let Some(package) = pending.pop_front() {
+            if !selected.insert(package.name.clone()) {
+                continue;
+            }
+            added.push(package);
+            for alternatives in &package.depends {
+                let satisfied = alternatives.iter().any(|name| {
+                    selected.contains(name)
+                        || providers.get(name.as_str()).is_some_and(|candidates| candidates.iter().any(|candidate| selected.contains(&candidate.name)))
+                        || pending.iter().any(|candidate| candidate.name == *name || candidate.provides.contains(name))
+                });
+                if satisfied {
+                    continue;
+                }
+                let choice = alternatives.iter().find_map(|name| {
+                    packages.get(name).or_else(|| providers.get(name.as_str()).and_then(|candidates| candidates.first().copied()))
+                });
+                match choice {
+                    Some(choice) => pending.push_back(choice),
+                    None => warn!("{} depends on {}, which is not in the release", package.name, alternatives.join(" | "))
+                }
+            }
+        }
+        added
+    }
+
+    /// Download a package to the directory of the archives of apt, checking it is the one of the
+    /// index
+    fn download(&self, package: &Package, root: &Path) -> Result<(), Error> {
+        if !Path::new(&package.filename).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(Error::new(format!("Invalid file name {:?} of the package {}", package.filename, package.name).as_str()));
+        }
+        let data = self.fetch(&package.filename)?;
+        Bootstrapper::verify(&package.filename, &data, &package.sha256, package.size)?;
+        fs::write(self.deb_path(root, package), data)?;
+        Ok(())
+    }
+
+    fn deb_path(&self, root: &Path, package: &Package) -> PathBuf {
+        root.join(Bootstrapper::ARCHIVES_DIR).join(package.file_name())
+    }
+
+    /// Read a file of the mirror, e.g. `dists/bookworm/Release`
+    fn fetch(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let url = format!("{}/{}", self.mirror, path);
+        debug!("Fetching {}", url);
+        if let Some(dir) = self.mirror.strip_prefix("file://") {
+            return match fs::read(Path::new(dir).join(path)) {
+                Ok(data) => Ok(data),
+                Err(e) => Err(Error::new_error(format!("Could not read {}", url).as_str(), Box::from(e)))
+            };
+        }
+        let output = match Command::new("curl").arg("-fsSL").arg(&url).output() {
+            Ok(output) => output,
+            Err(e) => return Err(Error::new_error("Could not run curl, is it installed?", Box::from(e)))
+        };
+        if !output.status.success() {
+            return Err(Error::new(format!("Could not download {}: {}", url, String::from_utf8_lossy(&output.stderr).trim()).as_str()));
+        }
+        Ok(output.stdout)
+    }
+
+    fn verify(name: &str, data: &[u8], sha256: &str, size: u64) -> Result<(), Error> {
+        if data.len() as u64 != size || format!("{:x}", Sha256::digest(data)) != sha256.to_ascii_lowercase() {
+            return Err(Error::new(format!("The checksum of {} does not match the one of the release", name).as_str()));
+        }
+        Ok(())
+    }
+
+    /// Extract the files of the package `deb` into `root`, without running its maintainer
+    /// scripts. Paths are resolved inside the root, as its directories may be symlinks, e.g. /bin.
+    fn extract(deb: &Path, root: &Path) -> Result<(), Error> {
+        let mut input = Input::from_reader(io::Cursor::new(Bootstrapper::data_member(deb)?))?;
+        {
+            let view = RootView::Merged(root.to_path_buf());
+            let mut archive = Archive::new(&mut input);
+            // Directories are updated once their content is written, as they might be read-only
+            let mut directories: Vec<(PathBuf, Header)> = vec![];
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let path = view.resolve(&Path::new("/").join(entry.path()?), false)?;
+                if path.as_os_str().is_empty() {
+                    continue;
+                }
+                let destination = root.join(&path);
+                // Like dpkg, keep the symlinks to directories
+                if entry.header().entry_type().is_dir() && destination.is_dir() && fs::symlink_metadata(&destination)?.file_type().is_symlink() {
+                    continue;
+                }
+                let link = match entry.header().entry_type() {
+                    EntryType::Link => match entry.link_name()? {
+                        Some(link) => Some(root.join(view.resolve(&Path::new("/").join(link), false)?)),
+                        None => None
+                    },
+                    _ => None
+                };
+                if let Unpacked::Directory = archive::unpack_entry(&mut entry, &destination, link.as_deref(), &vec![])? {
+                    directories.push((destination, entry.header().clone()));
+                }
+            }
+            for (destination, header) in directories.iter().rev() {
+                archive::set_attributes(destination, header, &vec![])?;
+            }
+        }
+        input.finish()
+    }
+
+    /// Content of the `data.tar` member of a .deb, the archive with the files of the package
+    fn data_member(deb: &Path) -> Result<Vec<u8>, Error> {
+        let mut archive = ar::Archive::new(File::open(deb)?);
+        while let Some(entry) = archive.next_entry() {
+            let mut entry = entry?;
+            if entry.header().identifier().starts_with(b"data.tar") {
+                let mut data = vec![];
+                entry.read_to_end(&mut data)?;
+                return Ok(data);
+            }
+        }
+        Err(Error::new(format!("{:?} is not a Debian package", deb).as_str()))
+    }
+
+    /// Install the packages with dpkg, the way debootstrap does: the core packages first, then
+    /// the other required ones, then the base ones
+    fn install(&self, root: &Path, required: &[&Package], base: &[&Package]) -> Result<(), Error> {
+        let policy = root.join(Bootstrapper::POLICY_RC);
+        fs::write(&policy, "#!/bin/sh\nexit 101\n")?;
+        fs::set_permissions(&policy, fs::Permissions::from_mode(0o755))?;
+        let find = |name: &str| required.iter().find(|package| package.name == name).copied();
+        // dpkg pre-depends on itself
+        if let Some(dpkg) = find("dpkg") {
+            let status = format!("Package: dpkg\nVersion: {}\nMaintainer: unknown\nStatus: install ok installed\n\n", dpkg.version);
+            fs::write(root.join(Bootstrapper::DPKG_DIR).join("status"), status)?;
+            fs::write(root.join(Bootstrapper::DPKG_DIR).join("info/dpkg.list"), "")?;
+        }
+        // The maintainer scripts of the core packages need an awk, which mawk provides once configured
+        let awk = root.join("usr/bin/awk");
+        let mawk = find("mawk");
+        if mawk.is_some() && fs::symlink_metadata(&awk).is_err() {
+            symlink("mawk", &awk)?;
+        }
+        let mut installed: Vec<&str> = vec![];
+        for name in Bootstrapper::CORE_PACKAGES {
+            if let Some(package) = find(name) {
+                info!("Installing {}", name);
+                self.dpkg(root, &["--force-depends", "--install"], &[package])?;
+                installed.push(name);
+            }
+        }
+        if let Some(mawk) = mawk {
+            fs::remove_file(&awk)?;
+            self.dpkg(root, &["--force-depends", "--install"], &[mawk])?;
+            installed.push("mawk");
+        }
+        info!("Installing the required packages");
+        let rest: Vec<&Package> = required.iter().filter(|package| !installed.contains(&package.name.as_str())).copied().collect();
+        if !rest.is_empty() {
+            self.dpkg(root, &["--force-depends", "--unpack"], &rest)?;
+        }
+        self.dpkg(root, &["--configure", "--pending", "--force-configure-any", "--force-depends"], &[])?;
+        if !base.is_empty() {
+            info!("Installing the base packages");
+            self.dpkg(root, &["--force-overwrite", "--force-confold", "--skip-same-version", "--unpack"], base)?;
+            self.dpkg(root, &["--force-confold", "--skip-same-version", "--configure", "-a"], &[])?;
+        }
+        fs::remove_file(&policy)?;
+        Ok(())
+    }
+
+    /// Run the dpkg of the root inside it, with the downloaded packages as arguments
+    fn dpkg(&self, root: &Path, args: &[&str], packages: &[&Package]) -> Result<(), Error> {
+        let mut command = Command::new("/usr/bin/dpkg");
+        command.args(args)
+            .args(packages.iter().map(|package| Path::new("/").join(Bootstrapper::ARCHIVES_DIR).join(package.file_name())))
+            .env_clear()
+            .envs(Bootstrapper::ENV.iter().copied())
+            .stdin(Stdio::null());
+        // Computed before forking, as allocating in the child is not safe
+        let root = root.to_path_buf();
+        let proc = root.join("proc");
+        let dev = root.join("dev");
+        let devices: Vec<(PathBuf, u64)> = Bootstrapper::DEVICES.iter().map(|(name, major, minor)| (dev.join(name), makedev(*major, *minor))).collect();
+        unsafe {
+            command.pre_exec(move || Bootstrapper::enter(&root, &proc, &dev, &devices).map_err(|e| match e.as_errno() {
+                Some(errno) => io::Error::from_raw_os_error(errno as i32),
+                None => io::Error::other(e.to_string())
+            }));
+        }
+        debug!("Running {:?}", command);
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(e) => return Err(Error::new_error("Could not run dpkg inside the image", Box::from(e)))
+        };
+        debug!("{}", String::from_utf8_lossy(&output.stdout));
+        if !output.status.success() {
+            return Err(Error::new(format!("dpkg {} failed with {}: {}", args.join(" "), output.status, String::from_utf8_lossy(&output.stderr).trim()).as_str()));
+        }
+        Ok(())
+    }
+
+    /// Chroot into the root in the child running dpkg, with /proc and a minimal /dev. The child
+    /// has a mount namespace of its own, so nothing is left mounted once it exits.
+    fn enter(root: &Path, proc: &Path, dev: &Path, devices: &[(PathBuf, u64)]) -> nix::Result<()> {
+        unshare(CloneFlags::CLONE_NEWNS)?;
+        mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)?;
+        mount(Some("proc"), proc, Some("proc"), MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC, None::<&str>)?;
+        mount(Some("tmpfs"), dev, Some("tmpfs"), MsFlags::MS_NOSUID, Some("mode=755"))?;
+        for (path, device) in devices {
+            mknod(path.as_path(), SFlag::S_IFCHR, Mode::from_bits_truncate(0o666), *device)?;
+        }
+        chroot(root)?;
+        chdir("/")
+    }
+
+    fn components(&self) -> Vec<&str> {
+        match &self.config.components {
+            Some(components) => components.iter().map(|component| component.as_str()).collect(),
+            None => vec!["main"]
+        }
+    }
+
+    /// Directories of the root that are symlinks to the ones in /usr, as debootstrap makes them
+    fn merged_usr_dirs(&self) -> Vec<&'static str> {
+        let mut dirs = vec!["bin", "sbin", "lib"];
+        dirs.extend_from_slice(match self.architecture.as_str() {
+            "amd64" => &["lib32", "lib64", "libx32"][..],
+            "i386" => &["lib64", "libx32"],
+            "mips64el" => &["lib32", "lib64", "libo32"],
+            "loong64" | "ppc64" => &["lib32", "lib64"],
+            "ppc64el" => &["lib64"],
+            "s390x" => &["lib32"],
+            _ => &[]
+        });
+        dirs
+    }
+
+    /// Debian name of the architecture of the host
+    fn host_architecture() -> &'static str {
+        match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "i386",
+            "aarch64" => "arm64",
+            "arm" => "armhf",
+            "powerpc64" => "ppc64el",
+            "mips64" => "mips64el",
+            "loongarch64" => "loong64",
+            architecture => architecture
+        }
+    }
+
+}
+
+/// Paragraphs of a control file, like the `Release` and `Packages` files, with their fields by
+/// name. The lines continuing a field are joined with newlines.
+fn paragraphs(text: &str) -> Vec<HashMap<String, String>> {
+    let mut paragraphs = vec![];
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut last: Option<String> = None;
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !fields.is_empty() {
+                paragraphs.push(mem::take(&mut fields));
+            }
+            last = None;
+        }
+        else if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = last.as_ref().and_then(|name| fields.get_mut(name)) {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        }
+        else if let Some(colon) = line.find(':') {
+            let name = line[..colon].to_owned();
+            fields.insert(name.clone(), line[colon + 1..].trim().to_owned());
+            last = Some(name);
+        }
+    }
+    if !fields.is_empty() {
+        paragraphs.push(fields);
+    }
+    paragraphs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Bootstrap {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn packages(index: &str) -> HashMap<String, Package> {
+        paragraphs(index).iter()
+            .map(|fields| Package::parse(fields).unwrap())
+            .map(|package| (package.name.clone(), package))
+            .collect()
+    }
+
+    fn names(packages: &[&Package]) -> Vec<String> {
+        packages.iter().map(|package| package.name.clone()).collect()
+    }
+
+    #[test]
+    fn reads_paragraphs_with_continuation_lines() {
+        let release = "Origin: Debian\nSuite: stable\nSHA256:\n 0123 42 main/binary-amd64/Packages.xz\n\t4567 7 main/binary-amd64/Packages\n\nPackage: apt\n";
+        let paragraphs = paragraphs(release);
+
+        assert_eq!(paragraphs.len(), 2);
+        assert_eq!(paragraphs[0]["Suite"], "stable");
+        assert_eq!(paragraphs[0]["SHA256"], "\n0123 42 main/binary-amd64/Packages.xz\n4567 7 main/binary-amd64/Packages");
+        assert_eq!(paragraphs[1]["Package"], "apt");
+    }
+
+    #[test]
+    fn reads_relations_with_alternatives() {
+        let relations = Package::relations("libc6 (>= 2.34), mawk | awk,perl:any (>= 5), debconf (>= 0.5) | debconf-2.0");
+
+        assert_eq!(relations, vec![
+            vec!["libc6".to_owned()],
+            vec!["mawk".to_owned(), "awk".to_owned()],
+            vec!["perl".to_owned()],
+            vec!["debconf".to_owned(), "debconf-2.0".to_owned()]
+        ]);
+    }
+
+    #[test]
+    fn selects_the_dependencies_of_the_packages() {
+        let packages = packages("\
+Package: base-files\nVersion: 1\nPriority: required\nPre-Depends: libc6\nDepends: mawk | gawk\nFilename: pool/b/base-files.deb\nSize: 1\nSHA256: 00\n
+Package: libc6\nVersion: 1\nPriority: optional\nFilename: pool/l/libc6.deb\nSize: 1\nSHA256: 00\n
+Package: mawk\nVersion: 1\nProvides: awk\nFilename: pool/m/mawk.deb\nSize: 1\nSHA256: 00\n
+Package: gawk\nVersion: 1\nProvides: awk\nFilename: pool/g/gawk.deb\nSize: 1\nSHA256: 00\n
+Package: apt\nVersion: 1\nDepends: awk, libc6\nFilename: pool/a/apt.deb\nSize: 1\nSHA256: 00\n
+Package: mutt\nVersion: 1\nDepends: mail-transport-agent\nFilename: pool/m/mutt.deb\nSize: 1\nSHA256: 00\n
+Package: postfix\nVersion: 1\nProvides: mail-transport-agent\nFilename: pool/p/postfix.deb\nSize: 1\nSHA256: 00\n
+Package: exim4\nVersion: 1\nProvides: mail-transport-agent\nFilename: pool/e/exim4.deb\nSize: 1\nSHA256: 00\n");
+        let config = config("suite = \"stable\"\nmirror = \"file:///srv/mirror\"\ninclude = [\"mutt\"]");
+        let bootstrapper = Bootstrapper::new(&config).unwrap();
+
+        let (required, base) = bootstrapper.select(&packages).unwrap();
+
+        // The first alternative is chosen, and a virtual package is satisfied by one selected before
+        assert_eq!(names(&required), vec!["base-files", "libc6", "mawk"]);
+        // Among the providers of a virtual package, the first by name is chosen
+        assert_eq!(names(&base), vec!["apt", "mutt", "exim4"]);
+    }
+
+    #[test]
+    fn rejects_a_checksum_mismatch() {
+        let sha256 = format!("{:x}", Sha256::digest(b"Package: apt\n"));
+
+        assert!(Bootstrapper::verify("Packages", b"Package: apt\n", &sha256, 13).is_ok());
+        assert!(Bootstrapper::verify("Packages", b"Package: apt\n", &sha256.to_ascii_uppercase(), 13).is_ok());
+        assert!(Bootstrapper::verify("Packages", b"Package: dpkg\n", &sha256, 14).is_err());
+        assert!(Bootstrapper::verify("Packages", b"Package: apt\n", &sha256, 12).is_err());
+    }
+
+    #[test]
+    fn requires_a_keyring_for_a_remote_mirror() {
+        let remote = config("suite = \"stable\"\nmirror = \"http://deb.debian.org/debian\"");
+        let insecure = config("suite = \"stable\"\nmirror = \"http://deb.debian.org/debian\"\ninsecure = true");
+        let signed = config("suite = \"stable\"\nmirror = \"https://deb.debian.org/debian\"\nkeyring = \"debian.gpg\"");
+        let local = config("suite = \"stable\"\nmirror = \"file:///srv/mirror\"");
+
+        assert!(Bootstrapper::new(&remote).is_err());
+        assert!(Bootstrapper::new(&insecure).is_ok());
+        assert!(Bootstrapper::new(&signed).is_ok());
+        assert!(Bootstrapper::new(&local).is_ok());
+    }
+}
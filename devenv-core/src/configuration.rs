@@ -21,7 +21,9 @@
  * THE SOFTWARE.
  */
 
-use serde_derive::{Deserialize};
+use std::{collections::HashMap, convert::TryFrom, fmt, str::FromStr};
+use serde::de::{self, Deserializer, Visitor};
+use serde_derive::{Deserialize, Serialize};
 use devenv_common::dependency::Dependency;
 
 
@@ -31,11 +33,275 @@ pub struct Configuration {
     pub dest: Option<String>,
     pub image: Option<Image>,
     pub shell: Option<String>,
-    pub dependencies: Vec<Dependency>
+    pub dependencies: Vec<Dependency>,
+    /// Paths hidden inside the container. Replaces the default list when present.
+    pub masked_paths: Option<Vec<String>>,
+    /// Paths mounted read-only inside the container. Replaces the default list when present.
+    pub readonly_paths: Option<Vec<String>>,
+    pub devices: Option<Vec<Device>>,
+    /// Seconds the container has to exit after devenv is asked to stop, before it is killed
+    pub stop_timeout: Option<u64>,
+    /// Seconds the init of the image has to finish booting, when booting the container
+    pub boot_timeout: Option<u64>,
+    /// Named commands, run with `devenv task <name>`
+    pub tasks: Option<HashMap<String, Task>>,
+    pub hooks: Option<Hooks>,
+    /// Background processes supervised by the container
+    pub services: Option<Vec<Service>>,
+    /// Ports of the host forwarded into the container
+    pub ports: Option<Vec<Port>>,
+    /// Space the changes made to the DevEnv can take, e.g. `10G`. The DevEnv gets a filesystem of
+    /// this size, at least 1M, and writes fail once it is full.
+    pub max_size: Option<Size>
 }
 
 #[derive(Debug)]
 #[derive(Deserialize)]
 pub struct Image {
-    pub path: String
-}
\ No newline at end of file
+    /// Name of an image built with `devenv image build`, used instead of `path`
+    pub name: Option<String>,
+    /// How `devenv image build` bootstraps the image `name`
+    pub bootstrap: Option<Bootstrap>,
+    /// Root filesystem of the image, the name of a layer made with `devenv commit`, or an OCI
+    /// image layout or an archive made by `docker save`, whose layers are unpacked
+    pub path: Option<String>,
+    /// Tag of the image to use when `path` has several, e.g. `debian:bookworm`
+    pub tag: Option<String>,
+    /// Read-only layers stacked on top of `path`, from the bottom one. Layers can be shared by
+    /// several DevEnvs. Names without a '/' are committed layers, stacked along with the layers
+    /// they were committed on.
+    pub layers: Option<Vec<String>>
+}
+
+/// Minimal Debian or Ubuntu root filesystem bootstrapped from an archive mirror, like
+/// `debootstrap --variant=minbase` does
+#[derive(Debug)]
+#[derive(Deserialize)]
+pub struct Bootstrap {
+    /// Release of the distribution, e.g. `bookworm` or `jammy`
+    pub suite: String,
+    /// URL of the archive, e.g. `http://deb.debian.org/debian` or `file:///srv/mirror/debian`
+    pub mirror: String,
+    /// Components whose packages are installed. Defaults to `main`.
+    pub components: Option<Vec<String>>,
+    /// Debian name of the architecture, e.g. `amd64`. Defaults to the one of the host.
+    pub architecture: Option<String>,
+    /// Packages installed along with the required ones and apt
+    pub include: Option<Vec<String>>,
+    /// Keyring verifying the signature of the release. Required unless the mirror is a
+    /// `file://` URL or `insecure` is set.
+    pub keyring: Option<String>,
+    /// Bootstrap from a remote mirror without a keyring, only verifying the checksums of the
+    /// downloaded files against the unsigned release
+    pub insecure: Option<bool>
+}
+
+/// A host device passed through to the container
+#[derive(Debug)]
+#[derive(Deserialize)]
+pub struct Device {
+    pub path: String,
+    /// Path of the device inside the container. Defaults to `path`.
+    pub container_path: Option<String>
+}
+/// A named command run inside the container
+#[derive(Debug)]
+#[derive(Deserialize)]
+pub struct Task {
+    /// Command line, run with `/bin/sh -c`
+    pub command: String,
+    /// Environment variables set for the command
+    pub env: Option<HashMap<String, String>>,
+    /// Working directory of the command. Defaults to the home directory.
+    pub workdir: Option<String>,
+    /// Tasks that must succeed before this one runs
+    pub depends_on: Option<Vec<String>>
+}
+
+/// Commands run at defined points of the lifecycle of the DevEnv
+#[derive(Debug)]
+#[derive(Deserialize)]
+pub struct Hooks {
+    /// Run once, the first time the DevEnv is created
+    pub on_create: Option<Vec<Hook>>,
+    /// Run after the dependencies are resolved
+    pub post_dependencies: Option<Vec<Hook>>,
+    /// Run before opening a shell
+    pub pre_shell: Option<Vec<Hook>>,
+    /// Run before the DevEnv is deleted
+    pub on_destroy: Option<Vec<Hook>>
+}
+
+#[derive(Debug)]
+#[derive(Deserialize)]
+pub struct Hook {
+    /// Command line, run with `/bin/sh -c`
+    pub command: String,
+    /// Run the command on the host instead of inside the container
+    pub host: Option<bool>,
+    /// Only run the command the first time. Always true for `on_create` hooks.
+    pub once: Option<bool>
+}
+
+/// A background process started after the dependencies are resolved, and restarted by the
+/// container when it exits
+#[derive(Debug, Clone)]
+#[derive(Deserialize, Serialize)]
+pub struct Service {
+    /// Name of the service, also used for its log file
+    pub name: String,
+    /// Command line, run with `/bin/sh -c`
+    pub command: String,
+    /// Environment variables set for the command
+    pub env: Option<HashMap<String, String>>,
+    /// Working directory of the command. Defaults to the home directory.
+    pub workdir: Option<String>,
+    /// When the service is restarted after exiting. Defaults to `on-failure`.
+    pub restart: Option<RestartPolicy>,
+    /// How to know that the service is ready. Without it, the service is ready once started.
+    pub ready: Option<Readiness>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never
+}
+
+/// Readiness check of a service. Either `port` or `command` must be given.
+#[derive(Debug, Clone)]
+#[derive(Deserialize, Serialize)]
+pub struct Readiness {
+    /// The service is ready once it accepts TCP connections on this port
+    pub port: Option<u16>,
+    /// The service is ready once this command, run with `/bin/sh -c`, succeeds
+    pub command: Option<String>,
+    /// Seconds the service has to get ready
+    pub timeout: Option<u64>
+}
+
+/// A port of the host forwarded to a port of the container, while the devenv process that
+/// created the container runs
+#[derive(Debug, Clone)]
+#[derive(Deserialize)]
+pub struct Port {
+    /// Port listened on the loopback interface of the host
+    pub host: u16,
+    /// Port connected to inside the container. Defaults to `host`.
+    pub container: Option<u16>,
+    /// Defaults to `tcp`
+    pub protocol: Option<Protocol>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp
+}
+
+/// A number of bytes, written as a number or a string with a binary suffix like `512M` or `2GiB`.
+/// Invalid sizes, and sizes too small to hold a filesystem, are rejected when the configuration is
+/// loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Size(pub u64);
+
+impl Size {
+
+    /// Smallest size accepted, 1 MiB
+    const MIN: u64 = 1 << 20;
+
+    fn new(bytes: u64) -> Result<Size, String> {
+        if bytes < Size::MIN {
+            return Err(format!("Invalid size {}, it must be at least 1M", bytes));
+        }
+        Ok(Size(bytes))
+    }
+
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(size: &str) -> Result<Size, String> {
+        let size = size.trim();
+        let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+        let shift = match size[digits..].trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+            "" => 0,
+            "K" => 10,
+            "M" => 20,
+            "G" => 30,
+            "T" => 40,
+            _ => return Err(format!("Invalid size {}, expected a number of bytes or a suffix K, M, G or T", size))
+        };
+        match size[..digits].parse::<u64>().ok().and_then(|number| number.checked_mul(1 << shift)) {
+            Some(bytes) => Size::new(bytes),
+            None => Err(format!("Invalid size {}", size))
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Size, D::Error> {
+        struct SizeVisitor;
+
+        impl<'de> Visitor<'de> for SizeVisitor {
+            type Value = Size;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number of bytes, or a size like \"512M\" or \"2G\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, bytes: u64) -> Result<Size, E> {
+                Size::new(bytes).map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, bytes: i64) -> Result<Size, E> {
+                match u64::try_from(bytes) {
+                    Ok(bytes) => self.visit_u64(bytes),
+                    Err(_) => Err(E::custom(format!("Invalid size {}", bytes)))
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, size: &str) -> Result<Size, E> {
+                size.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_size(toml: &str) -> Result<Option<Size>, toml::de::Error> {
+        toml::from_str::<Configuration>(&format!("dependencies = []\n{}", toml)).map(|config| config.max_size)
+    }
+
+    #[test]
+    fn parses_max_size() {
+        assert_eq!(max_size("").unwrap(), None);
+        assert_eq!(max_size("max_size = 1048576").unwrap(), Some(Size(1 << 20)));
+        assert_eq!(max_size("max_size = \"512M\"").unwrap(), Some(Size(512 << 20)));
+        assert_eq!(max_size("max_size = \"2GiB\"").unwrap(), Some(Size(2 << 30)));
+    }
+
+    #[test]
+    fn rejects_invalid_max_size() {
+        let error = max_size("max_size = \"10X\"").unwrap_err();
+        assert!(error.to_string().contains("Invalid size 10X"), "{}", error);
+        assert!(max_size("max_size = \"G\"").is_err());
+        assert!(max_size("max_size = \"99999999999T\"").is_err());
+        assert!(max_size("max_size = -1").is_err());
+        let error = max_size("max_size = \"512K\"").unwrap_err();
+        assert!(error.to_string().contains("at least 1M"), "{}", error);
+        assert!(max_size("max_size = 4096").is_err());
+        assert!(max_size("max_size = 0").is_err());
+    }
+}
@@ -21,39 +21,80 @@
  * THE SOFTWARE.
  */
 
-use std::fs::OpenOptions;
 use nix::sched::{unshare, CloneFlags};
-use nix::unistd::{fork, ForkResult, getpid, Pid, execvp, execv, chroot};
-use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult, getpid, Pid, chroot, setsid, pipe2, close};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::signalfd::SignalFd;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::errno::Errno;
+use nix::libc;
+use std::convert::TryFrom;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::thread::{self, JoinHandle};
+use nix::fcntl::OFlag;
+use std::time::{Duration, Instant};
 use std::ffi::{CString, CStr};
-use std::env::{current_exe, set_current_dir, var_os};
+use std::env::{current_exe, set_current_dir, set_var, var_os};
 use std::fs::{copy, File};
-use std::{io::{Read, Write}, path::{PathBuf, Path}};
-use devenv_common::error::Error;
+use std::{io::{self, Read}, path::{PathBuf, Path}};
+use devenv_common::error::{Error, ErrorKind};
 use log::{debug, error, warn};
-use ipc_channel;
+use ipc_channel::ipc::{self, IpcReceiver, IpcReceiverSet, IpcSelectionResult, IpcSender};
+use std::collections::VecDeque;
+use std::cell::Cell;
+use std::sync::Arc;
 use serde_derive::{Serialize, Deserialize};
 use directories::BaseDirs;
-use uuid::Uuid;
 
 use devenv_dependencies;
 use devenv_common::dependency::Dependency;
 use crate::filesystem::Filesystem;
+use crate::init::{Command, Init};
+use crate::control::{ControlClient, ControlServer};
+use crate::layers::RootView;
+use crate::hooks;
+use crate::join;
+use crate::ports::PortForwarder;
+use crate::configuration::Port;
+use crate::services::{ServiceCommand, ServiceStatus, Supervisor};
+use crate::terminal::{self, RawMode};
 
 pub struct Container {
     child_pid: Option<Pid>,
+    // Set once the init was replaced by the init of the image, which does not receive tasks
+    booted: bool,
     fs: Filesystem,
-    ipc: ContainerIPC
+    ipc: ContainerIPC,
+    // Set when attached to a container created by another process
+    control: Option<ControlClient>,
+    ports: Vec<Port>,
+    forwarder: Option<PortForwarder>,
+    // Namespaces of devenv before it unshared the ones of the container, for the host hooks
+    host_namespaces: Vec<(File, CloneFlags)>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ContainerTask {
+    /// Run a command in a new process, and wait for it to finish. If `capture` is set, its
+    /// stdout and stderr are sent back to devenv instead of being inherited. The command gets the
+    /// variables of `env` added to its environment, and runs in `workdir` when given.
     Command {
         name: String,
         params: Vec<String>,
-        reuse_pid: bool
+        capture: bool,
+        env: Vec<(String, String)>,
+        workdir: Option<String>
+    },
+    /// Replace the container's init with a command, which becomes its PID 1 (e.g. systemd).
+    /// No more tasks are processed afterwards.
+    ReplaceInit {
+        name: String,
+        params: Vec<String>
     },
     ResolveDependencies(Vec<Dependency>),
+    /// Start, query or restart the services supervised by the init
+    Services(ServiceCommand),
     Exit
 }
 
@@ -61,14 +102,72 @@ impl Container {
 
     const INIT_TARGETS: &'static [&'static str] = &["/usr/lib/systemd/systemd", "/lib/systemd/systemd", "/sbin/init"];
 
+    /// Signals received by devenv that are forwarded to the container
+    const FORWARDED_SIGNALS: &'static [Signal] = &[Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGQUIT, Signal::SIGWINCH];
+
+    // The channels of tasks cannot be polled with the signals, so they are checked at this interval
+    const TASK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Control socket of the container, in the target directory
+    const CONTROL_SOCKET: &'static str = "control.sock";
+
+    /// Time the services have to stop when the container exits, before they are killed
+    const SERVICES_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// SIGRTMIN+3, which asks systemd to halt. Real-time signals are not part of nix's `Signal`.
+    const HALT_SIGNAL: libc::c_int = 37;
+
+    /// How often the state of a booting container is checked
+    const BOOT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
     pub fn new(fs: Filesystem) -> Container {
         return Container {
             child_pid: None,
+            booted: false,
             fs: fs,
-            ipc: ContainerIPC::new()
+            ipc: ContainerIPC::new(),
+            control: None,
+            ports: vec![],
+            forwarder: None,
+            host_namespaces: vec![]
+        }
+    }
+
+    /// Forward ports of the host to the container while this process runs
+    pub fn set_ports(&mut self, ports: Vec<Port>) {
+        self.ports = ports;
+    }
+
+    /// Attach to the container if it is already running, so tasks are sent through its control
+    /// socket. Returns whether the container was running.
+    pub fn attach(&mut self) -> Result<bool, Error> {
+        let socket = self.control_socket();
+        if !socket.exists() {
+            return Ok(false);
+        }
+        match ControlClient::connect(&socket) {
+            Ok(client) => {
+                debug!("Attached to the container listening on {:?}", socket);
+                self.control = Some(client);
+                Ok(true)
+            }
+            Err(e) => {
+                // Left behind by a container that did not exit cleanly
+                debug!("Could not connect to {:?}: {}", socket, e);
+                Ok(false)
+            }
         }
     }
 
+    /// Whether the container was created by another process
+    pub fn is_attached(&self) -> bool {
+        self.control.is_some()
+    }
+
+    fn control_socket(&self) -> PathBuf {
+        self.fs.target_path().join(Container::CONTROL_SOCKET)
+    }
+
     pub fn create(&mut self) -> Result<(), Error> {
         match self.fs.mount() {
             Ok(_) => {}
@@ -86,6 +185,21 @@ impl Container {
                 return Err(Error::from(err))
             }
         }
+        match self.fs.setup_machine_id() {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Could not set up the machine id");
+                return Err(e);
+            }
+        }
+        // Block the forwarded signals until wait_for_container handles them, so they are not lost
+        // in between. The container process unblocks them.
+        Container::signal_mask().thread_block()?;
+        // Threads cannot be started after unsharing the PID namespace
+        if !self.ports.is_empty() {
+            self.forwarder = Some(PortForwarder::start(&self.ports)?);
+        }
+        self.host_namespaces = hooks::host_namespaces()?;
         match unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWCGROUP | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_FS ) {
             Ok(_) => {}
             Err(err) => {
@@ -93,13 +207,22 @@ impl Container {
                 return Err(Error::from(err));
             }
         }
+        // unshare only moved this thread to the new namespaces
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.enter(File::open("/proc/thread-self/ns/net")?)?;
+        }
         match fork() {
             Ok(ForkResult::Parent {child, ..}) => {
                 debug!("(from parent process) Container pid: {}", child);
                 self.child_pid = Some(child);
+                self.ipc.close_receiver();
             }
             Ok(ForkResult::Child) => {
-                self.container_process().unwrap();
+                // Processes of the container must not get a way back to the host
+                self.host_namespaces.clear();
+                self.ipc.close_sender();
+                let tasks = self.ipc.take_receiver().unwrap();
+                self.container_process(tasks).unwrap();
                 std::process::exit(0);
             }
             Err(e) => { 
@@ -111,32 +234,207 @@ impl Container {
     }
 
     pub fn destroy(&self) -> Result<(), Error> {
+        if self.is_attached() {
+            return Err(Error::new("Cannot destroy a running container"));
+        }
         self.fs.umount()?;
         self.fs.delete()?;
         Ok(())
     }
 
-    pub fn boot(&self) -> Result<(), Error> {
-        for target in Container::INIT_TARGETS {
-            self.run_in_container(ContainerTask::Command{name: target.to_string(), params: vec![target.to_string()], reuse_pid: true})?;
+    /// Replace the init of the container with the first init found in the image, and wait until
+    /// `systemctl is-system-running` reports that it finished booting. Afterwards, commands run
+    /// by joining the namespaces of the container.
+    pub fn boot(&mut self, timeout: Duration) -> Result<(), Error> {
+        let view = self.fs.root_view();
+        let target = Container::INIT_TARGETS.iter().find(|target| {
+            let real = view.resolve(Path::new(target), true).ok().and_then(|path| view.lookup(&path));
+            real.is_some_and(|real| real.is_file())
+        });
+        let target = match target {
+            Some(target) => target.to_string(),
+            None => return Err(Error::new("Could not find an init in the container"))
+        };
+        debug!("Booting {}", target);
+        let replaced = self.run_in_container(ContainerTask::ReplaceInit{name: target.clone(), params: vec![target]})?;
+        self.booted = true;
+        let deadline = Instant::now() + timeout;
+        let mut replacing = true;
+        loop {
+            // The init only replies if it could not be replaced. Otherwise its end of the channel
+            // is closed on exec.
+            if replacing {
+                match replaced.poll() {
+                    Ok(Some(result)) => {
+                        self.booted = false;
+                        return Err(result.into_error());
+                    }
+                    Ok(None) => {}
+                    Err(_) => replacing = false
+                }
+            }
+            if self.has_exited() {
+                self.booted = false;
+                return Err(Error::new("The container exited while booting"));
+            }
+            match self.system_state() {
+                Ok(state) if state == "running" || state == "degraded" => {
+                    debug!("The container booted, its state is {}", state);
+                    return Ok(());
+                }
+                Ok(state) => debug!("The container is booting, its state is {}", state),
+                Err(e) => debug!("Could not get the state of the container: {}", e)
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::new(format!("The container did not finish booting in {:?}", timeout).as_str()));
+            }
+            thread::sleep(Container::BOOT_POLL_INTERVAL);
+        }
+    }
+
+    /// Whether the PID 1 of the container exited, without reaping it
+    fn has_exited(&self) -> bool {
+        let pid = match self.child_pid {
+            Some(pid) => pid,
+            None => return true
+        };
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        let result = unsafe { libc::waitid(libc::P_PID, pid.as_raw() as libc::id_t, &mut info, flags) };
+        result == 0 && unsafe { info.si_pid() } != 0
+    }
+
+    /// State of the system of a booted container, as reported by systemctl
+    fn system_state(&self) -> Result<String, Error> {
+        let params = vec!["systemctl".to_owned(), "is-system-running".to_owned()];
+        let task = self.run_in_container(ContainerTask::Command{name: "systemctl".to_owned(), params, capture: true, env: vec![], workdir: None})?;
+        let mut state: Vec<u8> = vec![];
+        while let Some(chunk) = task.read_output()? {
+            if let TaskOutput::Stdout(data) = chunk {
+                state.extend(data);
+            }
+        }
+        task.wait()?;
+        Ok(String::from_utf8_lossy(&state).trim().to_owned())
+    }
+
+    /// Wait for the container to exit, forwarding the signals received by devenv to it.
+    ///
+    /// After a signal that asks the container to stop (anything but SIGWINCH), the container has
+    /// `stop_timeout` to exit before its PID 1, and with it the whole PID namespace, is killed.
+    pub fn wait_for_container(&self, stop_timeout: Duration) -> Result<(), Error> {
+        let child = match self.child_pid {
+            Some(pid) => pid,
+            None => return Ok(())
+        };
+        let mut forwarder = SignalForwarder::new(child, self.booted, stop_timeout)?;
+        loop {
+            match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => {}
+                Ok(status) => {
+                    debug!("Child process exited with status {:?}", status);
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Child exited with error {}", e);
+                    return Err(Error::from(e));
+                }
+            }
+            // SIGCHLD wakes it up when the container exits
+            forwarder.forward(None)?;
+        }
+    }
+
+    /// Wait for a task to finish, passing its output to `output` as it comes. Like
+    /// `wait_for_container`, the signals received by devenv meanwhile are forwarded to the container.
+    pub fn wait_for_task(&self, mut task: PendingTask, stop_timeout: Duration, mut output: impl FnMut(TaskOutput)) -> Result<TaskResult, Error> {
+        let child = match self.child_pid {
+            Some(pid) => pid,
+            // Attached to a DevEnv created by another process, which handles the signals
+            None => {
+                while let Some(chunk) = task.read_output()? {
+                    output(chunk);
+                }
+                return task.wait();
+            }
+        };
+        let mut forwarder = SignalForwarder::new(child, self.booted, stop_timeout)?;
+        loop {
+            if task.read_available_output(&mut output)? {
+                if let Some(result) = task.poll()? {
+                    return Ok(result);
+                }
+            }
+            forwarder.forward(Some(Container::TASK_POLL_INTERVAL))?;
+        }
+    }
+
+    /// Timeout of poll to wait for `remaining`, rounded up so it does not return before the end
+    fn poll_timeout(remaining: Duration) -> i32 {
+        i32::try_from(remaining.as_nanos().div_ceil(1_000_000)).unwrap_or(i32::MAX)
+    }
+
+    /// Signals handled by wait_for_container: the forwarded ones plus SIGCHLD to know when the container exits
+    fn signal_mask() -> SigSet {
+        let mut mask = SigSet::empty();
+        for signal in Container::FORWARDED_SIGNALS {
+            mask.add(*signal);
+        }
+        mask.add(Signal::SIGCHLD);
+        mask
+    }
+
+    fn bring_up_loopback() -> Result<(), Error> {
+        // struct ifreq, with the flags as the only member of the union used
+        #[repr(C)]
+        struct InterfaceRequest {
+            name: [libc::c_char; libc::IFNAMSIZ],
+            flags: libc::c_short,
+            padding: [u8; 22]
+        }
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if socket < 0 {
+            return Err(Error::from(nix::Error::last()));
+        }
+        let socket = unsafe { File::from_raw_fd(socket) };
+        let mut request = InterfaceRequest { name: [0; libc::IFNAMSIZ], flags: 0, padding: [0; 22] };
+        for (i, byte) in b"lo".iter().enumerate() {
+            request.name[i] = *byte as libc::c_char;
+        }
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) } < 0 {
+            return Err(Error::from(nix::Error::last()));
+        }
+        request.flags |= libc::IFF_UP as libc::c_short;
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) } < 0 {
+            return Err(Error::from(nix::Error::last()));
         }
         Ok(())
     }
 
-    pub fn wait_for_container(&self) -> Result<(), Error> {
-        match waitpid(self.child_pid, None) {
-            Ok(status) => { debug!("Child process exited with status {:?}", status) }
-            Err(e) => { 
-                error!("Child exited with error {}", e);
-                return Err(Error::from(e));
-            } 
+    fn halt(pid: Pid) -> Result<(), Error> {
+        if unsafe { libc::kill(pid.as_raw(), Container::HALT_SIGNAL) } != 0 {
+            return match nix::Error::last() {
+                nix::Error::Sys(Errno::ESRCH) => Ok(()),
+                e => Err(Error::from(e))
+            };
         }
         Ok(())
     }
 
-    fn container_process(&self) -> Result<(), Error> {
+    fn send_signal(pid: Pid, signal: Signal) -> Result<(), Error> {
+        match kill(pid, signal) {
+            // The container already exited, waitpid will notice
+            Ok(_) | Err(nix::Error::Sys(Errno::ESRCH)) => Ok(()),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    fn container_process(&self, tasks: IpcReceiver<ContainerRequest>) -> Result<(), Error> {
         let pid = getpid();
         debug!("(from container process) Container pid: {}", pid);
+        // Signals from the terminal reach devenv only, which forwards them through the init. Without
+        // a controlling terminal, the init can also relay the terminal to the foreground command.
+        setsid()?;
         match pid.to_string().as_str() {
             "1" => { /* Ok */ }
             _ => {
@@ -145,6 +443,21 @@ impl Container {
                 return Err(Error::new("Container is not running with PID 1"))
             }
         }
+        // The network namespace starts with its loopback interface down
+        match Container::bring_up_loopback() {
+            Ok(_) => (),
+            Err(e) => warn!("Could not bring up the loopback interface: {}", e)
+        }
+        match self.fs.mount_dev() {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Could not populate /dev");
+                return Err(e);
+            }
+        }
+        // The socket is outside the root of the container
+        let control = ControlServer::bind(&self.control_socket())?;
+        let logs = Supervisor::open_logs(self.fs.target_path())?;
         match chroot(&self.root()) {
             Ok(_) => {}
             Err(err) => {
@@ -174,81 +487,264 @@ impl Container {
                 return Err(e);
             }
         }
-        /*match self.setup_boot_id() {
-            Ok(_) => (),
-            Err (e) => {
-                error!("Could not set boot id");
-                return Err(Error::from(e))
-            }
-        }*/
-        self.run_tasks();
+        let init = Init::start()?;
+        let (control_sender, control_receiver) = ipc::channel()?;
+        let supervisor = Supervisor::new(init.clone(), logs);
+        control.start(control_sender, init.clone(), supervisor.clone())?;
+        let mut tasks = TaskReceiver::new(tasks, control_receiver)?;
+        self.run_tasks(&init, &supervisor, &mut tasks);
         Ok(())
     }
 
-    fn run_tasks(&self) {
+    fn run_tasks(&self, init: &Init, supervisor: &Arc<Supervisor>, tasks: &mut TaskReceiver) {
         debug!("Executing tasks");
         loop {
-            match self.ipc.receive() {
-                Ok(task) => {
-                    self.run_task(task);
-                }
+            let request = match tasks.receive() {
+                Ok(request) => request,
                 Err(e) => {
-                    warn!("Error while receiving new tasks: {}", e)
+                    // Nobody can send tasks anymore
+                    warn!("Error while receiving new tasks: {}", e);
+                    supervisor.stop_all(Container::SERVICES_STOP_TIMEOUT);
+                    init.exit();
                 }
+            };
+            let exit = matches!(request.task, ContainerTask::Exit);
+            let result = self.run_task(request.task, request.output, init, supervisor);
+            debug!("Task {} finished with {:?}", request.id, result);
+            match request.reply.send(result) {
+                Ok(_) => {}
+                // Nobody is required to wait for the container to exit
+                Err(_) if exit => {}
+                Err(e) => warn!("Could not send the result of task {}: {}", request.id, e)
+            }
+            if exit {
+                supervisor.stop_all(Container::SERVICES_STOP_TIMEOUT);
+                init.exit();
             }
         }
     }
 
-    fn run_task(&self, task: ContainerTask) {
+    fn run_task(&self, task: ContainerTask, output: Option<IpcSender<Option<TaskOutput>>>, init: &Init, supervisor: &Arc<Supervisor>) -> TaskResult {
         debug!("Executing task {:?}", task);
         match task {
-            ContainerTask::Command { name, params, reuse_pid } => self.execute_command(name, params, reuse_pid),
+            ContainerTask::Command { name, params, env, workdir, .. } => self.execute_command(name, params, &env, workdir.as_deref(), init, output),
+            ContainerTask::ReplaceInit { name, params } => self.replace_init(name, params, init),
             ContainerTask::ResolveDependencies(dependencies) => {
-                
                 match devenv_dependencies::resolve_dependencies(dependencies) {
-                    Ok(_) => (),
+                    Ok(resolved) => TaskResult::Dependencies(resolved),
                     Err(e) => {
                         error!("{}", e);
+                        TaskResult::from(e)
                     }
                 }
             }
-            ContainerTask::Exit => {
+            ContainerTask::Services(command) => supervisor.handle(command),
+            ContainerTask::Exit => TaskResult::Exited(init.status())
+        }
+    }
 
+    fn execute_command(&self, filename: String, args: Vec<String>, env: &[(String, String)], workdir: Option<&str>, init: &Init, output: Option<IpcSender<Option<TaskOutput>>>) -> TaskResult {
+        let (filename, args) = Container::command_line(filename, args);
+        let c_args: Vec<&CStr> = args.iter().map(|arg| arg.as_c_str()).collect();
+        debug!("Executing command {:?} {:?}", filename, args);
+        let command = Command { filename: &filename, args: &c_args, env, workdir };
+        let status = match output {
+            Some(output) => self.spawn_captured(&command, init, output),
+            None => init.spawn(&command, None).map(|_| init.wait())
+        };
+        match status {
+            Ok(status) => {
+                debug!("Command {:?} exited with status {}", filename, status);
+                TaskResult::Exited(status)
+            }
+            Err(e) => {
+                error!("Could not execute {:?}: {}", filename, e);
+                TaskResult::from(e)
             }
         }
     }
 
-    fn execute_command(&self, filename: String, args: Vec<String>, same_pid: bool) {
+    /// Run a command sending its stdout and stderr through `output`, and wait for it to finish.
+    /// The end of the output is signaled with `None`.
+    fn spawn_captured(&self, command: &Command, init: &Init, output: IpcSender<Option<TaskOutput>>) -> Result<i32, Error> {
+        let (stdout_read, stdout_write) = pipe2(OFlag::O_CLOEXEC)?;
+        let (stderr_read, stderr_write) = pipe2(OFlag::O_CLOEXEC)?;
+        let readers = vec![
+            Container::forward_output(stdout_read, output.clone(), TaskOutput::Stdout),
+            Container::forward_output(stderr_read, output.clone(), TaskOutput::Stderr)
+        ];
+        let spawned = init.spawn(command, Some((stdout_write, stderr_write)));
+        // Only the command writes to the pipes, so the readers finish when it does
+        let _ = close(stdout_write);
+        let _ = close(stderr_write);
+        let status = spawned.map(|_| init.wait());
+        for reader in readers {
+            let _ = reader.join();
+        }
+        if let Err(e) = output.send(None) {
+            warn!("Could not send the end of the output: {}", e);
+        }
+        status
+    }
+
+    /// Start a command without waiting for it, so it runs along with the task in the foreground.
+    /// Its result is sent through the reply channel of `request` once the init reaps it.
+    pub(crate) fn start_command(request: ContainerRequest, init: &Init) {
+        let ContainerRequest { id, task, reply, output } = request;
+        let started = match task {
+            ContainerTask::Command { name, params, env, workdir, .. } => {
+                let (filename, args) = Container::command_line(name, params);
+                let c_args: Vec<&CStr> = args.iter().map(|arg| arg.as_c_str()).collect();
+                debug!("Starting command {:?} {:?}", filename, args);
+                let command = Command { filename: &filename, args: &c_args, env: &env, workdir: workdir.as_deref() };
+                Container::start_process(&command, init, output.clone())
+            }
+            task => Err(Error::new(format!("{:?} is not a command", task).as_str()))
+        };
+        let (pid, readers) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                error!("Could not start task {}: {}", id, e);
+                if let Some(output) = &output {
+                    let _ = output.send(None);
+                }
+                if let Err(e) = reply.send(TaskResult::from(e)) {
+                    warn!("Could not send the result of task {}: {}", id, e);
+                }
+                return;
+            }
+        };
+        let init = init.clone();
+        thread::spawn(move || {
+            let result = match init.wait_process(pid, None) {
+                Some(status) => TaskResult::Exited(status),
+                None => TaskResult::from(Error::new("The command was reaped by someone else"))
+            };
+            for reader in readers {
+                let _ = reader.join();
+            }
+            if let Some(output) = &output {
+                if let Err(e) = output.send(None) {
+                    warn!("Could not send the end of the output: {}", e);
+                }
+            }
+            debug!("Task {} finished with {:?}", id, result);
+            if let Err(e) = reply.send(result) {
+                warn!("Could not send the result of task {}: {}", id, e);
+            }
+        });
+    }
+
+    /// Start a command as a background process of the init. Its output is sent through `output`
+    /// by the returned threads, which finish when it exits.
+    fn start_process(command: &Command, init: &Init, output: Option<IpcSender<Option<TaskOutput>>>) -> Result<(Pid, Vec<JoinHandle<()>>), Error> {
+        let output = match output {
+            Some(output) => output,
+            None => return Ok((init.start_process(command, None)?, vec![]))
+        };
+        let (stdout_read, stdout_write) = pipe2(OFlag::O_CLOEXEC)?;
+        let (stderr_read, stderr_write) = pipe2(OFlag::O_CLOEXEC)?;
+        let readers = vec![
+            Container::forward_output(stdout_read, output.clone(), TaskOutput::Stdout),
+            Container::forward_output(stderr_read, output, TaskOutput::Stderr)
+        ];
+        let started = init.start_process(command, Some((stdout_write, stderr_write)));
+        let _ = close(stdout_write);
+        let _ = close(stderr_write);
+        started.map(|pid| (pid, readers))
+    }
+
+    pub fn forward_output(fd: RawFd, output: IpcSender<Option<TaskOutput>>, chunk: fn(Vec<u8>) -> TaskOutput) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut pipe = unsafe { File::from_raw_fd(fd) };
+            let mut buffer = [0u8; 4096];
+            loop {
+                match pipe.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if output.send(Some(chunk(buffer[..n].to_vec()))).is_err() {
+                            break;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break
+                }
+            }
+        })
+    }
+
+    fn replace_init(&self, filename: String, args: Vec<String>, init: &Init) -> TaskResult {
+        let (filename, args) = Container::command_line(filename, args);
+        let c_args: Vec<&CStr> = args.iter().map(|arg| arg.as_c_str()).collect();
+        debug!("Replacing init with {:?} {:?}", filename, args);
+        if let Err(e) = self.fs.prepare_boot() {
+            error!("Could not prepare the container to boot: {}", e);
+            return TaskResult::from(e);
+        }
+        // Tells systemd it runs in a container
+        set_var("container", "devenv");
+        let e = init.replace(&filename, &c_args);
+        error!("Could not execute {:?}: {}", filename, e);
+        TaskResult::from(e)
+    }
+
+    /// Build the command line of a task. Commands named after an environment variable are replaced
+    /// by its value (e.g. SHELL), and argv[0] defaults to the command itself.
+    pub fn command_line(filename: String, args: Vec<String>) -> (CString, Vec<CString>) {
         let resolved_filename = match var_os(&filename) {
             None => filename,
             Some(val) => val.to_str().unwrap().to_owned()
         };
-        debug!("Executing command {} {:?}", resolved_filename, args);
-        let t_filename = CString::new(resolved_filename).unwrap();
-        let c_filename = t_filename.as_c_str();
-        let t_args: Vec<CString> = args.iter().map(|arg| CString::new(arg.as_bytes()).unwrap()).collect();
-        let c_args: Vec<&CStr> = t_args.iter().map(|arg| arg.as_c_str()).collect();
-        if same_pid {
-            match execvp(c_filename, c_args.as_slice()) {
-                    Ok(_) => {}
-                    Err(e) => {
+        let args = if args.is_empty() { vec![resolved_filename.clone()] } else { args };
+        let c_filename = CString::new(resolved_filename).unwrap();
+        let c_args: Vec<CString> = args.iter().map(|arg| CString::new(arg.as_bytes()).unwrap()).collect();
+        (c_filename, c_args)
+    }
 
-                    }
+    /// Send a task to the container. The tasks are executed in order, one at a time.
+    pub fn run_in_container(&self, task: ContainerTask) -> Result<PendingTask, Error> {
+        debug!("Sending task {:?}", task);
+        if self.booted {
+            return self.run_in_booted(task);
+        }
+        match &self.control {
+            Some(control) => control.send(task),
+            None => {
+                // The init gives the foreground command a terminal, which is relayed to this one
+                let raw_mode = match &task {
+                    ContainerTask::Command { capture: false, .. } if terminal::is_interactive() => Some(RawMode::enable()?),
+                    _ => None
+                };
+                let mut pending = self.ipc.send(task)?;
+                pending.raw_mode = raw_mode;
+                Ok(pending)
             }
         }
-        else {
-            match execv(c_filename, c_args.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
+    }
 
-                }
+    /// Run a task once the init was replaced: commands join the namespaces of the container, and
+    /// exiting asks the new init to halt. Tasks run concurrently, unlike with the devenv init.
+    fn run_in_booted(&self, task: ContainerTask) -> Result<PendingTask, Error> {
+        let pid = match self.child_pid {
+            Some(pid) => pid,
+            None => return Err(Error::new("The container is not running"))
+        };
+        let (request, pending) = ContainerRequest::new(self.ipc.next_id(), task)?;
+        match request.task {
+            ContainerTask::Command { .. } => join::spawn(pid, request)?,
+            ContainerTask::Exit => {
+                Container::halt(pid)?;
+                // The exit status is the one of the container
+                let _ = request.reply.send(TaskResult::Exited(0));
             }
+            task => return Err(Error::new(format!("Cannot run {:?} in a booted container", task).as_str()))
         }
+        Ok(pending)
     }
 
-    pub fn run_in_container(&self, task: ContainerTask) -> Result<(), Error> {
-        debug!("Sending task {:?}", task);
-        self.ipc.send(task)
+    /// Namespaces of the host, if this process created the container and is in its namespaces
+    pub fn host_namespaces(&self) -> &[(File, CloneFlags)] {
+        &self.host_namespaces
     }
 
     pub fn root(&self) -> PathBuf {
@@ -259,62 +755,392 @@ impl Container {
         return self.fs.target_path().to_str();
     }
 
-    fn setup_boot_id(&self) -> Result<(), Error> {
-        let boot_id = Uuid::new_v4();
-        debug!("Boot id: {}", boot_id.to_hyphenated());
-        let boot_id_path = Path::new("/proc/sys/kernel/random/boot_id");
-        let mut boot_id_file = match OpenOptions::new().write(true).create(true).truncate(true).open(boot_id_path) {
-            Ok(file) => file,
-            Err(e) => { 
-                error!("Could not open boot_id file");
-                return Err(Error::from(e))
+    pub fn target_path(&self) -> &PathBuf {
+        self.fs.target_path()
+    }
+
+    pub fn root_view(&self) -> RootView {
+        self.fs.root_view()
+    }
+
+    pub fn filesystem(&self) -> &Filesystem {
+        &self.fs
+    }
+
+    pub fn filesystem_mut(&mut self) -> &mut Filesystem {
+        &mut self.fs
+    }
+
+}
+
+/// Identifier of a task sent to the container
+pub type TaskId = u64;
+
+/// A task sent to the container, with the channel where its result is sent back
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContainerRequest {
+    pub(crate) id: TaskId,
+    pub(crate) task: ContainerTask,
+    pub(crate) reply: IpcSender<TaskResult>,
+    // Only for commands capturing their output
+    pub(crate) output: Option<IpcSender<Option<TaskOutput>>>
+}
+
+impl ContainerRequest {
+
+    /// Create the request for a task, along with the `PendingTask` where its result is received
+    pub fn new(id: TaskId, task: ContainerTask) -> Result<(ContainerRequest, PendingTask), Error> {
+        let (reply, receiver) = match ipc::channel() {
+            Ok(channel) => channel,
+            Err(e) => return Err(Error::from(e))
+        };
+        let (output, output_receiver) = match task {
+            ContainerTask::Command { capture: true, .. } => match ipc::channel() {
+                Ok((output, output_receiver)) => (Some(output), Some(output_receiver)),
+                Err(e) => return Err(Error::from(e))
+            },
+            _ => (None, None)
+        };
+        let request = ContainerRequest { id, task, reply, output };
+        Ok((request, PendingTask { id, receiver, output: output_receiver, raw_mode: None }))
+    }
+
+}
+
+/// A chunk of the output of a command
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TaskOutput {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>)
+}
+
+/// The outcome of a task run in the container
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TaskResult {
+    /// The task finished, with the given exit status
+    Exited(i32),
+    /// The task could not be completed
+    Failed {
+        kind: TaskErrorKind,
+        message: String
+    },
+    /// The dependencies found for a `ResolveDependencies` task
+    Dependencies(Vec<Dependency>),
+    /// The state of the services after a `Services` task
+    Services(Vec<ServiceStatus>)
+}
+
+/// Serializable counterpart of `devenv_common::error::ErrorKind`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TaskErrorKind {
+    /// A system call failed with the given errno
+    UnixError(i32),
+    IOError,
+    Custom,
+    Other
+}
+
+impl TaskResult {
+
+    /// Get the exit status of a finished task, or the error it failed with
+    pub fn exit_status(self) -> Result<i32, Error> {
+        match self {
+            TaskResult::Exited(status) => Ok(status),
+            result => Err(result.into_error())
+        }
+    }
+
+    /// Get the dependencies found by a `ResolveDependencies` task, or the error it failed with
+    pub fn dependencies(self) -> Result<Vec<Dependency>, Error> {
+        match self {
+            TaskResult::Dependencies(dependencies) => Ok(dependencies),
+            result => Err(result.into_error())
+        }
+    }
+
+    /// Get the state of the services after a `Services` task, or the error it failed with
+    pub fn services(self) -> Result<Vec<ServiceStatus>, Error> {
+        match self {
+            TaskResult::Services(services) => Ok(services),
+            result => Err(result.into_error())
+        }
+    }
+
+    fn into_error(self) -> Error {
+        match self {
+            TaskResult::Failed { kind: TaskErrorKind::UnixError(errno), message } => {
+                Error::new_error(message.as_str(), Box::from(nix::Error::Sys(Errno::from_i32(errno))))
             }
+            TaskResult::Failed { message, .. } => Error::new(message.as_str()),
+            result => Error::new(format!("Unexpected task result {:?}", result).as_str())
+        }
+    }
+
+}
+
+impl From<Error> for TaskResult {
+
+    fn from(error: Error) -> TaskResult {
+        let kind = match error.kind() {
+            ErrorKind::UnixError(nix::Error::Sys(errno)) => TaskErrorKind::UnixError(*errno as i32),
+            ErrorKind::IOError(_) => TaskErrorKind::IOError,
+            ErrorKind::Custom => TaskErrorKind::Custom,
+            _ => TaskErrorKind::Other
+        };
+        TaskResult::Failed { kind, message: error.message().to_owned() }
+    }
+
+}
+
+/// Forwards the signals received by devenv to the container, and kills it if it does not stop
+/// in time after being asked to
+struct SignalForwarder {
+    child: Pid,
+    booted: bool,
+    signals: SignalFd,
+    stop_timeout: Duration,
+    // When the container is killed, once it was asked to stop
+    deadline: Option<Instant>
+}
+
+impl SignalForwarder {
+
+    fn new(child: Pid, booted: bool, stop_timeout: Duration) -> Result<SignalForwarder, Error> {
+        let signals = SignalFd::new(&Container::signal_mask())?;
+        Ok(SignalForwarder { child, booted, signals, stop_timeout, deadline: None })
+    }
+
+    /// Wait up to `timeout`, or until a signal is received, and forward it
+    fn forward(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        let remaining = self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            warn!("The container did not stop after {:?}, killing it", self.stop_timeout);
+            Container::send_signal(self.child, Signal::SIGKILL)?;
+            self.deadline = None;
+            return Ok(());
+        }
+        let timeout = match (timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining)
         };
-        //let mut old_id: String = String::new();
-        //boot_id_file.read_to_string(&mut old_id)?;
-        //debug!("boot id: {}", old_id);
-        match boot_id_file.write_all(boot_id.to_hyphenated().to_string().as_bytes()) {
+        let mut fds = [PollFd::new(self.signals.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout.map_or(-1, Container::poll_timeout)) {
+            Ok(0) | Err(nix::Error::Sys(Errno::EINTR)) => return Ok(()),
             Ok(_) => {}
-            Err(e) => {
-                return Err(Error::from(e))
-            }
+            Err(e) => return Err(Error::from(e))
+        }
+        let signal = match self.signals.read_signal()? {
+            Some(info) => Signal::try_from(info.ssi_signo as i32)?,
+            None => return Ok(())
+        };
+        if signal == Signal::SIGCHLD {
+            return Ok(());
+        }
+        if self.booted && signal != Signal::SIGWINCH {
+            // systemd does not stop on the usual signals
+            debug!("Halting the container after receiving {}", signal);
+            Container::halt(self.child)?;
+        }
+        else {
+            debug!("Forwarding {} to the container", signal);
+            Container::send_signal(self.child, signal)?;
+        }
+        if signal != Signal::SIGWINCH && self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.stop_timeout);
         }
         Ok(())
     }
 
 }
 
+/// A task sent to the container whose result has not been received yet
+pub struct PendingTask {
+    id: TaskId,
+    receiver: IpcReceiver<TaskResult>,
+    output: Option<IpcReceiver<Option<TaskOutput>>>,
+    // Mode of the terminal of devenv, restored once the task is done with it
+    raw_mode: Option<RawMode>
+}
+
+impl PendingTask {
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Block until the next chunk of output of the task is available. Returns `None` once the
+    /// whole output has been read, or if the task does not capture its output.
+    pub fn read_output(&self) -> Result<Option<TaskOutput>, Error> {
+        match &self.output {
+            None => Ok(None),
+            Some(output) => match output.recv() {
+                Ok(chunk) => Ok(chunk),
+                // The container is gone, so there is nothing else to read
+                Err(_) => Ok(None)
+            }
+        }
+    }
+
+    /// Pass the output of the task already received to `handler`, without blocking. Returns
+    /// whether the whole output was read.
+    fn read_available_output(&mut self, handler: &mut impl FnMut(TaskOutput)) -> Result<bool, Error> {
+        let output = match &self.output {
+            Some(output) => output,
+            None => return Ok(true)
+        };
+        loop {
+            match output.try_recv() {
+                Ok(Some(chunk)) => handler(chunk),
+                Ok(None) => break,
+                Err(e) => match *e {
+                    bincode::ErrorKind::IoError(ref err) if err.raw_os_error() == Some(libc::EAGAIN) => return Ok(false),
+                    // The container is gone, so there is nothing else to read
+                    _ => break
+                }
+            }
+        }
+        self.output = None;
+        Ok(true)
+    }
+
+    /// Block until the task finishes
+    pub fn wait(self) -> Result<TaskResult, Error> {
+        match self.receiver.recv() {
+            Ok(result) => Ok(result),
+            Err(_) => Err(Error::new("The container exited before the task finished"))
+        }
+    }
+
+    /// Get the result of the task if it already finished
+    pub fn poll(&self) -> Result<Option<TaskResult>, Error> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(Some(result)),
+            Err(e) => match *e {
+                bincode::ErrorKind::IoError(ref err) if err.raw_os_error() == Some(libc::EAGAIN) => Ok(None),
+                _ => Err(Error::new("The container exited before the task finished"))
+            }
+        }
+    }
+
+}
+
+/// Channel to send tasks from devenv to the container. After forking, the parent process keeps
+/// the sending end and the container the receiving end.
 pub struct ContainerIPC {
-    sender: ipc_channel::ipc::IpcSender<ContainerTask>,
-    receiver: ipc_channel::ipc::IpcReceiver<ContainerTask>
+    sender: Option<IpcSender<ContainerRequest>>,
+    receiver: Option<IpcReceiver<ContainerRequest>>,
+    next_id: Cell<TaskId>
 }
 
 impl ContainerIPC {
 
     pub fn new() -> ContainerIPC {
-        let (tx, rx) = ipc_channel::ipc::channel().unwrap();
+        let (tx, rx) = ipc::channel().unwrap();
         return ContainerIPC {
-            sender: tx,
-            receiver: rx
+            sender: Some(tx),
+            receiver: Some(rx),
+            next_id: Cell::new(0)
         }
     }
 
-    pub fn send(&self, payload: ContainerTask) -> Result<(), Error> {
-        match self.sender.send(payload) {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                Err(Error::new("Error sending task"))
-            }
+    pub fn close_sender(&mut self) {
+        self.sender = None;
+    }
+
+    pub fn close_receiver(&mut self) {
+        self.receiver = None;
+    }
+
+    pub fn next_id(&self) -> TaskId {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        id
+    }
+
+    pub fn take_receiver(&mut self) -> Option<IpcReceiver<ContainerRequest>> {
+        self.receiver.take()
+    }
+
+    pub fn send(&self, task: ContainerTask) -> Result<PendingTask, Error> {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return Err(Error::new("Cannot send tasks from the container"))
+        };
+        let (request, pending) = ContainerRequest::new(self.next_id(), task)?;
+        match sender.send(request) {
+            Ok(_) => Ok(pending),
+            Err(_) => Err(Error::new("Error sending task"))
         }
     }
 
-    pub fn receive(&self) -> Result<ContainerTask, Error> {
-        match self.receiver.recv() {
-            Ok(task) => Ok(task),
-            Err(e) => {
-                Err(Error::new("Error receiving task"))
+}
+
+/// Receives the tasks of the container, from devenv and from the control socket. Receiving fails
+/// once devenv is gone, even if the control socket is still open, so the container never
+/// outlives the process that created it. The tasks sent before are received first.
+struct TaskReceiver {
+    receivers: IpcReceiverSet,
+    devenv: u64,
+    // Once devenv is gone, a message sent to itself ends the draining of the other receivers
+    drain: IpcSender<()>,
+    drain_id: u64,
+    drained: bool,
+    received: VecDeque<ContainerRequest>
+}
+
+impl TaskReceiver {
+
+    fn new(devenv: IpcReceiver<ContainerRequest>, control: IpcReceiver<ContainerRequest>) -> Result<TaskReceiver, Error> {
+        let mut receivers = IpcReceiverSet::new()?;
+        let devenv = receivers.add(devenv)?;
+        receivers.add(control)?;
+        let (drain, drain_receiver) = ipc::channel()?;
+        let drain_id = receivers.add(drain_receiver)?;
+        Ok(TaskReceiver { receivers, devenv, drain, drain_id, drained: false, received: VecDeque::new() })
+    }
+
+    fn receive(&mut self) -> Result<ContainerRequest, Error> {
+        loop {
+            if let Some(request) = self.received.pop_front() {
+                return Ok(request);
+            }
+            if self.drained {
+                return Err(Error::new("devenv exited"));
+            }
+            let results = match self.receivers.select() {
+                Ok(results) => results,
+                Err(_) => return Err(Error::new("Error receiving task"))
+            };
+            for result in results {
+                match result {
+                    IpcSelectionResult::MessageReceived(id, _) if id == self.drain_id => self.drained = true,
+                    IpcSelectionResult::MessageReceived(_, message) => match message.to() {
+                        Ok(request) => self.received.push_back(request),
+                        Err(e) => warn!("Ignoring an invalid task: {}", e)
+                    },
+                    IpcSelectionResult::ChannelClosed(id) if id == self.devenv => {
+                        // The next select returns what the control socket already sent, without blocking
+                        if self.drain.send(()).is_err() {
+                            self.drained = true;
+                        }
+                    }
+                    IpcSelectionResult::ChannelClosed(_) => {}
+                }
             }
         }
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_timeout_rounds_up_and_saturates() {
+        assert_eq!(Container::poll_timeout(Duration::from_micros(1)), 1);
+        assert_eq!(Container::poll_timeout(Duration::from_micros(1500)), 2);
+        assert_eq!(Container::poll_timeout(Duration::from_secs(10)), 10_000);
+        assert_eq!(Container::poll_timeout(Duration::from_secs(30 * 24 * 3600)), i32::MAX);
+    }
+}
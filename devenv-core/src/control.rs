@@ -0,0 +1,285 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+// Control socket of a running container.
+//
+// The init of the container listens on a Unix domain socket, so processes other than the one
+// that created the container can send it tasks. Each connection carries a single task: the client
+// sends a `ContainerTask`, and the server answers with the output of the task (commands always
+// capture their output) followed by its `TaskResult`.
+//
+// Every message is a frame made of the protocol version and the length of the payload, both as
+// big endian u32, followed by the payload serialized with bincode.
+
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use ipc_channel::ipc::IpcSender;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use serde::de::DeserializeOwned;
+use serde_derive::{Serialize, Deserialize};
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+
+use crate::services::Supervisor;
+use crate::init::Init;
+use crate::container::{Container, ContainerRequest, ContainerTask, PendingTask, TaskErrorKind, TaskId, TaskOutput, TaskResult};
+
+/// Version of the control protocol. Must be increased on every incompatible change.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Frames bigger than this are rejected
+const MAX_FRAME_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// Message sent by the server in response to a task
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlResponse {
+    Output(TaskOutput),
+    Result(TaskResult)
+}
+
+/// Server side of the control socket, run by the init of the container
+pub struct ControlServer {
+    listener: UnixListener,
+    // Besides root, only the owner of the environment is allowed to send tasks
+    owner: u32
+}
+
+impl ControlServer {
+
+    /// Listen on `path`. Must be run BEFORE chrooting, as the socket lives outside the container.
+    pub fn bind(path: &Path) -> Result<ControlServer, Error> {
+        // A previous container might have left its socket behind
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Could not listen on {:?}", path);
+                return Err(Error::from(e));
+            }
+        };
+        // Access is checked with the credentials of the peer
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+        let owner = match path.parent() {
+            Some(parent) => fs::metadata(parent)?.uid(),
+            None => 0
+        };
+        Ok(ControlServer { listener, owner })
+    }
+
+    /// Accept connections in a new thread, sending the tasks received to the init through `tasks`.
+    /// Commands are started through `init` and requests about services are answered by
+    /// `supervisor` right away, so they do not wait for the task the init is running.
+    pub fn start(self, tasks: IpcSender<ContainerRequest>, init: Init, supervisor: Arc<Supervisor>) -> Result<(), Error> {
+        let next_id = Arc::new(AtomicU64::new(0));
+        let spawned = thread::Builder::new().name("control".to_owned()).spawn(move || {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Error accepting a connection: {}", e);
+                        continue;
+                    }
+                };
+                let tasks = tasks.clone();
+                let id = next_id.fetch_add(1, Ordering::SeqCst);
+                let owner = self.owner;
+                let init = init.clone();
+                let supervisor = supervisor.clone();
+                thread::spawn(move || {
+                    if let Err(e) = ControlServer::serve(stream, tasks, &init, &supervisor, owner, id) {
+                        warn!("Error serving task {} from the control socket: {}", id, e);
+                    }
+                });
+            }
+        });
+        match spawned {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Could not start the control socket thread");
+                Err(Error::from(e))
+            }
+        }
+    }
+
+    fn serve(mut stream: UnixStream, tasks: IpcSender<ContainerRequest>, init: &Init, supervisor: &Arc<Supervisor>, owner: u32, id: TaskId) -> Result<(), Error> {
+        let credentials = getsockopt(stream.as_raw_fd(), PeerCredentials)?;
+        if credentials.uid() != 0 && credentials.uid() != owner {
+            warn!("Rejected task from uid {}", credentials.uid());
+            return ControlServer::reject(&mut stream, "Permission denied");
+        }
+        let mut task: ContainerTask = match read_frame(&mut stream) {
+            Ok(Some(task)) => task,
+            Ok(None) => return Ok(()),
+            // Tell the client why, e.g. it speaks another version of the protocol
+            Err(e) => {
+                ControlServer::reject(&mut stream, format!("Invalid request: {}", e.message()).as_str())?;
+                return Err(e);
+            }
+        };
+        debug!("Received task {:?} from pid {}", task, credentials.pid());
+        match task {
+            // The client cannot share its stdout and stderr, so the output is always sent back
+            ContainerTask::Command { ref mut capture, .. } => *capture = true,
+            ContainerTask::ReplaceInit { .. } => {
+                return ControlServer::reject(&mut stream, "The init cannot be replaced through the control socket");
+            }
+            ContainerTask::Services(command) => {
+                return write_frame(&mut stream, &ControlResponse::Result(supervisor.handle(command)));
+            }
+            _ => {}
+        }
+        let (request, pending) = ContainerRequest::new(id, task)?;
+        match request.task {
+            ContainerTask::Command { .. } => Container::start_command(request, init),
+            _ => {
+                if tasks.send(request).is_err() {
+                    return Err(Error::new("Error sending task"));
+                }
+            }
+        }
+        while let Some(chunk) = pending.read_output()? {
+            write_frame(&mut stream, &ControlResponse::Output(chunk))?;
+        }
+        let result = pending.wait()?;
+        write_frame(&mut stream, &ControlResponse::Result(result))?;
+        Ok(())
+    }
+
+    fn reject(stream: &mut UnixStream, message: &str) -> Result<(), Error> {
+        let result = TaskResult::Failed { kind: TaskErrorKind::Custom, message: message.to_owned() };
+        write_frame(stream, &ControlResponse::Result(result))?;
+        Ok(())
+    }
+
+}
+
+/// Client side of the control socket
+pub struct ControlClient {
+    path: PathBuf,
+    next_id: Cell<TaskId>
+}
+
+impl ControlClient {
+
+    /// Connect to the control socket at `path`. Fails if no container is listening on it.
+    pub fn connect(path: &Path) -> Result<ControlClient, Error> {
+        UnixStream::connect(path)?;
+        Ok(ControlClient {
+            path: path.to_path_buf(),
+            next_id: Cell::new(0)
+        })
+    }
+
+    /// Send a task to the container. The output of commands that do not capture it is written
+    /// to the stdout and stderr of the calling process.
+    pub fn send(&self, task: ContainerTask) -> Result<PendingTask, Error> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        let inherit_output = matches!(task, ContainerTask::Command { capture: false, .. });
+        write_frame(&mut stream, &task)?;
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let (request, pending) = ContainerRequest::new(id, task)?;
+        thread::spawn(move || ControlClient::receive(stream, request, inherit_output));
+        Ok(pending)
+    }
+
+    /// Forward the responses of the server to the channels of `request`. Dropping `request` tells
+    /// its `PendingTask` that the server is gone.
+    fn receive(mut stream: UnixStream, request: ContainerRequest, inherit_output: bool) {
+        loop {
+            match read_frame(&mut stream) {
+                Ok(Some(ControlResponse::Output(chunk))) => {
+                    if inherit_output {
+                        let _ = match chunk {
+                            TaskOutput::Stdout(data) => io::stdout().write_all(&data),
+                            TaskOutput::Stderr(data) => io::stderr().write_all(&data)
+                        };
+                    }
+                    else if let Some(output) = &request.output {
+                        let _ = output.send(Some(chunk));
+                    }
+                }
+                Ok(Some(ControlResponse::Result(result))) => {
+                    if let Some(output) = &request.output {
+                        let _ = output.send(None);
+                    }
+                    let _ = request.reply.send(result);
+                    return;
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Error reading from the control socket: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+}
+
+fn write_frame<T: serde::Serialize>(stream: &mut UnixStream, value: &T) -> Result<(), Error> {
+    let payload = match bincode::serialize(value, bincode::Infinite) {
+        Ok(payload) => payload,
+        Err(e) => return Err(Error::new_error("Could not serialize message", e))
+    };
+    let mut frame = Vec::with_capacity(8 + payload.len());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+/// Read a frame, returning `None` if the connection was closed before it started
+fn read_frame<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<Option<T>, Error> {
+    let mut header = [0u8; 8];
+    match stream.read_exact(&mut header) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::from(e))
+    }
+    let version = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if version != PROTOCOL_VERSION {
+        return Err(Error::new(format!("Unsupported control protocol version {}, expected version {}", version, PROTOCOL_VERSION).as_str()));
+    }
+    if length > MAX_FRAME_LENGTH {
+        return Err(Error::new(format!("Control message too big ({} bytes)", length).as_str()));
+    }
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload)?;
+    match bincode::deserialize(&payload) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => Err(Error::new_error("Could not deserialize message", e))
+    }
+}
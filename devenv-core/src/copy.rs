@@ -0,0 +1,349 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use nix::unistd::{chown, geteuid, Gid, Uid};
+use tar::{Archive, Builder, EntryType, Header};
+use log::{debug, warn};
+use devenv_common::error::Error;
+
+use crate::layers::RootView;
+
+/// One end of a copy
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopyLocation {
+    /// A path in the host, relative paths are relative to the current directory
+    Host(PathBuf),
+    /// A path inside the DevEnv, relative paths are relative to its root
+    Env(PathBuf),
+    /// A tar archive read from stdin or written to stdout
+    Stream
+}
+
+impl CopyLocation {
+
+    const ENV_PREFIX: &'static str = "env:";
+
+    const HOST_PREFIX: &'static str = "host:";
+
+    const STREAM: &'static str = "-";
+
+}
+
+impl FromStr for CopyLocation {
+
+    type Err = Error;
+
+    /// Parse `env:path`, `host:path` or `-`. Paths without a prefix are host paths.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == CopyLocation::STREAM {
+            Ok(CopyLocation::Stream)
+        }
+        else if let Some(path) = s.strip_prefix(CopyLocation::ENV_PREFIX) {
+            Ok(CopyLocation::Env(PathBuf::from(path)))
+        }
+        else if let Some(path) = s.strip_prefix(CopyLocation::HOST_PREFIX) {
+            Ok(CopyLocation::Host(PathBuf::from(path)))
+        }
+        else if s.is_empty() {
+            Err(Error::new("Empty path"))
+        }
+        else {
+            Ok(CopyLocation::Host(PathBuf::from(s)))
+        }
+    }
+
+}
+
+/// Copy files between the host and a DevEnv, seen through `env`.
+///
+/// Files are copied as a tar stream, keeping their permissions and symlinks. Directories are
+/// copied recursively. The owner of the files is kept when running as root, otherwise they belong
+/// to the calling user. Like `cp`, copying to an existing directory copies into it, and copying to
+/// any other path creates or replaces it. A tar stream read from stdin is always extracted into
+/// the destination directory.
+pub fn copy(env: &RootView, source: &CopyLocation, destination: &CopyLocation) -> Result<(), Error> {
+    match (source, destination) {
+        (CopyLocation::Env(_), _) | (_, CopyLocation::Env(_)) => {}
+        _ => return Err(Error::new("Either the source or the destination must be inside the DevEnv"))
+    }
+    match (source, destination) {
+        (CopyLocation::Stream, destination) => {
+            let (view, path) = open(env, destination)?;
+            extract(&view, &path, None, io::stdin().lock())
+        }
+        (source, CopyLocation::Stream) => {
+            let (view, path) = open(env, source)?;
+            archive(&view, &path, io::stdout().lock())
+        }
+        (source, destination) => {
+            let (source_view, source_path) = open(env, source)?;
+            let (destination_view, destination_path) = open(env, destination)?;
+            copy_between(source_view, source_path, &destination_view, &destination_path)
+        }
+    }
+}
+
+/// Get the view and the path of a location. Host paths are seen through a view of the host root.
+fn open(env: &RootView, location: &CopyLocation) -> Result<(RootView, PathBuf), Error> {
+    match location {
+        CopyLocation::Host(path) => Ok((RootView::Merged(PathBuf::from("/")), env::current_dir()?.join(path))),
+        CopyLocation::Env(path) => Ok((env.clone(), Path::new("/").join(path))),
+        CopyLocation::Stream => Err(Error::new("Both ends of the copy are streams"))
+    }
+}
+
+fn copy_between(source_view: RootView, source: PathBuf, destination_view: &RootView, destination: &Path) -> Result<(), Error> {
+    let destination = destination_view.resolve(destination, true)?;
+    // Copy into an existing directory, or rename the copy
+    let (directory, name) = match destination_view.lookup(&destination) {
+        Some(real) if real.is_dir() => (destination, None),
+        _ => match (destination.parent(), destination.file_name()) {
+            (Some(parent), Some(name)) => (parent.to_path_buf(), Some(name.to_os_string())),
+            _ => return Err(Error::new("Invalid destination"))
+        }
+    };
+    let (reader, writer) = UnixStream::pair()?;
+    let producer = thread::spawn(move || archive(&source_view, &source, writer));
+    let extracted = extract(destination_view, &directory, name, reader);
+    let archived = match producer.join() {
+        Ok(result) => result,
+        Err(_) => Err(Error::new("Could not read the source"))
+    };
+    // If the source failed the destination only sees a truncated archive, so report the cause
+    archived?;
+    extracted
+}
+
+/// Write `path` as a tar archive. Its entries are named after the last component of the path.
+fn archive<W: Write>(view: &RootView, path: &Path, writer: W) -> Result<(), Error> {
+    let path = view.resolve(path, false)?;
+    let name = match path.file_name() {
+        Some(name) => PathBuf::from(name),
+        None => return Err(Error::new("Cannot copy the root of the filesystem"))
+    };
+    if view.lookup(&path).is_none() {
+        return Err(Error::new(format!("{:?} does not exist", Path::new("/").join(&path)).as_str()));
+    }
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    append(&mut builder, view, &path, &name)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn append<W: Write>(builder: &mut Builder<W>, view: &RootView, path: &Path, name: &Path) -> Result<(), Error> {
+    let real = match view.lookup(path) {
+        Some(real) => real,
+        None => return Ok(())
+    };
+    let metadata = fs::symlink_metadata(&real)?;
+    if metadata.file_type().is_socket() {
+        warn!("Skipping socket {:?}", path);
+        return Ok(());
+    }
+    builder.append_path_with_name(&real, name)?;
+    if metadata.is_dir() {
+        for child in view.read_dir(path)? {
+            append(builder, view, &path.join(&child), &name.join(&child))?;
+        }
+    }
+    Ok(())
+}
+
+/// Extract a tar archive into the directory `directory`. If `name` is set, it replaces the first
+/// component of the paths in the archive.
+fn extract<R: Read>(view: &RootView, directory: &Path, name: Option<OsString>, reader: R) -> Result<(), Error> {
+    let directory = view.resolve(directory, true)?;
+    match view.lookup(&directory) {
+        Some(real) if real.is_dir() => {}
+        _ => return Err(Error::new(format!("{:?} is not a directory", Path::new("/").join(&directory)).as_str()))
+    }
+    let preserve_owner = geteuid().is_root();
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(preserve_owner);
+    archive.set_preserve_mtime(true);
+    // Directories are updated once their content is written, as they might be read-only
+    let mut directories: Vec<(PathBuf, Header)> = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = match entry_path(&entry.path()?, name.as_ref()) {
+            // The destination itself
+            Some(path) if path.as_os_str().is_empty() => continue,
+            // Symlinks of the DevEnv in the parents are resolved inside it, so they cannot lead to the host
+            Some(path) => view.resolve(&directory.join(path), false)?,
+            None => {
+                warn!("Skipping {:?}, its path is outside the destination", entry.path()?);
+                continue;
+            }
+        };
+        let entry_type = entry.header().entry_type();
+        let target = view.writable_path(&path, entry_type.is_dir())?;
+        debug!("Extracting {:?}", target);
+        let existing = fs::symlink_metadata(&target).ok();
+        if entry_type.is_dir() {
+            // A symlink is replaced by the directory instead of being followed
+            if existing.filter(|metadata| !metadata.is_dir()).is_some() {
+                fs::remove_file(&target)?;
+            }
+            fs::create_dir_all(&target)?;
+            directories.push((target, entry.header().clone()));
+            continue;
+        }
+        if let Some(metadata) = existing {
+            if metadata.is_dir() {
+                fs::remove_dir_all(&target)?;
+            }
+            else {
+                fs::remove_file(&target)?;
+            }
+        }
+        if entry_type == EntryType::Link {
+            // Hard links point to another entry of the archive
+            let link = entry.link_name()?.and_then(|link| entry_path(&link, name.as_ref()));
+            let link = link.filter(|link| !link.as_os_str().is_empty());
+            let link = link.map(|link| view.resolve(&directory.join(link), false)).transpose()?;
+            let source = link.and_then(|link| view.lookup(&link));
+            match source {
+                Some(source) => fs::hard_link(source, &target)?,
+                None => warn!("Skipping {:?}, the file it links to is not in the archive", path)
+            }
+            continue;
+        }
+        entry.unpack(&target)?;
+    }
+    for (target, header) in directories.iter().rev() {
+        fs::set_permissions(target, fs::Permissions::from_mode(header.mode()? & 0o7777))?;
+        if preserve_owner {
+            chown(target, Some(Uid::from_raw(header.uid()? as u32)), Some(Gid::from_raw(header.gid()? as u32)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of an archive entry relative to the destination, or `None` if it would end outside
+fn entry_path(path: &Path, name: Option<&OsString>) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => {
+                match name {
+                    Some(name) if result.as_os_str().is_empty() => result.push(name),
+                    _ => result.push(component)
+                }
+            }
+            Component::CurDir => {}
+            _ => return None
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    /// An archive with a directory `dir` holding the file `dir/file`
+    fn archive_with_file() -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Directory, 0o755, 0), "dir", io::empty()).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 5), "dir/file", &b"data\n"[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn extract_resolves_symlinks_inside_the_rootfs() {
+        let host = tempfile::tempdir().unwrap();
+        let root = host.path().join("root");
+        let outside = host.path().join("outside");
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        // Seen from the host, both symlinks point to `outside`
+        symlink(&outside, root.join("absolute")).unwrap();
+        symlink("../outside", root.join("relative")).unwrap();
+        symlink("/target", root.join("target-link")).unwrap();
+        let view = RootView::Merged(root.clone());
+
+        extract(&view, Path::new("/absolute"), None, &archive_with_file()[..]).unwrap_err();
+        extract(&view, Path::new("/relative"), None, &archive_with_file()[..]).unwrap_err();
+        extract(&view, Path::new("/target-link"), None, &archive_with_file()[..]).unwrap();
+        assert_eq!(fs::read(root.join("target/dir/file")).unwrap(), b"data\n");
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn extract_does_not_follow_symlinks_of_the_entries() {
+        let host = tempfile::tempdir().unwrap();
+        let root = host.path().join("root");
+        let outside = host.path().join("outside");
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&outside).unwrap();
+        symlink(&outside, root.join("dir")).unwrap();
+        let view = RootView::Merged(root.clone());
+
+        extract(&view, Path::new("/"), None, &archive_with_file()[..]).unwrap();
+        assert!(fs::symlink_metadata(root.join("dir")).unwrap().is_dir());
+        assert_eq!(fs::read(root.join("dir/file")).unwrap(), b"data\n");
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn extract_keeps_entries_under_a_symlinked_parent_inside_the_rootfs() {
+        let host = tempfile::tempdir().unwrap();
+        let root = host.path().join("root");
+        let outside = host.path().join("outside");
+        fs::create_dir_all(root.join("usr/dir")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        // `dir` resolves to /usr/dir of the rootfs, not to the host
+        symlink("/usr/dir", root.join("dir")).unwrap();
+        let mut builder = Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 5), "dir/file", &b"data\n"[..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+        let view = RootView::Merged(root.clone());
+
+        extract(&view, Path::new("/"), None, &archive[..]).unwrap();
+        assert_eq!(fs::read(root.join("usr/dir/file")).unwrap(), b"data\n");
+        assert!(fs::read_dir(&outside).unwrap().next().is_none());
+    }
+}
@@ -21,17 +21,43 @@
  * THE SOFTWARE.
  */
 
+use crate::archive::{self, Manifest};
+use crate::bootstrap::Bootstrapper;
 use crate::filesystem::Filesystem;
-use crate::configuration::Configuration;
+use crate::configuration::{Configuration, Image, Task};
 use crate::container::{Container, ContainerTask};
+use crate::copy::{self, CopyLocation};
+use crate::diff::Differ;
+use crate::hooks::{self, HookRecord, HookStage};
+use crate::layers::RootView;
+use crate::oci::OciImage;
+use crate::registry::{Environment, Registry};
+use crate::services::{ServiceCommand, Supervisor};
+use crate::snapshot::SnapshotStore;
+use crate::store::{ImageStore, LayerStore, Stores};
+use devenv_common::dependency::Dependency;
 use devenv_common::error::Error;
 
+pub use crate::archive::{ArchiveKind, Compression};
+pub use crate::container::{PendingTask, TaskErrorKind, TaskId, TaskOutput, TaskResult};
+pub use crate::diff::{changes_to_json, Change, ChangeKind};
+pub use crate::filesystem::{DiskUsage, StorageUsage};
+pub use crate::services::{ServiceState, ServiceStatus};
+pub use crate::snapshot::Snapshot;
+pub use crate::store::{EntryDetails, EntryKind, Layer, StoreEntry, StoredImage};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use log::{debug, error, info, warn};
 
 pub struct DevEnv {
     container: Container,
-    config: Option<Configuration>
+    config: Option<Configuration>,
+    // Whether the layers of the image were resolved into the filesystem
+    image_resolved: bool
 }
 
 impl DevEnv {
@@ -40,61 +66,610 @@ impl DevEnv {
 
     const DEFAULT_TARGET: &'static str = ".devenv";
 
+    const LOG_FILE: &'static str = "devenv.log";
+
     // Will be expanded as the $SHELL environment variable for the container user
     const DEFAULT_SHELL: &'static str = "SHELL";
 
+    // Seconds the container has to exit after being asked to stop, before it is killed
+    const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+    // Seconds the init of the image has to finish booting
+    const DEFAULT_BOOT_TIMEOUT: u64 = 90;
+
+    // Shell that runs the commands of tasks
+    const TASK_SHELL: &'static str = "/bin/sh";
+
+    // Where an imported upper layer is extracted before it replaces the current one
+    const IMPORT_DIR: &'static str = ".upper.import";
+
     pub fn new() -> DevEnv {
         let target = env::current_dir().unwrap().join(DevEnv::DEFAULT_TARGET);
-        let fs = Filesystem::new(&DevEnv::DEFAULT_IMAGE, &target);
+        let fs = Filesystem::new(vec![PathBuf::from(DevEnv::DEFAULT_IMAGE)], &target);
         return DevEnv {
             container: Container::new(fs),
-            config: None
+            config: None,
+            image_resolved: true
+        }
+    }
+
+    /// Resolve the layers of the image into the filesystem of the DevEnv, unpacking the ones of
+    /// OCI images that are not in the layer cache yet. Only done once, before the filesystem is used.
+    pub fn resolve_image(&mut self) -> Result<(), Error> {
+        if self.image_resolved {
+            return Ok(());
+        }
+        let mut layers = match self.config.as_ref().and_then(|config| config.image.as_ref()) {
+            Some(image) => DevEnv::resolve_layers(image)?,
+            None => vec![]
+        };
+        if layers.is_empty() {
+            layers.push(PathBuf::from(DevEnv::DEFAULT_IMAGE));
         }
+        self.container.filesystem_mut().set_layers(layers);
+        self.image_resolved = true;
+        Ok(())
     }
 
     pub fn create(&mut self) -> Result<(), Error> {
-        return self.container.create()
+        self.resolve_image()?;
+        if let Err(e) = self.container.create() {
+            if self.is_full() {
+                return Err(Error::new("The DevEnv ran out of space, free some or raise its max_size"));
+            }
+            return Err(e);
+        }
+        // Keeps the images and layers it uses from being pruned
+        if let Err(e) = self.register() {
+            warn!("Could not register the DevEnv: {}", e);
+        }
+        self.run_hooks(HookStage::OnCreate)
+    }
+
+    fn register(&self) -> Result<(), Error> {
+        let fs = self.container.filesystem();
+        let environment = Environment {
+            target: fs::canonicalize(fs.target_path())?,
+            image: self.image_references(),
+            layers: fs.layers().to_vec(),
+            used: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+        };
+        Registry::new()?.register(&environment)
+    }
+
+    /// Attach to the DevEnv if it is already running, so tasks are sent through its control socket
+    /// instead of creating it again. Returns whether the DevEnv was running.
+    pub fn attach(&mut self) -> Result<bool, Error> {
+        self.container.attach()
+    }
+
+    /// Whether the DevEnv was created by another process
+    pub fn is_attached(&self) -> bool {
+        self.container.is_attached()
     }
 
     pub fn destroy(&self) -> Result<(), Error> {
-        self.container.destroy()
+        if self.is_attached() {
+            return Err(Error::new("Cannot delete a DevEnv created by another process"));
+        }
+        self.run_hooks(HookStage::OnDestroy)?;
+        let target = fs::canonicalize(self.container.target_path());
+        self.container.destroy()?;
+        if let Err(e) = Registry::new().and_then(|registry| registry.unregister(&target?)) {
+            warn!("Could not unregister the DevEnv: {}", e);
+        }
+        Ok(())
     }
 
     pub fn location(&self) -> Option<&str> {
         return self.container.location();
     }
 
-    pub fn run(&self, command: String, args: Vec<String>) -> Result<(), Error> {
-        self.container.run_in_container(ContainerTask::Command{name: command, params: args, reuse_pid: false})
+    /// Run a command inside the DevEnv and wait for it to finish, returning its exit status
+    pub fn run(&self, command: String, args: Vec<String>) -> Result<i32, Error> {
+        self.wait_for_task(self.run_async(command, args)?, |_| {})?.exit_status()
+    }
+
+    /// Run a command inside the DevEnv without waiting for it to finish
+    pub fn run_async(&self, command: String, args: Vec<String>) -> Result<PendingTask, Error> {
+        self.container.run_in_container(ContainerTask::Command{name: command, params: args, capture: false, env: vec![], workdir: None})
+    }
+
+    /// Run a command inside the DevEnv capturing its output, which can be read from the returned task
+    pub fn run_captured(&self, command: String, args: Vec<String>) -> Result<PendingTask, Error> {
+        self.container.run_in_container(ContainerTask::Command{name: command, params: args, capture: true, env: vec![], workdir: None})
+    }
+
+    /// Run a task declared in the configuration, after the tasks it depends on. Stops at the first
+    /// task that fails, returning its exit status.
+    pub fn run_task(&self, name: &str) -> Result<i32, Error> {
+        let tasks = match self.config.as_ref().and_then(|config| config.tasks.as_ref()) {
+            Some(tasks) => tasks,
+            None => return Err(Error::new("No tasks are declared in the configuration"))
+        };
+        let mut order: Vec<String> = vec![];
+        DevEnv::task_order(tasks, name, &mut vec![], &mut order)?;
+        for name in order {
+            let task = &tasks[&name];
+            info!("Running task {}", name);
+            let env = task.env.iter().flatten().map(|(key, value)| (key.clone(), value.clone())).collect();
+            let status = self.run_script(&task.command, env, task.workdir.clone())?;
+            if status != 0 {
+                error!("Task {} failed with exit status {}", name, status);
+                return Ok(status);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Run a command line with the shell inside the DevEnv, returning its exit status
+    fn run_script(&self, script: &str, env: Vec<(String, String)>, workdir: Option<String>) -> Result<i32, Error> {
+        let params = vec![DevEnv::TASK_SHELL.to_owned(), "-c".to_owned(), script.to_owned()];
+        let command = ContainerTask::Command{name: DevEnv::TASK_SHELL.to_owned(), params, capture: false, env, workdir};
+        self.wait_for_task(self.container.run_in_container(command)?, |_| {})?.exit_status()
+    }
+
+    /// Run the hooks of a stage of the lifecycle, skipping the one-time hooks that already ran.
+    /// Host hooks get the paths of the DevEnv in `DEVENV_ROOT` and `DEVENV_DEST`.
+    fn run_hooks(&self, stage: HookStage) -> Result<(), Error> {
+        let hooks = match self.config.as_ref().and_then(|config| config.hooks.as_ref()) {
+            Some(hooks) => stage.hooks(hooks),
+            None => return Ok(())
+        };
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        let mut record = HookRecord::load(self.container.target_path());
+        for hook in hooks {
+            let once = stage.is_once(hook);
+            if once && record.has_run(stage, hook) {
+                debug!("Skipping {} hook {:?}, it already ran", stage, hook.command);
+                continue;
+            }
+            info!("Running {} hook {:?}", stage, hook.command);
+            let status = if hook.host.unwrap_or(false) {
+                let env = vec![
+                    ("DEVENV_ROOT".to_owned(), self.container.root().to_string_lossy().into_owned()),
+                    ("DEVENV_DEST".to_owned(), self.container.target_path().to_string_lossy().into_owned())
+                ];
+                hooks::run_on_host(DevEnv::TASK_SHELL, &hook.command, &env, self.container.host_namespaces())?
+            }
+            else {
+                self.run_script(&hook.command, vec![], None)?
+            };
+            if status != 0 {
+                error!("The {} hook {:?} failed with exit status {}", stage, hook.command, status);
+                return Err(Error::new(format!("The {} hook failed", stage).as_str()));
+            }
+            if once {
+                record.add(stage, hook)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the task `name` to `order` after its dependencies. `visiting` is the chain of tasks
+    /// that led to it, to detect cycles.
+    fn task_order(tasks: &HashMap<String, Task>, name: &str, visiting: &mut Vec<String>, order: &mut Vec<String>) -> Result<(), Error> {
+        if order.iter().any(|task| task == name) {
+            return Ok(());
+        }
+        if visiting.iter().any(|task| task == name) {
+            return Err(Error::new(format!("Tasks depend on each other: {} -> {}", visiting.join(" -> "), name).as_str()));
+        }
+        let task = match tasks.get(name) {
+            Some(task) => task,
+            None => return Err(Error::new(format!("Unknown task {}", name).as_str()))
+        };
+        visiting.push(name.to_owned());
+        for dependency in task.depends_on.iter().flatten() {
+            DevEnv::task_order(tasks, dependency, visiting, order)?;
+        }
+        visiting.pop();
+        order.push(name.to_owned());
+        Ok(())
+    }
+
+    /// File where the output of commands is logged
+    pub fn log_file(&self) -> PathBuf {
+        self.container.target_path().join(DevEnv::LOG_FILE)
     }
 
-    pub fn boot(&self) -> Result<(), Error> {
-        self.container.boot()
+    /// Copy files between the host and the DevEnv, whether it is running or not
+    pub fn copy(&self, source: &CopyLocation, destination: &CopyLocation) -> Result<(), Error> {
+        copy::copy(&self.container.root_view(), source, destination)
     }
 
-    pub fn open_shell(&self) -> Result<(), Error> {
+    /// Boot the DevEnv with the init of its image (e.g. systemd), and wait until it finishes.
+    /// Commands run afterwards join the booted system.
+    pub fn boot(&mut self) -> Result<(), Error> {
+        let boot_timeout = match &self.config {
+            Some(config) => config.boot_timeout.unwrap_or(DevEnv::DEFAULT_BOOT_TIMEOUT),
+            None => DevEnv::DEFAULT_BOOT_TIMEOUT
+        };
+        self.container.boot(Duration::from_secs(boot_timeout))
+    }
+
+    pub fn open_shell(&self) -> Result<i32, Error> {
         let shell = match &self.config {
             Some(config) => {
                 config.shell.as_ref().unwrap_or(&DevEnv::DEFAULT_SHELL.to_owned()).into()
             }
             None => DevEnv::DEFAULT_SHELL.to_owned()
         };
+        if self.is_attached() {
+            // The control socket does not forward stdin
+            return Err(Error::new("Cannot open a shell in a DevEnv created by another process"));
+        }
+        self.run_hooks(HookStage::PreShell)?;
         let args: Vec<String> = vec![];
-        self.container.run_in_container(ContainerTask::Command{name: shell, params: args, reuse_pid: true})
+        let task = self.container.run_in_container(ContainerTask::Command{name: shell, params: args, capture: false, env: vec![], workdir: None})?;
+        self.wait_for_task(task, |_| {})?.exit_status()
     }
 
-    pub fn resolve_dependencies(&self) -> Result<(), Error> {
+    pub fn resolve_dependencies(&self) -> Result<Vec<Dependency>, Error> {
         match &self.config {
-            None => Ok(()),
+            None => Ok(vec![]),
             Some(config) => {
                 let deps = config.dependencies.clone();
-                self.container.run_in_container(ContainerTask::ResolveDependencies(deps))
+                let task = self.container.run_in_container(ContainerTask::ResolveDependencies(deps))?;
+                let resolved = self.wait_for_task(task, |_| {})?.dependencies()?;
+                self.run_hooks(HookStage::PostDependencies)?;
+                Ok(resolved)
+            }
+        }
+    }
+
+    pub fn has_services(&self) -> bool {
+        self.config.as_ref().and_then(|config| config.services.as_ref()).is_some_and(|services| !services.is_empty())
+    }
+
+    /// Start the services declared in the configuration, and wait until they are ready. Services
+    /// that fail to start or to get ready are reported, but they do not make this fail.
+    pub fn start_services(&self) -> Result<Vec<ServiceStatus>, Error> {
+        let services = match self.config.as_ref().and_then(|config| config.services.as_ref()) {
+            Some(services) if !services.is_empty() => services.clone(),
+            _ => return Ok(vec![])
+        };
+        let mut names: Vec<&str> = vec![];
+        for service in &services {
+            Supervisor::check_name(&service.name)?;
+            if names.contains(&service.name.as_str()) {
+                return Err(Error::new(format!("The service {} is declared more than once", service.name).as_str()));
+            }
+            if let Some(ready) = &service.ready {
+                if ready.port.is_none() && ready.command.is_none() {
+                    return Err(Error::new(format!("The readiness check of service {} needs a port or a command", service.name).as_str()));
+                }
+            }
+            names.push(&service.name);
+        }
+        info!("Starting services {}", names.join(", "));
+        let statuses = self.container.run_in_container(ContainerTask::Services(ServiceCommand::Start(services)))?.wait()?.services()?;
+        // Services that exited successfully and are not restarted are done, not failed
+        let failed = statuses.iter().filter(|status| status.state != ServiceState::Running && !(status.state == ServiceState::Stopped && status.last_status == Some(0)));
+        for status in failed {
+            warn!("The service {} is {}, see {:?}", status.name, status.state, self.service_log(&status.name)?);
+        }
+        Ok(statuses)
+    }
+
+    /// State of the services of the running DevEnv
+    pub fn service_status(&self) -> Result<Vec<ServiceStatus>, Error> {
+        self.container.run_in_container(ContainerTask::Services(ServiceCommand::Status))?.wait()?.services()
+    }
+
+    /// Restart a service of the running DevEnv, or start it again if it stopped
+    pub fn restart_service(&self, name: &str) -> Result<Vec<ServiceStatus>, Error> {
+        self.container.run_in_container(ContainerTask::Services(ServiceCommand::Restart(name.to_owned())))?.wait()?.services()
+    }
+
+    /// File where the output of a service is logged
+    pub fn service_log(&self, name: &str) -> Result<PathBuf, Error> {
+        Supervisor::log_file(self.container.target_path(), name)
+    }
+
+    /// Save the changes made to the DevEnv, i.e. its upper layer, as a snapshot
+    pub fn create_snapshot(&self, name: &str) -> Result<Snapshot, Error> {
+        if self.is_attached() {
+            warn!("The DevEnv is running, files changed while the snapshot is taken may be inconsistent");
+        }
+        self.snapshots().create(name, self.container.filesystem())
+    }
+
+    pub fn list_snapshots(&self) -> Result<Vec<Snapshot>, Error> {
+        self.snapshots().list()
+    }
+
+    /// Bring the DevEnv back to a snapshot, discarding the changes made since. The DevEnv must be
+    /// stopped.
+    pub fn restore_snapshot(&self, name: &str) -> Result<(), Error> {
+        if self.is_attached() {
+            return Err(Error::new("Cannot restore a snapshot while the DevEnv is running"));
+        }
+        self.snapshots().restore(name, self.container.filesystem())
+    }
+
+    pub fn delete_snapshot(&self, name: &str) -> Result<(), Error> {
+        self.snapshots().delete(name)
+    }
+
+    fn snapshots(&self) -> SnapshotStore {
+        SnapshotStore::new(self.container.target_path())
+    }
+
+    /// Turn the changes made to the DevEnv into a read-only layer that other configurations can
+    /// use by its name, as their image or one of its layers. `config` is the contents of the
+    /// configuration file, whose hash is recorded with the layer. The DevEnv must be stopped.
+    pub fn commit(&self, name: &str, config: &str) -> Result<Layer, Error> {
+        if self.is_attached() {
+            return Err(Error::new("Cannot commit a DevEnv while it is running"));
+        }
+        let fs = self.container.filesystem();
+        LayerStore::new()?.commit(name, &fs.upper_path(), fs.layers(), &DevEnv::config_hash(config))
+    }
+
+    /// Write the DevEnv as a tar archive to `output`, or to stdout. The archive has either the
+    /// whole root filesystem, or only the upper layer along with a reference to the image.
+    pub fn export(&self, kind: ArchiveKind, output: Option<&Path>, compression: Compression, config: &str) -> Result<(), Error> {
+        if self.is_attached() {
+            warn!("The DevEnv is running, files changed while it is exported may be inconsistent");
+        }
+        let fs = self.container.filesystem();
+        let root = match kind {
+            ArchiveKind::Upper => fs.upper_path(),
+            ArchiveKind::Rootfs => {
+                if let RootView::Layers { .. } = fs.root_view() {
+                    fs.mount()?;
+                }
+                fs.root_path()
+            }
+        };
+        if !root.is_dir() {
+            return Err(Error::new("The DevEnv has not been created yet"));
+        }
+        let manifest = Manifest {
+            kind,
+            image: self.image_references(),
+            layers: fs.layers().to_vec(),
+            config_hash: DevEnv::config_hash(config),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+            files: BTreeMap::new()
+        };
+        archive::export(&root, manifest, output, compression)
+    }
+
+    /// Space used by the changes made to the DevEnv
+    pub fn disk_usage(&self) -> Result<DiskUsage, Error> {
+        let fs = self.container.filesystem();
+        if !fs.upper_path().is_dir() {
+            return Err(Error::new("The DevEnv has not been created yet"));
+        }
+        fs.disk_usage()
+    }
+
+    /// Whether the DevEnv is out of space, so writes inside it fail
+    pub fn is_full(&self) -> bool {
+        self.container.filesystem().is_full()
+    }
+
+    /// Changes made to the DevEnv relative to its image, running or not. With `contents`, the
+    /// changes of text files come with a diff.
+    pub fn diff(&self, contents: bool) -> Result<Vec<Change>, Error> {
+        let fs = self.container.filesystem();
+        if !fs.upper_path().is_dir() {
+            return Err(Error::new("The DevEnv has not been created yet"));
+        }
+        Differ::new(&fs.upper_path(), fs.layers(), fs.hidden_paths(), contents).changes()
+    }
+
+    /// Import an archive made by `export`. The upper layer of an archive replaces the one of the
+    /// DevEnv, which must be stopped. A root filesystem is added as the committed layer `layer`.
+    pub fn import(&self, path: &Path, layer: Option<&str>) -> Result<(), Error> {
+        let manifest = archive::read_manifest(path)?;
+        match (manifest.kind, layer) {
+            (ArchiveKind::Rootfs, Some(layer)) => {
+                LayerStore::new()?.add(layer, &[], &manifest.config_hash, |root| archive::extract(path, &manifest, root))?;
+                Ok(())
+            }
+            (ArchiveKind::Rootfs, None) => Err(Error::new("The archive has a root filesystem, choose the name of the layer to import it as")),
+            (ArchiveKind::Upper, Some(_)) => Err(Error::new("The archive only has an upper layer, it cannot be imported as a layer")),
+            (ArchiveKind::Upper, None) => {
+                if self.is_attached() {
+                    return Err(Error::new("Cannot import an archive while the DevEnv is running"));
+                }
+                let fs = self.container.filesystem();
+                if manifest.layers != fs.layers() {
+                    warn!("The archive was made on top of the layers {:?}, but the DevEnv uses {:?}", manifest.layers, fs.layers());
+                }
+                fs::create_dir_all(fs.target_path())?;
+                // Next to the upper layer, to replace it with a rename
+                let staging = fs.target_path().join(DevEnv::IMPORT_DIR);
+                if staging.exists() {
+                    fs::remove_dir_all(&staging)?;
+                }
+                if let Err(e) = archive::extract(path, &manifest, &staging) {
+                    if let Err(e) = fs::remove_dir_all(&staging) {
+                        warn!("Could not remove {:?}: {}", staging, e);
+                    }
+                    return Err(e);
+                }
+                fs.replace_upper(&staging)
+            }
+        }
+    }
+
+    /// Bootstrap the image named in the configuration, which any configuration can then use by
+    /// its name. An image that was already built is only built again if `rebuild` is set.
+    pub fn build_image(config: &Configuration, rebuild: bool) -> Result<StoredImage, Error> {
+        let (name, bootstrap) = match &config.image {
+            Some(Image { name: Some(name), bootstrap: Some(bootstrap), .. }) => (name, bootstrap),
+            Some(Image { name: Some(name), .. }) => return Err(Error::new(format!("The configuration does not say how to bootstrap the image {}", name).as_str())),
+            _ => return Err(Error::new("The configuration does not name an image"))
+        };
+        let store = ImageStore::new()?;
+        if !rebuild {
+            if let Ok(image) = store.get(name) {
+                info!("The image {} is already built", name);
+                return Ok(image);
+            }
+        }
+        let bootstrapper = Bootstrapper::new(bootstrap)?;
+        store.add(name, |root| bootstrapper.run(root))
+    }
+
+    /// Images and layers of the user, with their details
+    pub fn list_images() -> Result<Vec<EntryDetails>, Error> {
+        let stores = Stores::new()?;
+        let environments = DevEnv::environments()?;
+        let entries = stores.entries()?;
+        entries.iter().map(|entry| stores.details(entry, &environments, &entries)).collect()
+    }
+
+    /// Details of the image or layer `name`. Images are looked for first, unless `kind` is given.
+    pub fn inspect_image(name: &str, kind: Option<EntryKind>) -> Result<EntryDetails, Error> {
+        let stores = Stores::new()?;
+        let entries = stores.entries()?;
+        stores.details(&stores.find(name, kind)?, &DevEnv::environments()?, &entries)
+    }
+
+    /// Remove the image or layer `name`. It is refused while a DevEnv or another layer uses it,
+    /// unless `force` is set.
+    pub fn remove_image(name: &str, kind: Option<EntryKind>, force: bool) -> Result<StoreEntry, Error> {
+        let stores = Stores::new()?;
+        let entry = stores.find(name, kind)?;
+        if !force {
+            if let Some(environment) = DevEnv::environments()?.iter().find(|environment| environment.layers.contains(&entry.path)) {
+                return Err(Error::new(format!("The {} {} is used by the DevEnv {:?}", entry.kind, entry.name, environment.target).as_str()));
+            }
+            if let Some(layer) = stores.entries()?.iter().find(|layer| layer.parents.contains(&entry.path)) {
+                return Err(Error::new(format!("The {} {} is below the layer {}", entry.kind, entry.name, layer.name).as_str()));
+            }
+        }
+        stores.remove(&entry)?;
+        Ok(entry)
+    }
+
+    /// Remove the images and layers that no registered DevEnv uses, and forget the DevEnvs
+    /// deleted without devenv. Only lists them if `dry_run` is set.
+    pub fn prune_images(dry_run: bool) -> Result<Vec<StoreEntry>, Error> {
+        let stores = Stores::new()?;
+        let registry = Registry::new()?;
+        let (environments, deleted): (Vec<Environment>, Vec<Environment>) = registry.list()?.into_iter().partition(|environment| environment.target.is_dir());
+        let mut used: HashSet<PathBuf> = environments.into_iter().flat_map(|environment| environment.layers).collect();
+        let entries = stores.entries()?;
+        // The layers below the ones in use
+        for entry in &entries {
+            if used.contains(&entry.path) {
+                used.extend(entry.parents.iter().cloned());
+            }
+        }
+        let unused: Vec<StoreEntry> = entries.into_iter().filter(|entry| !used.contains(&entry.path)).collect();
+        if dry_run {
+            return Ok(unused);
+        }
+        for environment in deleted {
+            debug!("Forgetting the deleted DevEnv {:?}", environment.target);
+            registry.unregister(&environment.target)?;
+        }
+        for entry in &unused {
+            info!("Removing the {} {}", entry.kind, entry.name);
+            stores.remove(entry)?;
+        }
+        Ok(unused)
+    }
+
+    /// Registered DevEnvs whose directory still exists
+    fn environments() -> Result<Vec<Environment>, Error> {
+        Ok(Registry::new()?.list()?.into_iter().filter(|environment| environment.target.is_dir()).collect())
+    }
+
+    /// Image of the DevEnv as referenced in its configuration
+    fn image_references(&self) -> Vec<String> {
+        match self.config.as_ref().and_then(|config| config.image.as_ref()) {
+            Some(image) => image.name.iter().chain(&image.path).chain(image.layers.iter().flatten()).cloned().collect(),
+            None => vec![]
+        }
+    }
+
+    fn config_hash(config: &str) -> String {
+        blake2b_simd::blake2b(config.as_bytes()).to_hex().to_string()
+    }
+
+    /// Paths of the layers of an image, from the bottom one. Built images and committed layers
+    /// are referenced by name, and committed layers bring the layers they were committed on top
+    /// of. The layers of OCI images are unpacked the first time they are used.
+    fn resolve_layers(image: &Image) -> Result<Vec<PathBuf>, Error> {
+        let mut layers: Vec<PathBuf> = vec![];
+        if let Some(name) = &image.name {
+            if image.path.is_some() {
+                warn!("The image {} is used instead of the path of the image", name);
+            }
+            match ImageStore::new().and_then(|store| store.get(name)) {
+                Ok(image) => layers.push(image.path),
+                Err(e) => {
+                    // Left as is, the filesystem will refuse it
+                    error!("Could not resolve the image {}: {}", name, e);
+                    layers.push(PathBuf::from(name));
+                }
+            }
+        }
+        let path = image.path.iter().filter(|_| image.name.is_none()).map(|path| (path, image.tag.as_deref()));
+        let references = path.chain(image.layers.iter().flatten().map(|layer| (layer, None)));
+        for (reference, tag) in references {
+            let resolved = if LayerStore::is_layer_name(reference) {
+                LayerStore::new().and_then(|store| store.resolve(reference))
+            }
+            else if OciImage::is_image(Path::new(reference)) {
+                match OciImage::layer_cache().and_then(|cache| OciImage::open(Path::new(reference), tag)?.unpack(&cache)) {
+                    Ok(layers) => Ok(layers),
+                    Err(e) => return Err(Error::new(format!("Could not unpack the image {}: {}", reference, e.message()).as_str()))
+                }
+            }
+            else {
+                Ok(vec![PathBuf::from(reference)])
+            };
+            match resolved {
+                Ok(resolved) => {
+                    // Layers shared with the ones below are only stacked once
+                    for layer in resolved {
+                        if !layers.contains(&layer) {
+                            layers.push(layer);
+                        }
+                    }
+                }
+                Err(e) => {
+                    // Left as is, the filesystem will refuse it
+                    error!("Could not resolve the layer {}: {}", reference, e);
+                    layers.push(PathBuf::from(reference));
+                }
             }
         }
+        Ok(layers)
+    }
+
+    /// Stop the container once the tasks sent before have finished
+    pub fn exit(&self) -> Result<(), Error> {
+        self.container.run_in_container(ContainerTask::Exit)?;
+        Ok(())
     }
 
     pub fn wait_for_container(&self) -> Result<(), Error> {
-        self.container.wait_for_container()
+        self.container.wait_for_container(self.stop_timeout())
+    }
+
+    /// Wait for a task to finish, passing its output to `output` if it is captured. The signals
+    /// received meanwhile are forwarded to the container.
+    pub fn wait_for_task(&self, task: PendingTask, output: impl FnMut(TaskOutput)) -> Result<TaskResult, Error> {
+        self.container.wait_for_task(task, self.stop_timeout(), output)
+    }
+
+    fn stop_timeout(&self) -> Duration {
+        let stop_timeout = match &self.config {
+            Some(config) => config.stop_timeout.unwrap_or(DevEnv::DEFAULT_STOP_TIMEOUT),
+            None => DevEnv::DEFAULT_STOP_TIMEOUT
+        };
+        Duration::from_secs(stop_timeout)
     }
 
 }
@@ -102,19 +677,76 @@ impl DevEnv {
 impl From<Configuration> for DevEnv {
 
     fn from(config: Configuration) -> DevEnv {
-        let image = match &config.image {
-            Some(i) => { &i.path }
-            None => { DevEnv::DEFAULT_IMAGE }
-        };
         let destination = match &config.dest {
             Some(dest) => { PathBuf::from(dest) }
             None => { env::current_dir().unwrap().join(DevEnv::DEFAULT_TARGET) }
         };
-        let fs = Filesystem::new(&image, &destination);
+        // The layers are resolved by resolve_image, which may have to unpack them
+        let mut fs = Filesystem::new(vec![], &destination);
+        if let Some(paths) = &config.masked_paths {
+            fs.set_masked_paths(paths.iter().map(PathBuf::from).collect());
+        }
+        if let Some(paths) = &config.readonly_paths {
+            fs.set_readonly_paths(paths.iter().map(PathBuf::from).collect());
+        }
+        for device in config.devices.iter().flatten() {
+            fs.add_device(&device.path, device.container_path.as_ref().map(Path::new));
+        }
+        if let Some(max_size) = &config.max_size {
+            fs.set_max_size(Some(max_size.0));
+        }
+        let mut container = Container::new(fs);
+        if let Some(ports) = &config.ports {
+            container.set_ports(ports.clone());
+        }
         return DevEnv {
-            container: Container::new(fs),
-            config: Some(config)
+            container,
+            config: Some(config),
+            image_resolved: false
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tasks(dependencies: &[(&str, &[&str])]) -> HashMap<String, Task> {
+        dependencies.iter().map(|(name, depends_on)| {
+            let task = Task {
+                command: format!("echo {}", name),
+                env: None,
+                workdir: None,
+                depends_on: Some(depends_on.iter().map(|dependency| dependency.to_string()).collect())
+            };
+            (name.to_string(), task)
+        }).collect()
+    }
+
+    fn order(tasks: &HashMap<String, Task>, name: &str) -> Result<Vec<String>, Error> {
+        let mut order = vec![];
+        DevEnv::task_order(tasks, name, &mut vec![], &mut order)?;
+        Ok(order)
+    }
+
+    #[test]
+    fn orders_a_diamond_of_tasks() {
+        let tasks = tasks(&[("test", &["build", "lint"]), ("build", &["fetch"]), ("lint", &["fetch"]), ("fetch", &[])]);
+        assert_eq!(order(&tasks, "test").unwrap(), vec!["fetch", "build", "lint", "test"]);
+        assert_eq!(order(&tasks, "fetch").unwrap(), vec!["fetch"]);
+    }
+
+    #[test]
+    fn rejects_a_cycle_of_tasks() {
+        let tasks = tasks(&[("test", &["build"]), ("build", &["generate"]), ("generate", &["build"])]);
+        let error = order(&tasks, "test").unwrap_err();
+        assert_eq!(error.message(), "Tasks depend on each other: test -> build -> generate -> build");
+    }
+
+    #[test]
+    fn rejects_an_unknown_dependency() {
+        let tasks = tasks(&[("test", &["build"])]);
+        assert_eq!(order(&tasks, "test").unwrap_err().message(), "Unknown task build");
+        assert_eq!(order(&tasks, "deploy").unwrap_err().message(), "Unknown task deploy");
+    }
+}
@@ -0,0 +1,378 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{BufReader, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use serde_derive::Serialize;
+use similar::TextDiff;
+use devenv_common::error::Error;
+
+use crate::layers::{is_opaque, is_whiteout, RootView};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted
+}
+
+impl ChangeKind {
+
+    /// Letter shown before the path, like `git status --short`
+    pub fn symbol(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D'
+        }
+    }
+
+}
+
+impl fmt::Display for ChangeKind {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeKind::Added => write!(f, "added"),
+            ChangeKind::Modified => write!(f, "modified"),
+            ChangeKind::Deleted => write!(f, "deleted")
+        }
+    }
+
+}
+
+/// A path of the DevEnv that differs from its image
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// Absolute path inside the DevEnv
+    pub path: PathBuf,
+    /// Unified diff of the contents, for text files when it was asked for
+    pub diff: Option<String>
+}
+
+/// Finds the changes kept in the upper layer of an overlay, by comparing it with the lower layers
+/// the way overlayfs merges them: whiteouts delete the entries below, and opaque directories
+/// replace the directories below.
+pub struct Differ {
+    upper: PathBuf,
+    /// The image, without the upper layer
+    lower: Option<RootView>,
+    /// Paths relative to the root that are not reported
+    ignored: Vec<PathBuf>,
+    contents: bool
+}
+
+impl Differ {
+
+    /// Larger files are not diffed
+    const MAX_DIFF_SIZE: u64 = 1024 * 1024;
+
+    /// `lowers` are the layers of the image, from the bottom one. With `contents`, the changes of
+    /// text files come with a diff.
+    pub fn new(upper: &Path, lowers: &[PathBuf], ignored: Vec<PathBuf>, contents: bool) -> Differ {
+        let mut lowers: Vec<PathBuf> = lowers.iter().rev().cloned().collect();
+        let lower = match lowers.is_empty() {
+            true => None,
+            false => Some(RootView::Layers {
+                upper: lowers.remove(0),
+                lowers
+            })
+        };
+        Differ {
+            upper: upper.to_path_buf(),
+            lower,
+            ignored,
+            contents
+        }
+    }
+
+    /// Changes sorted by path
+    pub fn changes(&self) -> Result<Vec<Change>, Error> {
+        let mut changes = vec![];
+        self.walk(Path::new(""), &mut changes)?;
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+
+    fn walk(&self, dir: &Path, changes: &mut Vec<Change>) -> Result<(), Error> {
+        let mut names: Vec<OsString> = fs::read_dir(self.upper.join(dir))?.map(|entry| entry.map(|entry| entry.file_name())).collect::<Result<_, _>>()?;
+        names.sort();
+        // The content of the directory in the image is hidden by an opaque directory
+        let replaces_lower = !dir.as_os_str().is_empty() && is_opaque(&self.upper.join(dir))
+            && self.lower_metadata(dir).is_some_and(|(_, metadata)| metadata.is_dir());
+        if replaces_lower {
+            for name in self.lower_view().read_dir(dir)? {
+                if names.contains(&name) {
+                    continue;
+                }
+                let path = dir.join(&name);
+                if let Some((lower, _)) = self.lower_metadata(&path) {
+                    self.push(changes, ChangeKind::Deleted, &path, None, Some(&lower))?;
+                }
+            }
+        }
+        for name in names {
+            let path = dir.join(&name);
+            if self.ignored.contains(&path) {
+                continue;
+            }
+            let upper = self.upper.join(&path);
+            let metadata = fs::symlink_metadata(&upper)?;
+            let lower = self.lower_metadata(&path);
+            if is_whiteout(&metadata) {
+                if let Some((lower, _)) = lower {
+                    self.push(changes, ChangeKind::Deleted, &path, None, Some(&lower))?;
+                }
+                continue;
+            }
+            match lower {
+                None => self.push(changes, ChangeKind::Added, &path, Some(&upper), None)?,
+                Some((lower, lower_metadata)) => {
+                    if Differ::differs(&upper, &metadata, &lower, &lower_metadata)? {
+                        self.push(changes, ChangeKind::Modified, &path, Some(&upper), Some(&lower))?;
+                    }
+                }
+            }
+            if metadata.is_dir() {
+                self.walk(&path, changes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lower_view(&self) -> &RootView {
+        // Only called when there is a lower layer
+        self.lower.as_ref().unwrap()
+    }
+
+    /// File of the image at `path`, if it exists
+    fn lower_metadata(&self, path: &Path) -> Option<(PathBuf, Metadata)> {
+        let lower = self.lower.as_ref()?.lookup(path)?;
+        let metadata = fs::symlink_metadata(&lower).ok()?;
+        Some((lower, metadata))
+    }
+
+    fn push(&self, changes: &mut Vec<Change>, kind: ChangeKind, path: &Path, new: Option<&Path>, old: Option<&Path>) -> Result<(), Error> {
+        let diff = match self.contents {
+            true => self.diff(path, new, old)?,
+            false => None
+        };
+        changes.push(Change {
+            kind,
+            path: Path::new("/").join(path),
+            diff
+        });
+        Ok(())
+    }
+
+    /// Whether a file of the upper layer differs from the one it replaces in the image. The
+    /// modification time of directories is ignored, it changes with their entries.
+    fn differs(upper: &Path, metadata: &Metadata, lower: &Path, lower_metadata: &Metadata) -> Result<bool, Error> {
+        let file_type = metadata.file_type();
+        if file_type != lower_metadata.file_type() || metadata.mode() != lower_metadata.mode()
+            || metadata.uid() != lower_metadata.uid() || metadata.gid() != lower_metadata.gid() {
+            return Ok(true);
+        }
+        if file_type.is_dir() {
+            return Ok(false);
+        }
+        if metadata.mtime() != lower_metadata.mtime() || metadata.mtime_nsec() != lower_metadata.mtime_nsec() {
+            return Ok(true);
+        }
+        if file_type.is_symlink() {
+            return Ok(fs::read_link(upper)? != fs::read_link(lower)?);
+        }
+        if file_type.is_block_device() || file_type.is_char_device() {
+            return Ok(metadata.rdev() != lower_metadata.rdev());
+        }
+        if file_type.is_file() {
+            return Ok(metadata.len() != lower_metadata.len() || !Differ::same_contents(upper, lower)?);
+        }
+        Ok(false)
+    }
+
+    fn same_contents(a: &Path, b: &Path) -> Result<bool, Error> {
+        let mut a = BufReader::new(File::open(a)?);
+        let mut b = BufReader::new(File::open(b)?);
+        let mut buffer_a = [0u8; 8192];
+        let mut buffer_b = [0u8; 8192];
+        loop {
+            let read = a.read(&mut buffer_a)?;
+            if read == 0 {
+                return Ok(true);
+            }
+            b.read_exact(&mut buffer_b[..read])?;
+            if buffer_a[..read] != buffer_b[..read] {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Unified diff between the old and the new version of a text file, a missing version being
+    /// empty. None if either is not a text file.
+    fn diff(&self, path: &Path, new: Option<&Path>, old: Option<&Path>) -> Result<Option<String>, Error> {
+        let old_text = match old.map(Differ::read_text).transpose()? {
+            Some(None) => return Ok(None),
+            text => text.flatten()
+        };
+        let new_text = match new.map(Differ::read_text).transpose()? {
+            Some(None) => return Ok(None),
+            text => text.flatten()
+        };
+        if old_text.is_none() && new_text.is_none() {
+            return Ok(None);
+        }
+        let old_name = old_text.as_ref().map(|_| format!("a/{}", path.display())).unwrap_or_else(|| "/dev/null".to_owned());
+        let new_name = new_text.as_ref().map(|_| format!("b/{}", path.display())).unwrap_or_else(|| "/dev/null".to_owned());
+        let old_text = old_text.unwrap_or_default();
+        let new_text = new_text.unwrap_or_default();
+        let diff = TextDiff::from_lines(old_text.as_str(), new_text.as_str());
+        Ok(Some(diff.unified_diff().header(&old_name, &new_name).to_string()))
+    }
+
+    /// Contents of a regular file, None if it is not text
+    fn read_text(path: &Path) -> Result<Option<String>, Error> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_file() || metadata.len() > Differ::MAX_DIFF_SIZE {
+            return Ok(None);
+        }
+        let contents = fs::read(path)?;
+        if contents.contains(&0) {
+            return Ok(None);
+        }
+        Ok(String::from_utf8(contents).ok())
+    }
+
+}
+
+/// A change as written by `changes_to_json`
+#[derive(Serialize)]
+struct JsonChange<'a> {
+    kind: String,
+    path: String,
+    diff: Option<&'a str>
+}
+
+/// The changes as a JSON array of objects with their `kind`, `path` and `diff`
+pub fn changes_to_json(changes: &[Change]) -> Result<String, Error> {
+    let changes: Vec<JsonChange> = changes.iter().map(|change| JsonChange {
+        kind: change.kind.to_string(),
+        path: change.path.to_string_lossy().into_owned(),
+        diff: change.diff.as_deref()
+    }).collect();
+    match serde_json::to_string(&changes) {
+        Ok(json) => Ok(json),
+        Err(e) => Err(Error::new_error("Could not write the changes as JSON", Box::from(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use nix::unistd::geteuid;
+    use crate::layers::{make_whiteout, set_opaque, set_times};
+
+    fn changes(upper: &Path, lower: &Path) -> Vec<(ChangeKind, PathBuf)> {
+        Differ::new(upper, &[lower.to_path_buf()], vec![], false).changes().unwrap()
+            .into_iter()
+            .map(|change| (change.kind, change.path))
+            .collect()
+    }
+
+    fn layers() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let (upper, lower) = (dir.path().join("upper"), dir.path().join("lower"));
+        fs::create_dir(&upper).unwrap();
+        fs::create_dir(&lower).unwrap();
+        (dir, upper, lower)
+    }
+
+    #[test]
+    fn reports_a_whiteout_as_deleted() {
+        if !geteuid().is_root() {
+            eprintln!("Skipping, creating a whiteout needs root");
+            return;
+        }
+        let (_dir, upper, lower) = layers();
+        fs::create_dir(lower.join("etc")).unwrap();
+        fs::write(lower.join("etc/motd"), "hello\n").unwrap();
+        fs::create_dir(upper.join("etc")).unwrap();
+        make_whiteout(&upper.join("etc/motd")).unwrap();
+
+        assert_eq!(changes(&upper, &lower), vec![(ChangeKind::Deleted, PathBuf::from("/etc/motd"))]);
+    }
+
+    #[test]
+    fn reports_the_lower_children_of_an_opaque_directory_as_deleted() {
+        if !geteuid().is_root() {
+            eprintln!("Skipping, marking a directory opaque needs root");
+            return;
+        }
+        let (_dir, upper, lower) = layers();
+        fs::create_dir(lower.join("cache")).unwrap();
+        fs::write(lower.join("cache/a"), "a").unwrap();
+        fs::create_dir(lower.join("cache/b")).unwrap();
+        fs::create_dir(upper.join("cache")).unwrap();
+        set_opaque(&upper.join("cache")).unwrap();
+        fs::write(upper.join("cache/c"), "c").unwrap();
+
+        assert_eq!(changes(&upper, &lower), vec![
+            (ChangeKind::Deleted, PathBuf::from("/cache/a")),
+            (ChangeKind::Deleted, PathBuf::from("/cache/b")),
+            (ChangeKind::Added, PathBuf::from("/cache/c"))
+        ]);
+    }
+
+    #[test]
+    fn reports_a_change_of_contents_as_modified() {
+        let (_dir, upper, lower) = layers();
+        for (name, old, new) in &[("same", "one", "one"), ("changed", "one", "two"), ("grown", "one", "three")] {
+            fs::write(lower.join(name), old).unwrap();
+            fs::write(upper.join(name), new).unwrap();
+            // Only the contents tell them apart
+            set_times(&upper.join(name), &fs::metadata(lower.join(name)).unwrap()).unwrap();
+        }
+
+        assert_eq!(changes(&upper, &lower), vec![
+            (ChangeKind::Modified, PathBuf::from("/changed")),
+            (ChangeKind::Modified, PathBuf::from("/grown"))
+        ]);
+    }
+
+    #[test]
+    fn reports_a_change_of_modification_time_as_modified() {
+        let (_dir, upper, lower) = layers();
+        fs::write(lower.join("touched"), "same").unwrap();
+        fs::write(upper.join("touched"), "same").unwrap();
+        let modified = fs::metadata(lower.join("touched")).unwrap().modified().unwrap();
+        File::options().write(true).open(upper.join("touched")).unwrap().set_modified(modified + Duration::from_secs(1)).unwrap();
+
+        assert_eq!(changes(&upper, &lower), vec![(ChangeKind::Modified, PathBuf::from("/touched"))]);
+    }
+}
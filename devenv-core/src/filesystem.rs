@@ -23,20 +23,55 @@
 
 use libmount::{Overlay, Tmpfs};
 use std::path::{Path, PathBuf};
-use std::fs;
-use log::{warn, error};
+use std::fs::{self, File, OpenOptions};
+use std::process::Command;
+use log::{debug, error, info, warn};
 use devenv_common::error::Error;
 
 use semver::Version;
-use nix::{libc::{S_IFCHR, S_IRUSR, S_IWUSR}, sys::{stat::{Mode, SFlag}, utsname::uname}};
+use nix::{libc::{self, S_IFCHR, S_IRUSR, S_IWUSR}, sys::{stat::{Mode, SFlag}, utsname::uname}};
 use crate::mount::{MountingPoint, MTab, FsType};
 use nix::mount::{MsFlags, umount};
-use nix::sys::stat::{mknod, makedev};
+use nix::sys::statvfs::statvfs;
+use nix::sys::stat::{mknod, makedev, stat, dev_t};
+use nix::errno::Errno;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt};
 use crate::mount::mount;
+use crate::layers::{disk_usage, make_whiteout, RootView};
+use uuid::Uuid;
 
 pub struct Filesystem {
-    imagepath: PathBuf,
-    targetpath: PathBuf
+    // Read-only layers of the image, from the bottom one
+    layers: Vec<PathBuf>,
+    targetpath: PathBuf,
+    masked_paths: Vec<PathBuf>,
+    readonly_paths: Vec<PathBuf>,
+    // Host devices made available inside the container, as (host path, container path)
+    devices: Vec<(PathBuf, PathBuf)>,
+    // Bytes the DevEnv can use
+    max_size: Option<u64>
+}
+
+/// Space used by the upper layer of a DevEnv
+#[derive(Debug)]
+pub struct DiskUsage {
+    /// Bytes used by each entry of the root of the upper layer, e.g. `/usr`
+    pub entries: Vec<(PathBuf, u64)>,
+    /// Bytes used by the whole upper layer
+    pub total: u64,
+    /// The filesystem of the DevEnv, when it has one of its own
+    pub storage: Option<StorageUsage>
+}
+
+/// Space of the filesystem mounted on the DevEnv: the tmpfs, or the filesystem sized by `max_size`.
+/// Snapshots and the work directory of the overlay are kept in it too.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageUsage {
+    pub size: u64,
+    pub used: u64,
+    pub available: u64
 }
 
 impl Filesystem {
@@ -47,7 +82,63 @@ impl Filesystem {
 
     const WORK_DIR: &'static str = "workdir";
 
-    pub fn new(image: &impl AsRef<Path>, target: &impl AsRef<Path>) -> Filesystem {
+    // The mount options of the overlay must fit in a page, along with the upper and work dirs
+    const MAX_LOWERDIR_LENGTH: usize = 3072;
+
+    // Flag of renameat2, not exported by the libc crate
+    const RENAME_EXCHANGE: libc::c_uint = 1 << 1;
+
+    // Extension of the file next to the DevEnv that holds its filesystem when it has a max_size
+    const STORAGE_EXTENSION: &'static str = "img";
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    // Writes fail before the space available reaches zero, as ext4 keeps 2% of its blocks, up to
+    // this, for its own use
+    const MAX_RESERVED_SPACE: u64 = 16 * 1024 * 1024;
+
+    // Relative to the root of the container
+    const MACHINE_ID_FILE: &'static str = "etc/machine-id";
+
+    // Written on every boot, and bind-mounted over the boot id of the kernel
+    const BOOT_ID_FILE: &'static str = "/run/devenv/boot_id";
+
+    /// Paths inside the container that are hidden from its processes. Files are covered with
+    /// `/dev/null` and directories with an empty, read-only tmpfs.
+    const DEFAULT_MASKED_PATHS: &'static [&'static str] = &[
+        "/proc/acpi",
+        "/proc/asound",
+        "/proc/kcore",
+        "/proc/keys",
+        "/proc/latency_stats",
+        "/proc/timer_list",
+        "/proc/timer_stats",
+        "/proc/sched_debug",
+        "/proc/scsi",
+        "/sys/firmware",
+        "/sys/devices/virtual/powercap"
+    ];
+
+    /// Symbolic links every /dev is expected to have, as (link, target)
+    const DEV_SYMLINKS: &'static [(&'static str, &'static str)] = &[
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+        ("ptmx", "pts/ptmx")
+    ];
+
+    /// Paths inside the container that are remounted read-only
+    const DEFAULT_READONLY_PATHS: &'static [&'static str] = &[
+        "/proc/bus",
+        "/proc/fs",
+        "/proc/irq",
+        "/proc/sysrq-trigger"
+    ];
+
+    /// Create the filesystem of a container whose image is made of `layers`, from the bottom one
+    /// (e.g. a base rootfs, then a toolchain shared by several DevEnvs).
+    pub fn new(layers: Vec<PathBuf>, target: &impl AsRef<Path>) -> Filesystem {
         // Overlayfs was introduced in kernel version 3.18
         match Filesystem::is_kernel_version_compatible("3.18.0") {
             Ok(true) => {} // The Kernel version is compatible
@@ -56,18 +147,45 @@ impl Filesystem {
             }
         }
         return Filesystem {
-            imagepath: image.as_ref().to_path_buf(),
+            layers,
             targetpath:  target.as_ref().to_path_buf(),
+            masked_paths: Filesystem::DEFAULT_MASKED_PATHS.iter().map(PathBuf::from).collect(),
+            readonly_paths: Filesystem::DEFAULT_READONLY_PATHS.iter().map(PathBuf::from).collect(),
+            devices: vec![],
+            max_size: None
         };
     }
 
+    /// Replace the default list of paths masked inside the container
+    pub fn set_masked_paths(&mut self, paths: Vec<PathBuf>) {
+        self.masked_paths = paths;
+    }
+
+    /// Replace the default list of paths mounted read-only inside the container
+    pub fn set_readonly_paths(&mut self, paths: Vec<PathBuf>) {
+        self.readonly_paths = paths;
+    }
+
+    /// Limit the space used by the DevEnv, rounded down to whole blocks
+    pub fn set_max_size(&mut self, size: Option<u64>) {
+        self.max_size = size.map(|size| size - size % Filesystem::BLOCK_SIZE);
+    }
+
+    /// Pass a host device through to the container. If `container_path` is `None` the device
+    /// keeps the same path inside the container.
+    pub fn add_device(&mut self, host_path: &impl AsRef<Path>, container_path: Option<&Path>) {
+        let host_path = host_path.as_ref().to_path_buf();
+        let container_path = container_path.map(Path::to_path_buf).unwrap_or_else(|| host_path.clone());
+        self.devices.push((host_path, container_path));
+    }
+
     /// Mount an overlayfs that will be used as the filesystem for the container.
     /// 
     /// Overlayfs works by combining several layers of read-only directories (lowerdirs), with a read/write 
     /// directory on top (upperdir). The writes to the resulting filesystem will be saved in the upperdir.
     /// 
     /// This is how the Overlayfs directories look in DevEnv 
-    ///     lowerdirs = the container image
+    ///     lowerdirs = the layers of the container image, shared with other DevEnvs
     ///     upperdir = .devenv/upper
     ///     workdir = .devenv/work
     ///     target = .devenv/merge
@@ -77,53 +195,233 @@ impl Filesystem {
     ///         workdir/
     ///         merge/
     /// 
-    /// It is possible to use the current rootfs as the container's image, but as Linux does not allow
-    /// to have circular references inside the same filesystem we must put the Overlayfs inside another
-    /// filesystem. In this case, a tmpfs. The downside of this election is that the contents of a tmpfs
-    /// are stored in memory, and changes are lost when rebooting.
+    /// It is possible to use the current rootfs as the container's image. Then the DevEnv is inside
+    /// a lower layer, which overlayfs allows as long as the upper layer cannot be reached through
+    /// the overlay, so the DevEnv is hidden with a whiteout and its changes persist like with any
+    /// other image. Kernels that refuse the overlapping layers get the overlay inside a tmpfs
+    /// instead, whose contents are stored in memory and lost when rebooting.
+    ///
+    /// With a max_size, the DevEnv gets a filesystem of that size, an ext4 image next to it
+    /// mounted through a loop device. A tmpfs is sized instead.
     /// 
     pub fn mount(&self) -> Result<(), Error> {
-        let mtab = MTab::new();
+        self.validate_layers()?;
+        let mut mtab = MTab::new();
         if !self.targetpath.exists() {
             fs::create_dir(&self.targetpath).unwrap();
         }
-        // If the image path contains the target path, we need to mount the merge/target
-        // directory inside a Tmpfs. Otherwise it will fail due to cyclic references.
-        if self.targetpath.ancestors().any(|x| x == self.imagepath) { 
-            warn!("The image path contains the devenv path. All non-persisted changes will be lost at reboot.");
-            if !mtab.contains(MountingPoint::new(None, &self.targetpath.join(Filesystem::MERGE_DIR), Some(FsType::Overlay))) {
-                let targetfs = Tmpfs::new(&self.targetpath);
-                match targetfs.mount() {
-                    Ok(_) => {}
-                    Err(e) => { return Err(Error::new("Could not mount tmpfs")) }
+        // The filesystem of the DevEnv is mounted again to be created or resized
+        let storage_changed = match (self.storage(&mtab), self.max_size) {
+            (None, Some(_)) => true,
+            (Some(FsType::Ext4), Some(size)) => fs::metadata(self.storage_image()).is_ok_and(|metadata| metadata.len() != size),
+            _ => false
+        };
+        // The overlay stays mounted after devenv exits, maybe with layers that are not used anymore
+        if let Some(lowerdir) = mtab.get(&self.targetpath.join(Filesystem::MERGE_DIR), FsType::Overlay).and_then(Filesystem::lowerdir_option) {
+            if lowerdir != self.lowerdir() || storage_changed {
+                info!("The layers of the image or its max_size changed, mounting the overlay again");
+                if let Err(e) = umount(&self.targetpath.join(Filesystem::MERGE_DIR)) {
+                    error!("Could not unmount the overlay with the old layers, is the DevEnv running?");
+                    return Err(Error::from(e));
+                }
+                mtab = MTab::new();
+            }
+        }
+        if storage_changed && self.storage(&mtab) == Some(FsType::Ext4) {
+            umount(&self.targetpath)?;
+            mtab = MTab::new();
+        }
+        // Kept until the tmpfs is unmounted, e.g. when rebooting
+        let storage = self.storage(&mtab);
+        if let (Some(FsType::Tmpfs), Some(size)) = (&storage, self.max_size) {
+            self.resize_tmpfs(size)?;
+        }
+        if mtab.contains(MountingPoint::new(None, &self.targetpath.join(Filesystem::MERGE_DIR), Some(FsType::Overlay))) {
+            return Ok(());
+        }
+        if let (None, Some(size)) = (&storage, self.max_size) {
+            self.mount_storage(size)?;
+        }
+        self.create_overlay_dirs()?;
+        let in_image = self.is_target_in_image();
+        if in_image && storage != Some(FsType::Tmpfs) {
+            self.hide_target()?;
+        }
+        match self.mount_overlay() {
+            Ok(_) => Ok(()),
+            Err(e) if !in_image || storage.is_some() || self.max_size.is_some() => Err(e),
+            Err(_) => {
+                warn!("The kernel does not allow the image path to contain the devenv path. All non-persisted changes will be lost at reboot.");
+                if let Err(e) = Tmpfs::new(&self.targetpath).mount() {
+                    return Err(Error::new(format!("Could not mount tmpfs: {}", e).as_str()));
+                }
+                self.create_overlay_dirs()?;
+                self.mount_overlay()
+            }
+        }
+    }
+
+    /// Type of the filesystem mounted on the DevEnv, if it has one of its own
+    fn storage(&self, mtab: &MTab) -> Option<FsType> {
+        [FsType::Tmpfs, FsType::Ext4].iter().find(|fstype| mtab.get(&self.targetpath, (*fstype).clone()).is_some()).cloned()
+    }
+
+    /// File holding the filesystem of a DevEnv with a max_size, e.g. `.devenv.img`
+    fn storage_image(&self) -> PathBuf {
+        let mut image = self.targetpath.clone().into_os_string();
+        image.push(".");
+        image.push(Filesystem::STORAGE_EXTENSION);
+        PathBuf::from(image)
+    }
+
+    /// Mount the filesystem of `size` bytes on the DevEnv, creating it or resizing it first.
+    /// The DevEnv must be empty when it is created, or its contents would be hidden.
+    fn mount_storage(&self, size: u64) -> Result<(), Error> {
+        let image = self.storage_image();
+        match fs::metadata(&image) {
+            Err(_) => {
+                if fs::read_dir(&self.targetpath)?.next().is_some() {
+                    return Err(Error::new("max_size can only be set on a new DevEnv. Delete it, or export its changes with `devenv export --upper` and import them into a new one"));
+                }
+                debug!("Creating a filesystem of {} bytes in {:?}", size, image);
+                File::create(&image)?.set_len(size)?;
+                if let Err(e) = Filesystem::run_tool(Command::new("mkfs.ext4").args(["-q", "-m", "0"]).arg(&image)) {
+                    fs::remove_file(&image)?;
+                    return Err(e);
+                }
+            }
+            Ok(metadata) if metadata.len() != size => {
+                info!("Resizing the filesystem of the DevEnv from {} to {} bytes", metadata.len(), size);
+                if size > metadata.len() {
+                    OpenOptions::new().write(true).open(&image)?.set_len(size)?;
+                }
+                // resize2fs refuses filesystems that were not checked
+                Filesystem::run_tool(Command::new("e2fsck").args(["-f", "-p"]).arg(&image))?;
+                if let Err(e) = Filesystem::run_tool(Command::new("resize2fs").arg(&image).arg(format!("{}K", size / 1024))) {
+                    return Err(Error::new(format!("The DevEnv does not fit in a max_size of {} bytes: {}", size, e).as_str()));
                 }
+                OpenOptions::new().write(true).open(&image)?.set_len(size)?;
             }
+            Ok(_) => {}
         }
-        // Before mounting, create the Overlay directories
-        if !self.targetpath.join(Filesystem::MERGE_DIR).exists() {
-            fs::create_dir(self.targetpath.join(Filesystem::MERGE_DIR)).unwrap();
+        Filesystem::run_tool(Command::new("mount").args(["-t", "ext4", "-o", "loop"]).arg(&image).arg(&self.targetpath))
+    }
+
+    fn resize_tmpfs(&self, size: u64) -> Result<(), Error> {
+        let options = format!("size={}", size);
+        match nix::mount::mount(None::<&str>, &self.targetpath, None::<&str>, MsFlags::MS_REMOUNT, Some(options.as_str())) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::new(format!("Could not resize the tmpfs of the DevEnv to {} bytes, it may be using more: {}", size, e).as_str()))
         }
-        if !self.targetpath.join(Filesystem::UPPER_DIR).exists() {
-            fs::create_dir(self.targetpath.join(Filesystem::UPPER_DIR)).unwrap();
+    }
+
+    fn run_tool(command: &mut Command) -> Result<(), Error> {
+        debug!("Running {:?}", command);
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(e) => return Err(Error::new_error(format!("Could not run {:?}, is it installed?", command.get_program()).as_str(), Box::from(e)))
+        };
+        if !output.status.success() {
+            return Err(Error::new(format!("{:?} failed with {}: {}", command.get_program(), output.status, String::from_utf8_lossy(&output.stderr).trim()).as_str()));
         }
-        if !self.targetpath.join(Filesystem::WORK_DIR).exists() {
-            fs::create_dir(self.targetpath.join(Filesystem::WORK_DIR)).unwrap();
+        Ok(())
+    }
+
+    /// Space used by the upper layer, by each of its top-level entries
+    pub fn disk_usage(&self) -> Result<DiskUsage, Error> {
+        let upper = self.upper_path();
+        let mut entries = vec![];
+        for entry in fs::read_dir(&upper)? {
+            let path = entry?.path();
+            let size = disk_usage(&path)?;
+            entries.push((Path::new("/").join(path.strip_prefix(&upper).unwrap_or(&path)), size));
         }
-        if !mtab.contains(MountingPoint::new(None, &self.targetpath.join(Filesystem::MERGE_DIR), Some(FsType::Overlay))) {
-            let overlayfs = Overlay::writable(
-                vec![&self.imagepath].iter().map(|x| x.as_path()), 
-                self.targetpath.join(Filesystem::UPPER_DIR), 
-                self.targetpath.join(Filesystem::WORK_DIR), 
-                self.targetpath.join(Filesystem::MERGE_DIR),
-            );
-            match overlayfs.mount() {
-                Ok(_) => {}
-                Err(e) => { return Err(Error::new("Could not mount overlayfs")) }
+        entries.sort();
+        Ok(DiskUsage {
+            entries,
+            total: disk_usage(&upper)?,
+            storage: self.storage_usage()?
+        })
+    }
+
+    /// Space of the filesystem of the DevEnv, if it has one of its own
+    pub fn storage_usage(&self) -> Result<Option<StorageUsage>, Error> {
+        if self.storage(&MTab::new()).is_none() {
+            return Ok(None);
+        }
+        let stats = statvfs(&self.targetpath)?;
+        let block_size = stats.fragment_size() as u64;
+        Ok(Some(StorageUsage {
+            size: stats.blocks() as u64 * block_size,
+            used: (stats.blocks() - stats.blocks_free()) as u64 * block_size,
+            available: stats.blocks_available() as u64 * block_size
+        }))
+    }
+
+    /// Whether the filesystem of the DevEnv is full, so writes inside it fail
+    pub fn is_full(&self) -> bool {
+        match self.storage_usage() {
+            Ok(Some(usage)) => usage.available <= (usage.size / 50).min(Filesystem::MAX_RESERVED_SPACE),
+            _ => false
+        }
+    }
+
+    fn create_overlay_dirs(&self) -> Result<(), Error> {
+        for dir in &[Filesystem::MERGE_DIR, Filesystem::UPPER_DIR, Filesystem::WORK_DIR] {
+            if !self.targetpath.join(dir).exists() {
+                fs::create_dir(self.targetpath.join(dir))?;
             }
         }
         Ok(())
     }
 
+    fn mount_overlay(&self) -> Result<(), Error> {
+        let overlayfs = Overlay::writable(
+            self.lower_dirs().iter().map(|x| x.as_path()),
+            self.targetpath.join(Filesystem::UPPER_DIR), 
+            self.targetpath.join(Filesystem::WORK_DIR), 
+            self.targetpath.join(Filesystem::MERGE_DIR),
+        );
+        match overlayfs.mount() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::new(format!("Could not mount overlayfs: {}", e).as_str()))
+        }
+    }
+
+    /// Whether a layer of the image contains the DevEnv, e.g. when the image is the host root
+    fn is_target_in_image(&self) -> bool {
+        self.targetpath.ancestors().any(|x| self.layers.iter().any(|layer| x == layer))
+    }
+
+    /// Cover the DevEnv with a whiteout in the upper layer, so the container cannot see it. Writing
+    /// to the upper layer through a lower layer is not allowed by overlayfs.
+    fn hide_target(&self) -> Result<(), Error> {
+        let view = RootView::Layers {
+            upper: self.upper_path(),
+            lowers: self.lower_dirs()
+        };
+        for path in self.hidden_paths() {
+            let whiteout = view.writable_path(&path, false)?;
+            if fs::symlink_metadata(&whiteout).is_err() {
+                debug!("Hiding /{} from the DevEnv", path.display());
+                make_whiteout(&whiteout)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Paths of the DevEnv inside the image, relative to its root, that are hidden from the container
+    pub fn hidden_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.targetpath.clone()];
+        if self.max_size.is_some() {
+            paths.push(self.storage_image());
+        }
+        paths.iter()
+            .flat_map(|path| self.layers.iter().filter_map(move |layer| path.strip_prefix(layer).ok()))
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
     pub fn inner_mount(&self) -> Result<(), Error> {
         let mount_table: Vec<MountingPoint> = vec![
             MountingPoint::new_all(None, &PathBuf::from("/"), None, None, Some(MsFlags::MS_REC|MsFlags::MS_PRIVATE), Some(true), Some(true), Some(false)),
@@ -133,49 +431,210 @@ impl Filesystem {
             MountingPoint::new_all(None, &PathBuf::from("/proc/sys"), None, None, Some(MsFlags::MS_BIND|MsFlags::MS_RDONLY|MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV|MsFlags::MS_REMOUNT), Some(true), Some(true), Some(false)),
             MountingPoint::new_all(Some("tmpfs".to_owned()), &PathBuf::from("/sys"), Some(FsType::Tmpfs), Some("mode=755".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV), Some(true), Some(false), Some(true)),
             MountingPoint::new_all(Some("sysfs".to_owned()), &PathBuf::from("/sys"), Some(FsType::Sysfs), None, Some(MsFlags::MS_RDONLY|MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV), Some(true), Some(false), Some(false)),
-            MountingPoint::new_all(Some("tmpfs".to_owned()), &PathBuf::from("/dev"), Some(FsType::Tmpfs), Some("mode=755".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_STRICTATIME), Some(true), Some(false), Some(false)),
-            MountingPoint::new_all(Some("tmpfs".to_owned()), &PathBuf::from("/dev/shm"), Some(FsType::Tmpfs), Some("mode=1777".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_STRICTATIME|MsFlags::MS_NODEV), Some(true), Some(false), Some(false)),
             MountingPoint::new_all(Some("tmpfs".to_owned()), &PathBuf::from("/run"), Some(FsType::Tmpfs), Some("mode=755".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_STRICTATIME|MsFlags::MS_NODEV), Some(true), Some(false), Some(false)),
             MountingPoint::new_all(Some("tmpfs".to_owned()), &PathBuf::from("/tmp"), Some(FsType::Tmpfs), Some("mode=1777".to_owned()), Some(MsFlags::MS_STRICTATIME), Some(true), Some(false), Some(false)),
 
         ];
         for mounting_point in mount_table {
             fs::create_dir_all(&mounting_point.path)?;
-            match mount(&mounting_point) {
-                Ok(()) => {}
+            Filesystem::mount_entry(&mounting_point)?;
+        }
+        // /dev was populated by mount_dev before chrooting, so /dev/null is available for masking
+        for mounting_point in self.restricted_mount_table() {
+            Filesystem::mount_entry(&mounting_point)?;
+        }
+        Ok(())
+    }
+
+    /// Build the mount table for the masked and read-only paths. Paths that do not exist in the
+    /// container are skipped, as they depend on the running kernel, but the container does not
+    /// start if an existing one cannot be masked.
+    fn restricted_mount_table(&self) -> Vec<MountingPoint> {
+        let mut mount_table: Vec<MountingPoint> = vec![];
+        for path in &self.masked_paths {
+            if path.is_dir() {
+                mount_table.push(MountingPoint::new_all(Some("tmpfs".to_owned()), path, Some(FsType::Tmpfs), Some("size=0".to_owned()), Some(MsFlags::MS_RDONLY|MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV), Some(true), Some(true), Some(false)));
+            }
+            else if path.exists() {
+                mount_table.push(MountingPoint::new_all(Some("/dev/null".to_owned()), path, None, None, Some(MsFlags::MS_BIND), Some(true), Some(true), Some(false)));
+            }
+        }
+        for path in self.readonly_paths.iter().filter(|path| path.exists()) {
+            let source = path.to_str().map(str::to_owned);
+            mount_table.push(MountingPoint::new_all(source, path, None, None, Some(MsFlags::MS_BIND|MsFlags::MS_REC), Some(true), Some(true), Some(false)));
+            mount_table.push(MountingPoint::new_all(None, path, None, None, Some(MsFlags::MS_BIND|MsFlags::MS_REMOUNT|MsFlags::MS_RDONLY|MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV), Some(true), Some(true), Some(false)));
+        }
+        mount_table
+    }
+
+    /// Mount a single entry of a mount table. Failures on entries not marked as fatal are only logged.
+    fn mount_entry(mounting_point: &MountingPoint) -> Result<(), Error> {
+        match mount(mounting_point) {
+            Ok(()) => Ok(()),
+            Err(e) if mounting_point.fatal == Some(false) => {
+                warn!("Skipping {:?}: {}", mounting_point.path, e);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error mounting {:?}", mounting_point);
+                Err(e)
+            }
+        }
+    }
+
+    /// Set up what systemd expects to find when booting in a container: a writable cgroup
+    /// hierarchy, and a boot id of its own. A new boot id is generated on every boot and
+    /// bind-mounted over /proc/sys/kernel/random/boot_id, which cannot be written.
+    ///
+    /// Must be run AFTER chrooting and inner_mount.
+    pub fn prepare_boot(&self) -> Result<(), Error> {
+        let cgroup = MountingPoint::new_all(Some("cgroup2".to_owned()), &PathBuf::from("/sys/fs/cgroup"), Some(FsType::Cgroup2), None, Some(MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV), Some(true), Some(true), Some(false));
+        Filesystem::mount_entry(&cgroup)?;
+        // /run is a tmpfs private to this boot
+        let boot_id = Path::new(Filesystem::BOOT_ID_FILE);
+        if let Some(parent) = boot_id.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        debug!("Boot id: {}", id);
+        fs::write(boot_id, id + "\n")?;
+        let mount_table = vec![
+            MountingPoint::new_all(Some(Filesystem::BOOT_ID_FILE.to_owned()), &PathBuf::from("/proc/sys/kernel/random/boot_id"), None, None, Some(MsFlags::MS_BIND), Some(false), Some(true), Some(false)),
+            MountingPoint::new_all(None, &PathBuf::from("/proc/sys/kernel/random/boot_id"), None, None, Some(MsFlags::MS_BIND|MsFlags::MS_REMOUNT|MsFlags::MS_RDONLY|MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC|MsFlags::MS_NODEV), Some(false), Some(true), Some(false))
+        ];
+        for mounting_point in mount_table {
+            Filesystem::mount_entry(&mounting_point)?;
+        }
+        Ok(())
+    }
+
+    /// Give the DevEnv a machine id of its own, instead of the one of its image (which might be
+    /// the host's). The id is stored in the upper layer, so it is kept until the DevEnv is deleted.
+    ///
+    /// Must be run AFTER mount and BEFORE chrooting.
+    pub fn setup_machine_id(&self) -> Result<(), Error> {
+        let upper = self.targetpath.join(Filesystem::UPPER_DIR).join(Filesystem::MACHINE_ID_FILE);
+        // The upper layer might hold a copy of the image's id, e.g. after systemd committed it
+        let image_id = self.lower_dirs().iter()
+            .find_map(|layer| fs::read_to_string(layer.join(Filesystem::MACHINE_ID_FILE)).ok())
+            .unwrap_or_default();
+        let has_own_id = match fs::symlink_metadata(&upper) {
+            Ok(metadata) if metadata.is_file() => {
+                let id = fs::read_to_string(&upper).unwrap_or_default();
+                !id.trim().is_empty() && id.trim() != image_id.trim()
+            }
+            _ => false
+        };
+        if has_own_id {
+            return Ok(());
+        }
+        let etc = self.root_path().join("etc");
+        fs::create_dir_all(&etc)?;
+        let id = Uuid::new_v4().to_simple().to_string();
+        debug!("Machine id: {}", id);
+        // Written through the merged directory, so the overlay replaces the file of the image. It
+        // might be a symlink, which would be followed outside of the container.
+        let machine_id = self.root_path().join(Filesystem::MACHINE_ID_FILE);
+        if fs::symlink_metadata(&machine_id).is_ok() {
+            fs::remove_file(&machine_id)?;
+        }
+        fs::write(&machine_id, id + "\n")?;
+        Ok(())
+    }
+
+    /// Populate the /dev of the container: a tmpfs with the standard devices and symlinks, a private
+    /// devpts instance, /dev/shm and the devices passed through from the host.
+    ///
+    /// Must be run BEFORE chrooting, as devices are bind-mounted from the host's /dev when creating
+    /// device nodes is not permitted (e.g. rootless mode). It is safe to run it more than once.
+    pub fn mount_dev(&self) -> Result<(), Error> {
+        let root = self.root_path();
+        let dev = root.join("dev");
+        // Keep the mounts below from propagating back to the host
+        let private_root = MountingPoint::new_all(None, &root, None, None, Some(MsFlags::MS_REC|MsFlags::MS_PRIVATE), Some(true), Some(true), Some(false));
+        Filesystem::mount_entry(&private_root)?;
+        let mtab = MTab::new();
+        let mount_table: Vec<MountingPoint> = vec![
+            MountingPoint::new_all(Some("tmpfs".to_owned()), &dev, Some(FsType::Tmpfs), Some("mode=755".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_STRICTATIME), Some(true), Some(false), Some(false)),
+            MountingPoint::new_all(Some("tmpfs".to_owned()), &dev.join("shm"), Some(FsType::Tmpfs), Some("mode=1777".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_STRICTATIME|MsFlags::MS_NODEV), Some(true), Some(false), Some(false)),
+            MountingPoint::new_all(Some("devpts".to_owned()), &dev.join("pts"), Some(FsType::Devpts), Some("newinstance,ptmxmode=0666,mode=0620,gid=5".to_owned()), Some(MsFlags::MS_NOSUID|MsFlags::MS_NOEXEC), Some(true), Some(false), Some(false)),
+        ];
+        for mounting_point in mount_table {
+            if mtab.contains(MountingPoint::new(None, &mounting_point.path, mounting_point.fstype.clone())) {
+                continue;
+            }
+            fs::create_dir_all(&mounting_point.path)?;
+            Filesystem::mount_entry(&mounting_point)?;
+        }
+        self.create_dev_devices(&dev)?;
+        for (link, target) in Filesystem::DEV_SYMLINKS {
+            let link = dev.join(link);
+            if fs::symlink_metadata(&link).is_err() {
+                symlink(target, &link)?;
+            }
+        }
+        for (host_path, container_path) in &self.devices {
+            let device = match stat(host_path) {
+                Ok(device) => device,
                 Err(e) => {
-                    error!("Error mounting {:?}", mounting_point);
-                    return Err(e);
+                    error!("Could not find device {:?}", host_path);
+                    return Err(Error::from(e));
                 }
+            };
+            let kind = SFlag::from_bits_truncate(device.st_mode) & SFlag::S_IFMT;
+            if kind != SFlag::S_IFCHR && kind != SFlag::S_IFBLK {
+                return Err(Error::new(format!("{:?} is not a device", host_path).as_str()));
             }
+            let target = root.join(container_path.strip_prefix("/").unwrap_or(container_path));
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mode = Mode::from_bits_truncate(device.st_mode);
+            Filesystem::create_device(host_path, &target, kind, mode, device.st_rdev)?;
         }
-        self.create_dev_devices()?;
         Ok(())
     }
 
-    fn create_dev_devices(&self) -> Result<(), Error> {
+    fn create_dev_devices(&self, dev: &Path) -> Result<(), Error> {
         let dev_null = makedev(1, 3);
         let dev_zero = makedev(1, 5);
         let dev_full = makedev(1, 7);
         let dev_random = makedev(1, 8);
         let dev_urandom = makedev(1, 9);
         let dev_tty = makedev(5, 0);
-        let dev_ptmx = makedev(5, 2);
         let _0666 = Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IWGRP | Mode::S_IROTH | Mode::S_IWOTH;
-        mknod("/dev/null", SFlag::S_IFCHR,  _0666, dev_null)?;
-        mknod("/dev/zero", SFlag::S_IFCHR,  _0666, dev_zero)?;
-        mknod("/dev/full", SFlag::S_IFCHR, _0666, dev_full)?;
-        mknod("/dev/random", SFlag::S_IFCHR, _0666, dev_random)?;
-        mknod("/dev/urandom", SFlag::S_IFCHR, _0666, dev_urandom)?;
-        mknod("/dev/tty", SFlag::S_IFCHR, _0666, dev_tty)?;
-        mknod("/dev/ptmx", SFlag::S_IFCHR, _0666, dev_ptmx)?;
+        let devices = [("null", dev_null), ("zero", dev_zero), ("full", dev_full), ("random", dev_random), ("urandom", dev_urandom), ("tty", dev_tty)];
+        for (name, device) in devices.iter() {
+            Filesystem::create_device(&Path::new("/dev").join(name), &dev.join(name), SFlag::S_IFCHR, _0666, *device)?;
+        }
         Ok(())
     }
 
-    /// Mounts the procfs of the container
+    /// Create the device node `target`, or bind-mount `host_path` over it if creating device nodes
+    /// is not permitted. Nothing is done if `target` already exists.
+    fn create_device(host_path: &Path, target: &Path, kind: SFlag, mode: Mode, device: dev_t) -> Result<(), Error> {
+        if fs::symlink_metadata(target).is_ok() {
+            return Ok(());
+        }
+        match mknod(target, kind, mode, device) {
+            Ok(_) => Ok(()),
+            Err(nix::Error::Sys(Errno::EPERM)) => {
+                debug!("Not allowed to create {:?}, bind-mounting it from the host", target);
+                fs::File::create(target)?;
+                let source = host_path.to_str().map(str::to_owned);
+                Filesystem::mount_entry(&MountingPoint::new_all(source, &target.to_path_buf(), None, None, Some(MsFlags::MS_BIND), Some(true), Some(true), Some(false)))
+            }
+            Err(e) => {
+                error!("Could not create device {:?}", target);
+                Err(Error::from(e))
+            }
+        }
+    }
+
+    /* /// Mounts the procfs of the container
     /// 
     /// Must be run AFTER chrooting, otherwise bad things might happen.
-   /* pub fn mount_procfs(&self) -> Result<(), Error> {
+    pub fn mount_procfs(&self) -> Result<(), Error> {
         // mount -t proc proc /proc
         match mount::<str, str, str, str>(None, "/proc", Some("proc"), MsFlags::MS_RDONLY, None) {
             Ok(_) => Ok(()),
@@ -186,7 +645,9 @@ impl Filesystem {
     pub fn umount(&self) -> Result<(), Error> {
         umount(&self.targetpath.join("merge").join("proc"))?;
         umount(&self.targetpath.join("merge"))?;
-        umount(&self.targetpath)?;
+        if self.storage(&MTab::new()).is_some() {
+            umount(&self.targetpath)?;
+        }
         Ok(())
     }
 
@@ -196,25 +657,164 @@ impl Filesystem {
         while mtab.contains(MountingPoint::new(None, &self.targetpath.join(Filesystem::MERGE_DIR), Some(FsType::Overlay))) {
             self.umount()?;
         }
-        fs::remove_dir_all(self.targetpath.join(Filesystem::MERGE_DIR))?;
-        fs::remove_dir_all(self.targetpath.join(Filesystem::UPPER_DIR))?;
-        fs::remove_dir_all(self.targetpath.join(Filesystem::WORK_DIR))?;
+        // Along with the overlay directories inside it
+        if self.storage(&MTab::new()).is_some() {
+            umount(&self.targetpath)?;
+        }
+        for dir in &[Filesystem::MERGE_DIR, Filesystem::UPPER_DIR, Filesystem::WORK_DIR] {
+            if self.targetpath.join(dir).exists() {
+                fs::remove_dir_all(self.targetpath.join(dir))?;
+            }
+        }
         fs::remove_dir_all(&self.targetpath)?;
+        if self.storage_image().exists() {
+            fs::remove_file(self.storage_image())?;
+        }
         Ok(())
     }
 
+    /// View of the root of the container from the host, through the overlay while it is mounted
+    pub fn root_view(&self) -> RootView {
+        let mtab = MTab::new();
+        if mtab.contains(MountingPoint::new(None, &self.targetpath.join(Filesystem::MERGE_DIR), Some(FsType::Overlay))) {
+            return RootView::Merged(self.root_path());
+        }
+        RootView::Layers {
+            upper: self.targetpath.join(Filesystem::UPPER_DIR),
+            lowers: self.lower_dirs()
+        }
+    }
+
     pub fn root_path(&self) -> PathBuf {
         return self.targetpath.join("merge");
     }
 
-    pub fn image_path(&self) -> &PathBuf {
-        return &self.imagepath;
+    /// Layers of the image in the order of the overlay lowerdirs, from the top one
+    fn lower_dirs(&self) -> Vec<PathBuf> {
+        self.layers.iter().rev().cloned().collect()
+    }
+
+    /// Value of the lowerdir option of the overlay, as shown in the mount table
+    fn lowerdir(&self) -> String {
+        let dirs: Vec<String> = self.lower_dirs().iter().map(|dir| {
+            dir.to_string_lossy().replace('\\', "\\134").replace(' ', "\\040").replace('\t', "\\011").replace('\n', "\\012")
+        }).collect();
+        dirs.join(":")
+    }
+
+    fn lowerdir_option(mounting_point: &MountingPoint) -> Option<String> {
+        let options = mounting_point.options.as_ref()?;
+        options.split(',').find_map(|option| option.strip_prefix("lowerdir=")).map(str::to_owned)
+    }
+
+    /// Check that the layers can be stacked by overlayfs
+    fn validate_layers(&self) -> Result<(), Error> {
+        if self.layers.is_empty() {
+            return Err(Error::new("The image has no layers"));
+        }
+        let mut length = 0;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if !layer.is_dir() {
+                return Err(Error::new(format!("The layer {:?} is not a directory", layer).as_str()));
+            }
+            if self.layers[..i].contains(layer) {
+                return Err(Error::new(format!("The layer {:?} is used more than once", layer).as_str()));
+            }
+            // overlayfs refuses layers nested in other layers of the same filesystem
+            let device = fs::metadata(layer)?.dev();
+            for other in &self.layers[..i] {
+                if (layer.starts_with(other) || other.starts_with(layer)) && fs::metadata(other)?.dev() == device {
+                    return Err(Error::new(format!("The layers {:?} and {:?} overlap", other, layer).as_str()));
+                }
+            }
+            if layer.starts_with(&self.targetpath) {
+                return Err(Error::new(format!("The layer {:?} is inside the DevEnv", layer).as_str()));
+            }
+            // Separators of the mount options of overlayfs
+            let path = layer.to_string_lossy();
+            if path.contains(':') || path.contains(',') {
+                return Err(Error::new(format!("The path of the layer {:?} cannot contain ':' or ','", layer).as_str()));
+            }
+            length += path.len() + 1;
+        }
+        if length > Filesystem::MAX_LOWERDIR_LENGTH {
+            return Err(Error::new(format!("Too many layers, their paths take {} bytes out of {}", length, Filesystem::MAX_LOWERDIR_LENGTH).as_str()));
+        }
+        Ok(())
     }
 
     pub fn target_path(&self) -> &PathBuf {
         return &self.targetpath;
     }
 
+    /// Read-only layers of the image, from the bottom one
+    pub fn layers(&self) -> &[PathBuf] {
+        &self.layers
+    }
+
+    pub fn set_layers(&mut self, layers: Vec<PathBuf>) {
+        self.layers = layers;
+    }
+
+    /// Writable layer of the overlay, where the changes made inside the container are kept
+    pub fn upper_path(&self) -> PathBuf {
+        self.targetpath.join(Filesystem::UPPER_DIR)
+    }
+
+    pub fn work_path(&self) -> PathBuf {
+        self.targetpath.join(Filesystem::WORK_DIR)
+    }
+
+    /// Replace the upper layer with the directory `upper`, which must be in the same filesystem.
+    /// The old upper layer is removed. The container must be stopped.
+    ///
+    /// Both directories are exchanged in a single rename, so if this is interrupted the upper
+    /// layer is either the old or the new one.
+    pub fn replace_upper(&self, upper: &Path) -> Result<(), Error> {
+        // overlayfs does not expect its layers to change while mounted
+        self.umount_overlay()?;
+        let current = self.upper_path();
+        if current.exists() {
+            Filesystem::exchange(upper, &current)?;
+            fs::remove_dir_all(upper)?;
+        }
+        else {
+            fs::rename(upper, &current)?;
+        }
+        // The work directory may keep files of the previous upper layer
+        let work = self.work_path();
+        if work.exists() {
+            for entry in fs::read_dir(&work)? {
+                fs::remove_dir_all(entry?.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Atomically exchange two paths of the same filesystem
+    fn exchange(a: &Path, b: &Path) -> Result<(), Error> {
+        let c_a = CString::new(a.as_os_str().as_bytes()).unwrap();
+        let c_b = CString::new(b.as_os_str().as_bytes()).unwrap();
+        let result = unsafe {
+            libc::syscall(libc::SYS_renameat2, libc::AT_FDCWD, c_a.as_ptr(), libc::AT_FDCWD, c_b.as_ptr(), Filesystem::RENAME_EXCHANGE)
+        };
+        if result != 0 {
+            error!("Could not exchange {:?} and {:?}", a, b);
+            return Err(Error::from(nix::Error::last()));
+        }
+        Ok(())
+    }
+
+    /// Unmount the overlay left mounted by a stopped container, so its layers can be modified
+    pub fn umount_overlay(&self) -> Result<(), Error> {
+        let mtab = MTab::new();
+        if mtab.contains(MountingPoint::new(None, &self.root_path(), Some(FsType::Overlay))) {
+            debug!("Unmounting the overlay at {:?}", self.root_path());
+            umount(&self.root_path())?;
+        }
+        Ok(())
+    }
+
     /// Check if the current kernel version is greater than the required version
     fn is_kernel_version_compatible(required_kernel_version: &str) -> Result<bool, Error> {
         let sysinfo = uname();
@@ -0,0 +1,270 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, ForkResult};
+use serde_derive::{Serialize, Deserialize};
+use log::{debug, error};
+use devenv_common::error::Error;
+
+use crate::configuration::{Hook, Hooks};
+use crate::init::Init;
+use crate::join::NAMESPACES;
+
+/// Points of the lifecycle of a DevEnv where hooks run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStage {
+    OnCreate,
+    PostDependencies,
+    PreShell,
+    OnDestroy
+}
+
+impl HookStage {
+
+    /// Hooks configured for this stage
+    pub fn hooks<'a>(&self, hooks: &'a Hooks) -> &'a [Hook] {
+        let stage_hooks = match self {
+            HookStage::OnCreate => &hooks.on_create,
+            HookStage::PostDependencies => &hooks.post_dependencies,
+            HookStage::PreShell => &hooks.pre_shell,
+            HookStage::OnDestroy => &hooks.on_destroy
+        };
+        stage_hooks.as_deref().unwrap_or_default()
+    }
+
+    /// Whether a hook of this stage only runs the first time
+    pub fn is_once(&self, hook: &Hook) -> bool {
+        *self == HookStage::OnCreate || hook.once.unwrap_or(false)
+    }
+
+}
+
+impl fmt::Display for HookStage {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HookStage::OnCreate => "on_create",
+            HookStage::PostDependencies => "post_dependencies",
+            HookStage::PreShell => "pre_shell",
+            HookStage::OnDestroy => "on_destroy"
+        };
+        write!(f, "{}", name)
+    }
+
+}
+
+/// Record of the one-time hooks that already ran, kept in the dest dir of the DevEnv. Hooks are
+/// identified by their stage and command, so changing the command runs it again.
+#[derive(Debug, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct HookRecord {
+    ran: Vec<RanHook>,
+    #[serde(skip)]
+    path: PathBuf
+}
+
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+struct RanHook {
+    stage: String,
+    command: String
+}
+
+impl HookRecord {
+
+    const FILE: &'static str = "hooks.toml";
+
+    /// Load the record of the DevEnv at `target`. A missing or unreadable record is empty.
+    pub fn load(target: &Path) -> HookRecord {
+        let path = target.join(HookRecord::FILE);
+        let mut record: HookRecord = match fs::read_to_string(&path) {
+            Ok(contents) => match toml::from_str(contents.as_str()) {
+                Ok(record) => record,
+                Err(e) => {
+                    error!("Ignoring the invalid hook record {:?}: {}", path, e);
+                    HookRecord::default()
+                }
+            },
+            Err(_) => HookRecord::default()
+        };
+        record.path = path;
+        record
+    }
+
+    pub fn has_run(&self, stage: HookStage, hook: &Hook) -> bool {
+        self.ran.contains(&RanHook { stage: stage.to_string(), command: hook.command.clone() })
+    }
+
+    /// Record that a hook ran, and save the record
+    pub fn add(&mut self, stage: HookStage, hook: &Hook) -> Result<(), Error> {
+        self.ran.push(RanHook { stage: stage.to_string(), command: hook.command.clone() });
+        let contents = match toml::to_string(self) {
+            Ok(contents) => contents,
+            Err(e) => return Err(Error::new_error("Could not serialize the hook record", Box::from(e)))
+        };
+        debug!("Saving the hook record to {:?}", self.path);
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+
+}
+
+/// Namespaces of the calling thread, opened before it unshares the ones of the container so host
+/// hooks can go back to them
+pub fn host_namespaces() -> Result<Vec<(File, CloneFlags)>, Error> {
+    let mut namespaces = vec![];
+    for (name, flag) in NAMESPACES {
+        namespaces.push((File::open(format!("/proc/thread-self/ns/{}", name))?, *flag));
+    }
+    Ok(namespaces)
+}
+
+/// Run the command of a host hook with `shell` and return its exit status. If devenv created the container,
+/// it is in the namespaces of the container, so the hook joins `namespaces`, the ones of the host.
+///
+/// A PID namespace can only be joined by the children of a process, and only if it is an ancestor
+/// of the one of the process, so devenv switches the one of its children for the fork.
+pub fn run_on_host(shell: &str, command: &str, variables: &[(String, String)], namespaces: &[(File, CloneFlags)]) -> Result<i32, Error> {
+    // Everything the child needs is prepared before forking, as devenv may have other threads
+    let (shell, command) = match (CString::new(shell), CString::new(command)) {
+        (Ok(shell), Ok(command)) => (shell, command),
+        _ => return Err(Error::new("The command of the hook contains a nul byte"))
+    };
+    let args = [shell.as_ptr(), b"-c\0".as_ptr() as *const libc::c_char, command.as_ptr(), std::ptr::null()];
+    let env = Init::environment(variables)?;
+    let mut envp: Vec<*const libc::c_char> = env.iter().map(|variable| variable.as_ptr()).collect();
+    envp.push(std::ptr::null());
+    let workdir = match CString::new(env::current_dir()?.as_os_str().as_bytes()) {
+        Ok(workdir) => workdir,
+        Err(_) => return Err(Error::new("The working directory contains a nul byte"))
+    };
+    let host_pid = namespaces.iter().find(|(_, flag)| *flag == CloneFlags::CLONE_NEWPID);
+    // The pid namespace goes last so that the processes the hook starts stay on the host as well
+    let joined: Vec<(libc::c_int, libc::c_int)> = namespaces.iter()
+        .filter(|(_, flag)| *flag != CloneFlags::CLONE_NEWPID)
+        .chain(host_pid)
+        .map(|(namespace, flag)| (namespace.as_raw_fd(), flag.bits()))
+        .collect();
+    let container_pid = match host_pid {
+        Some((namespace, flag)) => {
+            let container_pid = File::open("/proc/thread-self/ns/pid_for_children")?;
+            setns(namespace.as_raw_fd(), *flag)?;
+            Some(container_pid)
+        }
+        None => None
+    };
+    let forked = fork();
+    // The children devenv starts afterwards belong to the container again
+    if let (Ok(ForkResult::Parent { .. }) | Err(_), Some(container_pid)) = (&forked, &container_pid) {
+        setns(container_pid.as_raw_fd(), CloneFlags::CLONE_NEWPID)?;
+    }
+    let child = match forked {
+        Ok(ForkResult::Parent { child, .. }) => child,
+        Ok(ForkResult::Child) => unsafe {
+            for (namespace, flag) in &joined {
+                if libc::setns(*namespace, *flag) < 0 {
+                    Init::child_failed(&[b"Could not join the namespaces of the host"]);
+                }
+            }
+            // Joining the mount namespace changed the working directory to its root
+            if libc::chdir(workdir.as_ptr()) < 0 {
+                Init::child_failed(&[b"Could not change to the directory ", workdir.as_bytes()]);
+            }
+            // devenv blocks the signals it forwards
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            if libc::pthread_sigmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut()) != 0 {
+                Init::child_failed(&[b"Could not reset the signal mask of the hook"]);
+            }
+            libc::execve(args[0], args.as_ptr(), envp.as_ptr());
+            Init::child_failed(&[b"Could not execute ", shell.as_bytes()])
+        }
+        Err(e) => return Err(Error::from(e))
+    };
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, status)) => return Ok(status),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => return Err(Error::from(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sched::unshare;
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::{geteuid, pause};
+    use std::thread;
+
+    #[test]
+    fn host_hooks_run_in_the_namespaces_of_the_host() {
+        if !geteuid().is_root() {
+            eprintln!("Skipping, unsharing namespaces needs root");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("namespaces");
+        let host_net = fs::read_link("/proc/thread-self/ns/net").unwrap();
+        let host_pid = fs::read_link("/proc/thread-self/ns/pid").unwrap();
+        let command = format!("readlink /proc/self/ns/net /proc/self/ns/pid > {:?}", output);
+        // Like devenv creating a container, in a thread of its own as unsharing only moves it
+        let (status, container_net, pid_for_children) = thread::spawn(move || {
+            let namespaces = host_namespaces().unwrap();
+            unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_FS | CloneFlags::CLONE_NEWNET |
+                CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWCGROUP |
+                CloneFlags::CLONE_NEWPID).unwrap();
+            // Stands for the PID 1 of the container
+            let init = match fork().unwrap() {
+                ForkResult::Parent { child } => child,
+                ForkResult::Child => loop { pause() }
+            };
+            let container_net = fs::read_link("/proc/thread-self/ns/net").unwrap();
+            let container_pid = fs::read_link("/proc/thread-self/ns/pid_for_children").unwrap();
+            let status = run_on_host("/bin/sh", &command, &[], &namespaces).unwrap();
+            let pid_for_children = fs::read_link("/proc/thread-self/ns/pid_for_children").unwrap();
+            kill(init, Signal::SIGKILL).unwrap();
+            waitpid(init, None).unwrap();
+            (status, container_net, (container_pid, pid_for_children))
+        }).join().unwrap();
+
+        assert_eq!(status, 0);
+        assert_ne!(container_net, host_net);
+        assert_eq!(pid_for_children.0, pid_for_children.1);
+        let expected = format!("{}\n{}\n", host_net.display(), host_pid.display());
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+    }
+}
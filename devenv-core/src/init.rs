@@ -0,0 +1,383 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::{killpg, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{close, execvp, fork, ForkResult, Pid};
+use std::os::unix::io::RawFd;
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+use crate::terminal;
+
+/// Minimal init for the PID 1 of the container, in the spirit of tini.
+///
+/// A process running as PID 1 inherits every orphaned process of its PID namespace, and the kernel
+/// only delivers to it the signals it has a handler for. The init reaps those orphans, forwards the
+/// signals it receives to the process group of the main task, and exits with the status of the last
+/// main task.
+///
+/// The signals are handled in a dedicated thread, so the calling thread is free to serve tasks.
+/// Besides the main task, the init can run background processes (e.g. services), whose exit
+/// status is kept until it is waited for.
+#[derive(Clone)]
+pub struct Init {
+    state: Arc<InitState>
+}
+
+struct InitState {
+    tasks: Mutex<Tasks>,
+    // Notified when the main task finishes
+    finished: Condvar
+}
+
+/// A command run by the init
+pub struct Command<'a> {
+    pub filename: &'a CStr,
+    pub args: &'a [&'a CStr],
+    /// Variables added to the environment of the command
+    pub env: &'a [(String, String)],
+    /// Working directory of the command, instead of the init's
+    pub workdir: Option<&'a str>
+}
+
+struct Tasks {
+    main_task: Option<Pid>,
+    // Exit status of the last main task that finished
+    status: i32,
+    // Background processes, with their exit status once they finish
+    processes: HashMap<Pid, Option<i32>>,
+    // Master of the pseudo-terminal of the main task, while it is relayed
+    terminal: Option<RawFd>
+}
+
+impl Init {
+
+    /// Signals handled by the init
+    const SIGNALS: &'static [Signal] = &[Signal::SIGCHLD, Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGQUIT, Signal::SIGWINCH];
+
+    /// Start handling signals. Must be called before spawning any other thread, as they inherit
+    /// the signal mask of the calling thread.
+    pub fn start() -> Result<Init, Error> {
+        Init::block_signals()?;
+        let state = Arc::new(InitState {
+            tasks: Mutex::new(Tasks { main_task: None, status: 0, processes: HashMap::new(), terminal: None }),
+            finished: Condvar::new()
+        });
+        let thread_state = state.clone();
+        match thread::Builder::new().name("init".to_owned()).spawn(move || Init::handle_signals(&thread_state)) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Could not start the signal handling thread");
+                return Err(Error::from(e));
+            }
+        }
+        Ok(Init { state })
+    }
+
+    /// Run a command as the main task. The command runs in a child process with its own session,
+    /// which receives the forwarded signals and whose exit status is the exit status of the container.
+    ///
+    /// If `stdio` is given, the stdout and stderr of the command are redirected to those descriptors.
+    /// Otherwise, if devenv runs in a terminal, the command gets a pseudo-terminal as its controlling
+    /// terminal, relayed to the one of devenv by `wait`.
+    pub fn spawn(&self, command: &Command, stdio: Option<(RawFd, RawFd)>) -> Result<Pid, Error> {
+        let pty = match stdio {
+            None if terminal::is_interactive() => Some(terminal::open_pty()?),
+            _ => None
+        };
+        // Hold the lock until the main task is recorded, in case it finishes before that
+        let mut tasks = self.state.tasks.lock().unwrap();
+        let child = Init::fork_command(command, stdio, pty.map(|(_, slave)| slave));
+        if let Some((master, slave)) = pty {
+            let _ = close(slave);
+            match child {
+                Ok(_) => tasks.terminal = Some(master),
+                Err(_) => { let _ = close(master); }
+            }
+        }
+        let child = child?;
+        tasks.main_task = Some(child);
+        Ok(child)
+    }
+
+    /// Run a command in the background. Like the main task, it runs in its own session, but it
+    /// does not receive the forwarded signals. Its exit status is kept until `wait_process`.
+    pub fn start_process(&self, command: &Command, stdio: Option<(RawFd, RawFd)>) -> Result<Pid, Error> {
+        let mut tasks = self.state.tasks.lock().unwrap();
+        let child = Init::fork_command(command, stdio, None)?;
+        tasks.processes.insert(child, None);
+        Ok(child)
+    }
+
+    /// Wait for a background process to finish, and return its exit status. Returns `None` if it
+    /// is still running after `timeout`.
+    pub fn wait_process(&self, pid: Pid, timeout: Option<Duration>) -> Option<i32> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut tasks = self.state.tasks.lock().unwrap();
+        loop {
+            match tasks.processes.get(&pid) {
+                Some(Some(status)) => {
+                    let status = *status;
+                    tasks.processes.remove(&pid);
+                    return Some(status);
+                }
+                Some(None) => {}
+                // Not started by the init, or already waited for
+                None => return None
+            }
+            tasks = match deadline {
+                None => self.state.finished.wait(tasks).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.state.finished.wait_timeout(tasks, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Fork and execute a command in its own session. With `terminal`, the slave of a
+    /// pseudo-terminal, the command gets it as its standard streams and its controlling terminal.
+    fn fork_command(command: &Command, stdio: Option<(RawFd, RawFd)>, terminal: Option<RawFd>) -> Result<Pid, Error> {
+        // Everything the child needs is prepared before forking: other threads of the init may hold
+        // the locks of the allocator or the logger, so the child can only make system calls.
+        let mut args: Vec<*const libc::c_char> = command.args.iter().map(|arg| arg.as_ptr()).collect();
+        args.push(std::ptr::null());
+        let env = Init::environment(command.env)?;
+        let mut envp: Vec<*const libc::c_char> = env.iter().map(|variable| variable.as_ptr()).collect();
+        envp.push(std::ptr::null());
+        let workdir = match command.workdir.map(CString::new).transpose() {
+            Ok(workdir) => workdir,
+            Err(_) => return Err(Error::new("The working directory contains a nul byte"))
+        };
+        let mask = Init::signal_mask();
+        match fork() {
+            Ok(ForkResult::Parent { child, .. }) => {
+                debug!("Started task {} with pid {}", command.filename.to_string_lossy(), child);
+                Ok(child)
+            }
+            Ok(ForkResult::Child) => unsafe {
+                if libc::setsid() < 0 {
+                    Init::child_failed(&[b"Could not create the session of the task"]);
+                }
+                if let Some(terminal) = terminal {
+                    if libc::dup2(terminal, 0) < 0 || libc::dup2(terminal, 1) < 0 || libc::dup2(terminal, 2) < 0 {
+                        Init::child_failed(&[b"Could not redirect the task to its terminal"]);
+                    }
+                    if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 || libc::tcsetpgrp(0, libc::getpid()) < 0 {
+                        Init::child_failed(&[b"Could not set the controlling terminal of the task"]);
+                    }
+                }
+                if libc::pthread_sigmask(libc::SIG_UNBLOCK, mask.as_ref(), std::ptr::null_mut()) != 0 {
+                    Init::child_failed(&[b"Could not reset the signal mask of the task"]);
+                }
+                if let Some((stdout, stderr)) = stdio {
+                    if libc::dup2(stdout, 1) < 0 || libc::dup2(stderr, 2) < 0 {
+                        Init::child_failed(&[b"Could not redirect the output of the task"]);
+                    }
+                }
+                if let Some(workdir) = &workdir {
+                    if libc::chdir(workdir.as_ptr()) < 0 {
+                        Init::child_failed(&[b"Could not change to the directory ", workdir.as_bytes()]);
+                    }
+                }
+                libc::execvpe(command.filename.as_ptr(), args.as_ptr(), envp.as_ptr());
+                Init::child_failed(&[b"Could not execute ", command.filename.to_bytes()])
+            }
+            Err(e) => {
+                error!("Fork failed!");
+                Err(Error::from(e))
+            }
+        }
+    }
+
+    /// Environment of a command: the environment of the init, with the variables of the command
+    /// added or replaced
+    pub fn environment(variables: &[(String, String)]) -> Result<Vec<CString>, Error> {
+        let inherited = std::env::vars_os()
+            .filter(|(name, _)| !variables.iter().any(|(added, _)| name.as_bytes() == added.as_bytes()))
+            .map(|(name, value)| (name.into_vec(), value.into_vec()));
+        let added = variables.iter().map(|(name, value)| (name.clone().into_bytes(), value.clone().into_bytes()));
+        inherited.chain(added).map(|(mut variable, value)| {
+            variable.push(b'=');
+            variable.extend(value);
+            CString::new(variable).map_err(|_| Error::new("An environment variable contains a nul byte"))
+        }).collect()
+    }
+
+    /// Report why a forked child could not run its command and exit, using only system calls
+    pub fn child_failed(message: &[&[u8]]) -> ! {
+        let reason = Errno::last().desc().as_bytes();
+        for part in message.iter().chain(&[b": " as &[u8], reason, b"\n"]) {
+            unsafe { libc::write(2, part.as_ptr() as *const libc::c_void, part.len()) };
+        }
+        unsafe { libc::_exit(127) }
+    }
+
+    /// Wait for the main task to finish, and return its exit status. If it has a pseudo-terminal,
+    /// relay it until every process using it is gone.
+    pub fn wait(&self) -> i32 {
+        let master = self.state.tasks.lock().unwrap().terminal;
+        if let Some(master) = master {
+            if let Err(e) = terminal::relay(master) {
+                warn!("Could not relay the terminal of the task: {}", e);
+            }
+            self.state.tasks.lock().unwrap().terminal = None;
+            let _ = close(master);
+        }
+        let mut tasks = self.state.tasks.lock().unwrap();
+        while tasks.main_task.is_some() {
+            tasks = self.state.finished.wait(tasks).unwrap();
+        }
+        tasks.status
+    }
+
+    /// Replace the init with a command, which becomes the PID 1 of the container. Only returns if
+    /// the command could not be executed.
+    pub fn replace(&self, filename: &CStr, args: &[&CStr]) -> Error {
+        if let Err(e) = Init::reset_signal_mask() {
+            return e;
+        }
+        let error = match execvp(filename, args).err() {
+            Some(e) => Error::from(e),
+            None => Error::new("Could not replace the init")
+        };
+        if let Err(e) = Init::block_signals() {
+            warn!("Could not block signals: {}", e);
+        }
+        error
+    }
+
+    /// Block the init's signals in the calling thread, so only the signal handling thread gets them
+    fn block_signals() -> Result<(), Error> {
+        Init::signal_mask().thread_block()?;
+        Ok(())
+    }
+
+    /// Restore the signal mask of the calling thread, so processes started by the init receive signals
+    fn reset_signal_mask() -> Result<(), Error> {
+        Init::signal_mask().thread_unblock()?;
+        Ok(())
+    }
+
+    /// Terminate the container. As the init is PID 1, the kernel kills every other process of the
+    /// PID namespace when it exits.
+    pub fn exit(&self) -> ! {
+        let status = self.status();
+        debug!("Exiting the container with status {}", status);
+        std::process::exit(status)
+    }
+
+    /// Exit status of the last main task that finished
+    pub fn status(&self) -> i32 {
+        self.state.tasks.lock().unwrap().status
+    }
+
+    fn signal_mask() -> SigSet {
+        let mut mask = SigSet::empty();
+        for signal in Init::SIGNALS {
+            mask.add(*signal);
+        }
+        mask
+    }
+
+    fn handle_signals(state: &InitState) {
+        let mask = Init::signal_mask();
+        loop {
+            let signal = match mask.wait() {
+                Ok(signal) => signal,
+                Err(e) => {
+                    warn!("Error while waiting for signals: {}", e);
+                    continue;
+                }
+            };
+            match signal {
+                Signal::SIGCHLD => Init::reap(state),
+                _ => Init::forward(state, signal)
+            }
+        }
+    }
+
+    /// Reap every child that already finished, orphans included
+    fn reap(state: &InitState) {
+        loop {
+            let (pid, status) = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, code)) => (pid, code),
+                Ok(WaitStatus::Signaled(pid, signal, _)) => (pid, 128 + signal as i32),
+                Ok(WaitStatus::StillAlive) | Err(nix::Error::Sys(Errno::ECHILD)) => return,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Error while reaping processes: {}", e);
+                    return;
+                }
+            };
+            debug!("Reaped process {} with status {}", pid, status);
+            let mut tasks = state.tasks.lock().unwrap();
+            if tasks.main_task == Some(pid) {
+                tasks.main_task = None;
+                tasks.status = status;
+                state.finished.notify_all();
+            }
+            else if let Some(process) = tasks.processes.get_mut(&pid) {
+                *process = Some(status);
+                state.finished.notify_all();
+            }
+        }
+    }
+
+    fn forward(state: &InitState, signal: Signal) {
+        let tasks = state.tasks.lock().unwrap();
+        if let (Signal::SIGWINCH, Some(master)) = (signal, tasks.terminal) {
+            // The kernel signals the foreground process group of the terminal
+            terminal::resize(master);
+            return;
+        }
+        let main_task = match tasks.main_task {
+            Some(pid) => pid,
+            None => {
+                // Nothing is running, so the signal is meant for the container itself
+                if signal != Signal::SIGWINCH {
+                    debug!("Received {} with no running task, exiting", signal);
+                    std::process::exit(128 + signal as i32);
+                }
+                return;
+            }
+        };
+        debug!("Forwarding {} to process group {}", signal, main_task);
+        match killpg(main_task, signal) {
+            Ok(_) | Err(nix::Error::Sys(Errno::ESRCH)) => {}
+            Err(e) => warn!("Could not forward {} to process group {}: {}", signal, main_task, e)
+        }
+    }
+
+}
@@ -0,0 +1,303 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{chdir, chroot, close, fchdir, fork, pipe2, read, ForkResult, Pid};
+use directories::BaseDirs;
+use ipc_channel::ipc::IpcSender;
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+
+use crate::container::{Container, ContainerRequest, ContainerTask, TaskId, TaskOutput, TaskResult};
+use crate::init::Init;
+
+/// Namespaces of the container joined by `spawn`. The mount namespace goes last, as its /proc
+/// does not show the other ones.
+pub const NAMESPACES: &[(&str, CloneFlags)] = &[
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("mnt", CloneFlags::CLONE_NEWNS)
+];
+
+/// Run a command in the container whose PID 1 is `pid`, by joining its namespaces and its root.
+/// This is how commands run once the init of the container was replaced, as it does not receive
+/// tasks anymore. The output and the result of the command are sent through `request`.
+///
+/// After unsharing its PID namespace devenv cannot start threads, so the command is run by a
+/// monitor process that is orphaned right away, and reaped by the init of the container.
+pub fn spawn(pid: Pid, request: ContainerRequest) -> Result<(), Error> {
+    let ContainerRequest { id, task, reply, output } = request;
+    let command = match task {
+        ContainerTask::Command { name, params, env, workdir, .. } => {
+            let (filename, args) = Container::command_line(name, params);
+            JoinedCommand::new(id, filename, args, &env, workdir, output)?
+        }
+        task => {
+            let message = format!("Cannot run {:?} by joining the container", task);
+            let _ = reply.send(TaskResult::from(Error::new(message.as_str())));
+            return Err(Error::new(message.as_str()));
+        }
+    };
+    // Opened before forking, so errors are reported to the caller
+    let mut namespaces: Vec<(File, CloneFlags)> = vec![];
+    for (name, flag) in NAMESPACES {
+        namespaces.push((File::open(format!("/proc/{}/ns/{}", pid, name))?, *flag));
+    }
+    let root = File::open(format!("/proc/{}/root", pid))?;
+    match fork() {
+        Ok(ForkResult::Parent { child, .. }) => {
+            wait(child)?;
+            Ok(())
+        }
+        Ok(ForkResult::Child) => {
+            if let Ok(ForkResult::Child) = fork() {
+                let entered = enter(&namespaces, &root, &command.workdir, command.home);
+                let result = match entered.map_err(Error::from).and_then(|_| command.run()) {
+                    Ok(status) => TaskResult::Exited(status),
+                    Err(e) => {
+                        error!("Could not run task {} in the container: {}", id, e);
+                        TaskResult::from(e)
+                    }
+                };
+                debug!("Task {} finished with {:?}", id, result);
+                if let Err(e) = reply.send(result) {
+                    warn!("Could not send the result of task {}: {}", id, e);
+                }
+            }
+            unsafe { libc::_exit(0) }
+        }
+        Err(e) => {
+            error!("Fork failed!");
+            Err(Error::from(e))
+        }
+    }
+}
+
+/// A command run by joining the container. Everything the children need is prepared before
+/// forking: other threads of devenv may hold the locks of the allocator or the logger.
+struct JoinedCommand {
+    id: TaskId,
+    filename: CString,
+    // Owned by the command, as `argv` and `envp` point into them
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+    workdir: CString,
+    // Whether `workdir` is the home directory, which the container may not have
+    home: bool,
+    output: Option<IpcSender<Option<TaskOutput>>>
+}
+
+impl JoinedCommand {
+
+    fn new(id: TaskId, filename: CString, args: Vec<CString>, env: &[(String, String)], workdir: Option<String>,
+           output: Option<IpcSender<Option<TaskOutput>>>) -> Result<JoinedCommand, Error> {
+        let mut argv: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(std::ptr::null());
+        let env = Init::environment(env)?;
+        let mut envp: Vec<*const libc::c_char> = env.iter().map(|variable| variable.as_ptr()).collect();
+        envp.push(std::ptr::null());
+        let home = workdir.is_none();
+        let workdir = match workdir {
+            Some(workdir) => workdir.into_bytes(),
+            None => BaseDirs::new()
+                .map(|dirs| dirs.home_dir().as_os_str().as_bytes().to_vec())
+                .unwrap_or_else(|| b"/".to_vec())
+        };
+        let workdir = match CString::new(workdir) {
+            Ok(workdir) => workdir,
+            Err(_) => return Err(Error::new("The working directory contains a nul byte"))
+        };
+        Ok(JoinedCommand { id, filename, _args: args, _env: env, argv, envp, workdir, home, output })
+    }
+
+    /// Run the command and return its exit status. Must be called from inside the container.
+    fn run(&self) -> Result<i32, Error> {
+        let pipes = match self.output {
+            Some(_) => Some((pipe2(OFlag::O_CLOEXEC)?, pipe2(OFlag::O_CLOEXEC)?)),
+            None => None
+        };
+        let child = match fork() {
+            Ok(ForkResult::Parent { child, .. }) => child,
+            Ok(ForkResult::Child) => {
+                let stdio = pipes.map(|((_, stdout), (_, stderr))| (stdout, stderr));
+                unsafe { self.exec(stdio) }
+            }
+            Err(e) => return Err(Error::from(e))
+        };
+        debug!("Task {} joined the container with pid {}", self.id, child);
+        if let (Some(((stdout_read, stdout_write), (stderr_read, stderr_write))), Some(output)) = (pipes, &self.output) {
+            let _ = close(stdout_write);
+            let _ = close(stderr_write);
+            if let Err(e) = forward_output(stdout_read, stderr_read, output) {
+                warn!("Could not forward the output of task {}: {}", self.id, e);
+            }
+        }
+        let status = wait(child);
+        if let Some(output) = &self.output {
+            let _ = output.send(None);
+        }
+        status
+    }
+
+    /// Execute the command in a forked child, using only system calls
+    unsafe fn exec(&self, stdio: Option<(RawFd, RawFd)>) -> ! {
+        // devenv blocks the signals it forwards
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        if libc::pthread_sigmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut()) != 0 {
+            Init::child_failed(&[b"Could not reset the signal mask of the task"]);
+        }
+        if let Some((stdout, stderr)) = stdio {
+            if libc::dup2(stdout, 1) < 0 || libc::dup2(stderr, 2) < 0 {
+                Init::child_failed(&[b"Could not redirect the output of the task"]);
+            }
+        }
+        libc::execvpe(self.filename.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr());
+        Init::child_failed(&[b"Could not execute ", self.filename.to_bytes()])
+    }
+
+}
+
+/// Join the namespaces and the root of the container, and change to `workdir`, or to the root if
+/// `workdir` is a home directory missing from the container. Joining a PID namespace only applies
+/// to the children of the calling process. Only makes system calls, as it runs in a forked child.
+fn enter(namespaces: &[(File, CloneFlags)], root: &File, workdir: &CStr, home: bool) -> nix::Result<()> {
+    for (namespace, flag) in namespaces {
+        setns(namespace.as_raw_fd(), *flag)?;
+    }
+    fchdir(root.as_raw_fd())?;
+    chroot(".")?;
+    match chdir(workdir) {
+        Err(_) if home => chdir("/"),
+        result => result
+    }
+}
+
+/// Send what is written to the pipes through `output`, until they are all closed. Once in the PID
+/// namespace of the container no threads can be started, so the pipes are polled in turn.
+fn forward_output(stdout: RawFd, stderr: RawFd, output: &IpcSender<Option<TaskOutput>>) -> Result<(), Error> {
+    let mut pipes = vec![stdout, stderr];
+    let mut buffer = [0u8; 4096];
+    while !pipes.is_empty() {
+        let mut fds: Vec<PollFd> = pipes.iter().map(|fd| PollFd::new(*fd, PollFlags::POLLIN)).collect();
+        match poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => {
+                for fd in pipes {
+                    let _ = close(fd);
+                }
+                return Err(Error::from(e));
+            }
+        }
+        // Backwards, so closed pipes can be removed
+        for index in (0..pipes.len()).rev() {
+            if fds[index].revents().is_none_or(|events| events.is_empty()) {
+                continue;
+            }
+            let fd = pipes[index];
+            let open = match read(fd, &mut buffer) {
+                Ok(0) => false,
+                Ok(n) => {
+                    let data = buffer[..n].to_vec();
+                    let chunk = if fd == stdout { TaskOutput::Stdout(data) } else { TaskOutput::Stderr(data) };
+                    output.send(Some(chunk)).is_ok()
+                }
+                Err(nix::Error::Sys(Errno::EINTR)) => true,
+                Err(_) => false
+            };
+            if !open {
+                let _ = close(fd);
+                pipes.remove(index);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Wait for a process to finish, and return its exit status
+fn wait(pid: Pid) -> Result<i32, Error> {
+    loop {
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, status)) => return Ok(status),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => return Err(Error::from(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sched::clone;
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::{geteuid, pause};
+    use crate::container::ContainerRequest;
+
+    #[test]
+    fn captures_the_output_of_a_joined_command() {
+        if !geteuid().is_root() {
+            eprintln!("Skipping, joining namespaces needs root");
+            return;
+        }
+        // Stands for the PID 1 of a container: the command joins its PID namespace
+        let mut stack = vec![0u8; 64 * 1024];
+        let init = clone(Box::new(|| loop { pause() }), &mut stack, CloneFlags::CLONE_NEWPID, Some(Signal::SIGCHLD as i32)).unwrap();
+        let params = vec!["sh".to_owned(), "-c".to_owned(), "echo $GREETING; echo err >&2; exit 3".to_owned()];
+        let env = vec![("GREETING".to_owned(), "hello".to_owned())];
+        let task = ContainerTask::Command { name: "sh".to_owned(), params, capture: true, env, workdir: Some("/".to_owned()) };
+        let (request, pending) = ContainerRequest::new(1, task).unwrap();
+
+        let spawned = spawn(init, request);
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        while let Some(chunk) = pending.read_output().unwrap() {
+            match chunk {
+                TaskOutput::Stdout(data) => stdout.extend(data),
+                TaskOutput::Stderr(data) => stderr.extend(data)
+            }
+        }
+        let result = pending.wait();
+        kill(init, Signal::SIGKILL).unwrap();
+        waitpid(init, None).unwrap();
+
+        spawned.unwrap();
+        assert!(matches!(result.unwrap(), TaskResult::Exited(3)));
+        assert_eq!(stdout, b"hello\n");
+        assert_eq!(stderr, b"err\n");
+    }
+}
@@ -0,0 +1,488 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsString};
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use nix::libc;
+use nix::sys::stat::{mknod, Mode, SFlag};
+use nix::unistd::{chown, geteuid, Gid, Uid};
+use log::{debug, error};
+use devenv_common::error::Error;
+
+/// View of the filesystem of a DevEnv from the host.
+///
+/// While the overlay is mounted its merged directory is used as is. Otherwise the layers are
+/// resolved the way overlayfs would: the upper layer first, then the lower layers in order, with
+/// whiteouts hiding entries and opaque directories hiding the content of the layers below them.
+///
+/// Paths are always relative to the root of the DevEnv, see `RootView::resolve`.
+#[derive(Clone, Debug)]
+pub enum RootView {
+    Merged(PathBuf),
+    Layers {
+        upper: PathBuf,
+        lowers: Vec<PathBuf>
+    }
+}
+
+impl RootView {
+
+    /// Extended attribute set by overlayfs on opaque directories
+    const OPAQUE_XATTR: &'static [u8] = b"trusted.overlay.opaque\0";
+
+    // Same limit as the kernel
+    const MAX_SYMLINKS: usize = 40;
+
+    /// Find the file backing `path`, if it exists
+    pub fn lookup(&self, path: &Path) -> Option<PathBuf> {
+        match self {
+            RootView::Merged(root) => {
+                let candidate = root.join(path);
+                fs::symlink_metadata(&candidate).ok().map(|_| candidate)
+            }
+            RootView::Layers { .. } => {
+                for layer in self.layers() {
+                    let candidate = layer.join(path);
+                    if let Ok(metadata) = fs::symlink_metadata(&candidate) {
+                        if is_whiteout(&metadata) {
+                            return None;
+                        }
+                        return Some(candidate);
+                    }
+                    if RootView::hides_lower(layer, path) {
+                        return None;
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// List the entries of the directory `path`, merging the layers
+    pub fn read_dir(&self, path: &Path) -> Result<Vec<OsString>, Error> {
+        let mut names: Vec<OsString> = vec![];
+        // Names found in upper layers, whiteouts included
+        let mut seen: HashSet<OsString> = HashSet::new();
+        for layer in self.layers() {
+            let dir = layer.join(path);
+            match fs::symlink_metadata(&dir) {
+                Ok(metadata) if metadata.is_dir() => {}
+                // A file or a whiteout hides this directory in the layers below
+                Ok(_) => break,
+                Err(_) => {
+                    if RootView::hides_lower(layer, path) {
+                        break;
+                    }
+                    continue;
+                }
+            }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                if !is_whiteout(&entry.metadata()?) {
+                    names.push(name);
+                }
+            }
+            if is_opaque(&dir) {
+                break;
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Turn an absolute path inside the DevEnv into a path relative to its root, resolving `.`,
+    /// `..` and symlinks without ever leaving the DevEnv. The last component is only resolved if
+    /// `follow` is set.
+    pub fn resolve(&self, path: &Path, follow: bool) -> Result<PathBuf, Error> {
+        let mut resolved = PathBuf::new();
+        // Components left to resolve, in reverse order
+        let mut pending: Vec<OsString> = path.components().rev().filter_map(RootView::component_name).collect();
+        let mut symlinks = 0;
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&name);
+            let target = match self.lookup(&candidate) {
+                Some(real) if follow || !pending.is_empty() => fs::read_link(real).ok(),
+                _ => None
+            };
+            match target {
+                Some(target) => {
+                    symlinks += 1;
+                    if symlinks > RootView::MAX_SYMLINKS {
+                        return Err(Error::new(format!("Too many levels of symbolic links in {:?}", path).as_str()));
+                    }
+                    if target.is_absolute() {
+                        resolved = PathBuf::new();
+                    }
+                    pending.extend(target.components().rev().filter_map(RootView::component_name));
+                }
+                None => resolved = candidate
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Get the path where `path` has to be written, so the change is visible in the DevEnv.
+    ///
+    /// With the layers, the parent directories are copied up to the upper layer keeping their
+    /// permissions and owner, and a whiteout for `path` is removed. A directory replacing a whiteout
+    /// is created opaque, so it does not show the content of the layers below.
+    pub fn writable_path(&self, path: &Path, is_dir: bool) -> Result<PathBuf, Error> {
+        let upper = match self {
+            RootView::Merged(root) => return Ok(root.join(path)),
+            RootView::Layers { upper, .. } => upper
+        };
+        let mut parent = PathBuf::new();
+        if let Some(dir) = path.parent() {
+            for component in dir.components() {
+                parent.push(component);
+                self.copy_up_dir(&parent)?;
+            }
+        }
+        let target = upper.join(path);
+        if let Ok(metadata) = fs::symlink_metadata(&target) {
+            if is_whiteout(&metadata) {
+                debug!("Removing whiteout {:?}", target);
+                fs::remove_file(&target)?;
+                if is_dir {
+                    fs::create_dir(&target)?;
+                    set_opaque(&target)?;
+                }
+            }
+        }
+        Ok(target)
+    }
+
+    /// Create the directory `path` in the upper layer, copying the permissions and owner from the
+    /// layer where it is visible
+    fn copy_up_dir(&self, path: &Path) -> Result<(), Error> {
+        let upper = match self {
+            RootView::Merged(_) => return Ok(()),
+            RootView::Layers { upper, .. } => upper
+        };
+        let target = upper.join(path);
+        match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => return Ok(()),
+            Ok(metadata) if is_whiteout(&metadata) => {
+                fs::remove_file(&target)?;
+                fs::create_dir(&target)?;
+                return set_opaque(&target);
+            }
+            Ok(_) => return Err(Error::new(format!("{:?} is not a directory", path).as_str())),
+            Err(_) => {}
+        }
+        // Looked up before creating it, or the new directory would be found
+        let source = self.lookup(path).and_then(|source| fs::metadata(source).ok());
+        fs::create_dir(&target)?;
+        if let Some(metadata) = source {
+            copy_metadata(&metadata, &target)?;
+        }
+        Ok(())
+    }
+
+    /// Layers from top to bottom
+    fn layers(&self) -> Vec<&PathBuf> {
+        match self {
+            RootView::Merged(root) => vec![root],
+            RootView::Layers { upper, lowers } => std::iter::once(upper).chain(lowers.iter()).collect()
+        }
+    }
+
+    /// Whether an ancestor of `path` in `layer` hides the layers below
+    fn hides_lower(layer: &Path, path: &Path) -> bool {
+        let mut ancestor = layer.to_path_buf();
+        for component in path.parent().map(Path::components).into_iter().flatten() {
+            ancestor.push(component);
+            match fs::symlink_metadata(&ancestor) {
+                Ok(metadata) if metadata.is_dir() => {
+                    if is_opaque(&ancestor) {
+                        return true;
+                    }
+                }
+                Ok(_) => return true,
+                Err(_) => return false
+            }
+        }
+        false
+    }
+
+    fn component_name(component: Component) -> Option<OsString> {
+        match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None
+        }
+    }
+
+}
+
+/// Whether a file is an overlayfs whiteout, a character device with number 0/0
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Whether a directory is an overlayfs opaque directory
+pub fn is_opaque(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false
+    };
+    let mut value = [0u8; 1];
+    let size = unsafe {
+        libc::lgetxattr(path.as_ptr(), RootView::OPAQUE_XATTR.as_ptr() as *const libc::c_char, value.as_mut_ptr() as *mut libc::c_void, value.len())
+    };
+    size == 1 && value[0] == b'y'
+}
+
+pub fn set_opaque(path: &Path) -> Result<(), Error> {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return Err(Error::new("Invalid path"))
+    };
+    let value = b"y";
+    let result = unsafe {
+        libc::lsetxattr(path.as_ptr(), RootView::OPAQUE_XATTR.as_ptr() as *const libc::c_char, value.as_ptr() as *const libc::c_void, value.len(), 0)
+    };
+    if result != 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    Ok(())
+}
+
+/// Create an overlayfs whiteout at `path`, hiding it in the layers below
+pub fn make_whiteout(path: &Path) -> Result<(), Error> {
+    mknod(path, SFlag::S_IFCHR, Mode::empty(), 0)?;
+    Ok(())
+}
+
+/// Copy the permissions of a file, and its owner when running as root
+pub fn copy_metadata(metadata: &Metadata, target: &Path) -> Result<(), Error> {
+    fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+    if geteuid().is_root() {
+        chown(target, Some(Uid::from_raw(metadata.uid())), Some(Gid::from_raw(metadata.gid())))?;
+    }
+    Ok(())
+}
+
+/// Copy a layer of an overlay (e.g. the upper layer) to `target`, which must not exist. Keeps
+/// what overlayfs relies on: whiteouts, opaque directories and the other extended attributes,
+/// along with ownership, permissions, timestamps and hard links.
+pub fn copy_layer(source: &Path, target: &Path) -> Result<(), Error> {
+    LayerCopy::new(false).copy(source, target)
+}
+
+/// Copy the upper layer of an overlay to `target` so it can be used as a lower layer. Whiteouts
+/// and opaque directories mean the same in lower layers, the other overlayfs attributes are left
+/// out, see `read_portable_xattrs`.
+pub fn copy_upper_as_lower(source: &Path, target: &Path) -> Result<(), Error> {
+    LayerCopy::new(true).copy(source, target)
+}
+
+struct LayerCopy {
+    // Whether the copy is used as a lower layer
+    as_lower: bool,
+    // First copy of the files with several hard links, by device and inode
+    links: HashMap<(u64, u64), PathBuf>,
+    // Directories copied, whose timestamps are set at the end
+    directories: Vec<(PathBuf, Metadata)>
+}
+
+impl LayerCopy {
+
+    fn new(as_lower: bool) -> LayerCopy {
+        LayerCopy {
+            as_lower,
+            links: HashMap::new(),
+            directories: vec![]
+        }
+    }
+
+    fn copy(mut self, source: &Path, target: &Path) -> Result<(), Error> {
+        self.copy_entry(source, target)?;
+        // Creating the entries of a directory changes its timestamps
+        for (directory, metadata) in &self.directories {
+            set_times(directory, metadata)?;
+        }
+        Ok(())
+    }
+
+    fn copy_entry(&mut self, source: &Path, target: &Path) -> Result<(), Error> {
+        let metadata = fs::symlink_metadata(source)?;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            fs::create_dir(target)?;
+            for entry in fs::read_dir(source)? {
+                let entry = entry?;
+                self.copy_entry(&entry.path(), &target.join(entry.file_name()))?;
+            }
+            self.copy_attributes(source, target, &metadata)?;
+            self.directories.push((target.to_path_buf(), metadata));
+            return Ok(());
+        }
+        if metadata.nlink() > 1 {
+            if let Some(first) = self.links.get(&(metadata.dev(), metadata.ino())) {
+                fs::hard_link(first, target)?;
+                return Ok(());
+            }
+            self.links.insert((metadata.dev(), metadata.ino()), target.to_path_buf());
+        }
+        if file_type.is_symlink() {
+            symlink(fs::read_link(source)?, target)?;
+        }
+        else if file_type.is_file() {
+            fs::copy(source, target)?;
+        }
+        else {
+            // Whiteouts, and the devices, fifos and sockets created inside the DevEnv
+            let kind = SFlag::from_bits_truncate(metadata.mode() & libc::S_IFMT);
+            mknod(target, kind, Mode::from_bits_truncate(metadata.mode()), metadata.rdev())?;
+        }
+        self.copy_attributes(source, target, &metadata)?;
+        set_times(target, &metadata)
+    }
+
+    /// Copy the owner, the permissions and the extended attributes of a file, without following
+    /// symlinks
+    fn copy_attributes(&self, source: &Path, target: &Path, metadata: &Metadata) -> Result<(), Error> {
+        let c_target = CString::new(target.as_os_str().as_bytes()).unwrap();
+        // Before the permissions, as changing the owner clears the setuid and setgid bits
+        if geteuid().is_root() && unsafe { libc::lchown(c_target.as_ptr(), metadata.uid(), metadata.gid()) } != 0 {
+            return Err(Error::from(nix::Error::last()));
+        }
+        if !metadata.file_type().is_symlink() {
+            fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+        }
+        let xattrs = if self.as_lower { read_portable_xattrs(source)? } else { read_xattrs(source)? };
+        for (name, value) in xattrs {
+            write_xattr(target, &name, &value)?;
+        }
+        Ok(())
+    }
+
+}
+
+/// Names and values of extended attributes
+pub type Xattrs = Vec<(CString, Vec<u8>)>;
+
+/// Extended attributes of a file, without following symlinks
+pub fn read_xattrs(path: &Path) -> Result<Xattrs, Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return Ok(vec![]);
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+    if size < 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    let mut xattrs = vec![];
+    for name in names[..size as usize].split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name).unwrap();
+        let size = unsafe { libc::lgetxattr(c_path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(Error::from(nix::Error::last()));
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe { libc::lgetxattr(c_path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+        if size < 0 {
+            return Err(Error::from(nix::Error::last()));
+        }
+        value.truncate(size as usize);
+        xattrs.push((name, value));
+    }
+    Ok(xattrs)
+}
+
+const OVERLAY_XATTR_PREFIX: &[u8] = b"trusted.overlay.";
+
+// Attributes of files that depend on the layers below them
+const UNSUPPORTED_XATTRS: &[&[u8]] = &[b"trusted.overlay.redirect", b"trusted.overlay.metacopy"];
+
+/// Extended attributes of a file of an upper layer that keep their meaning in another overlay.
+/// Opaque directories are kept, but the other overlayfs attributes only make sense for the upper
+/// layer they were set on. Files that need the layers below them are refused.
+pub fn read_portable_xattrs(path: &Path) -> Result<Xattrs, Error> {
+    let mut xattrs = read_xattrs(path)?;
+    for (name, _) in &xattrs {
+        if UNSUPPORTED_XATTRS.contains(&name.as_bytes()) {
+            return Err(Error::new(format!("{:?} has the attribute {:?}, which only works in the upper layer", path, name).as_str()));
+        }
+    }
+    xattrs.retain(|(name, _)| !name.as_bytes().starts_with(OVERLAY_XATTR_PREFIX) || name.as_bytes_with_nul() == RootView::OPAQUE_XATTR);
+    Ok(xattrs)
+}
+
+/// Set an extended attribute of a file, without following symlinks
+pub fn write_xattr(path: &Path, name: &CString, value: &[u8]) -> Result<(), Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    if unsafe { libc::lsetxattr(c_path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) } != 0 {
+        error!("Could not set the extended attribute {:?} of {:?}", name, path);
+        return Err(Error::from(nix::Error::last()));
+    }
+    Ok(())
+}
+
+/// Set the access and modification times of a file, without following symlinks
+pub fn set_times(target: &Path, metadata: &Metadata) -> Result<(), Error> {
+    let c_target = CString::new(target.as_os_str().as_bytes()).unwrap();
+    let times = [
+        libc::timespec { tv_sec: metadata.atime(), tv_nsec: metadata.atime_nsec() },
+        libc::timespec { tv_sec: metadata.mtime(), tv_nsec: metadata.mtime_nsec() }
+    ];
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_target.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    Ok(())
+}
+
+/// Space used by the files under `path`, counting files with several hard links once
+pub fn disk_usage(path: &Path) -> Result<u64, Error> {
+    let mut total = 0;
+    let mut links: HashSet<(u64, u64)> = HashSet::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let metadata = fs::symlink_metadata(&path)?;
+        if !metadata.is_dir() && metadata.nlink() > 1 && !links.insert((metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        total += metadata.blocks() * 512;
+        if metadata.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+        }
+    }
+    Ok(total)
+}
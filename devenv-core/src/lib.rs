@@ -21,8 +21,24 @@
  * THE SOFTWARE.
  */
 
+mod archive;
+mod bootstrap;
 pub mod configuration;
 mod container;
+mod control;
+pub mod copy;
 pub mod devenv;
+mod diff;
 mod filesystem;
+mod hooks;
+mod init;
+mod join;
+mod layers;
 mod mount;
+mod oci;
+mod ports;
+mod registry;
+mod services;
+mod snapshot;
+mod store;
+mod terminal;
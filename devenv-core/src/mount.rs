@@ -22,7 +22,7 @@
  */
 
 use std::str::FromStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use devenv_common::error::Error;
 use std::fs::File;
 use std::io;
@@ -31,12 +31,15 @@ use nix::{self, mount::MsFlags, };
 use std::fmt;
 
 // Some interesting filesystem types for DevEnv
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum FsType {
     Proc,
     Overlay,
     Tmpfs,
     Sysfs,
+    Devpts,
+    Cgroup2,
+    Ext4,
     Other(String)
 }
 
@@ -50,6 +53,9 @@ impl FromStr for FsType {
             "tmpfs" => Ok(FsType::Tmpfs),
             "overlay" => Ok(FsType::Overlay),
             "sysfs" => Ok(FsType::Sysfs),
+            "devpts" => Ok(FsType::Devpts),
+            "cgroup2" => Ok(FsType::Cgroup2),
+            "ext4" => Ok(FsType::Ext4),
             &_ => Ok(FsType::Other(s.to_owned()))
         }
     }
@@ -63,6 +69,9 @@ impl fmt::Display for FsType {
             FsType::Overlay => "overlay",
             FsType::Tmpfs => "tmpfs",
             FsType::Sysfs => "sysfs",
+            FsType::Devpts => "devpts",
+            FsType::Cgroup2 => "cgroup2",
+            FsType::Ext4 => "ext4",
             FsType::Other(s) => s.as_str()
         };
         write!(f, "{}", fsname)
@@ -134,6 +143,11 @@ impl MTab {
         filtered.len() > 0
     }
 
+    /// The last filesystem of type `fstype` mounted on `path`, which is the visible one
+    pub fn get(&self, path: &Path, fstype: FsType) -> Option<&MountingPoint> {
+        self.mounting_points.iter().rev().find(|mts| mts.path == path && mts.fstype.as_ref() == Some(&fstype))
+    }
+
     pub fn get_mounting_points() -> Result<Vec<MountingPoint>, Error> {
         let mut results: Vec<MountingPoint> = vec![];
         let mtab = File::open("/etc/mtab")?;
@@ -0,0 +1,702 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::ffi::CString;
+use nix::sys::stat::{mknod, Mode, SFlag};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType, Header};
+use log::{debug, info, warn};
+use devenv_common::error::Error;
+
+use crate::archive::{self, Input, Unpacked};
+use crate::layers::{set_opaque, Xattrs};
+use crate::store::data_dir;
+
+/// Where the files of an image are read from
+enum ImageSource {
+    /// An OCI image layout, or an extracted `docker save` archive
+    Directory(PathBuf),
+    /// An archive made by `docker save`, or an OCI image layout in a tarball, with the position and
+    /// the size of its files
+    Tarball {
+        path: PathBuf,
+        files: HashMap<PathBuf, (u64, u64)>
+    }
+}
+
+impl ImageSource {
+
+    fn open(path: &Path) -> Result<ImageSource, Error> {
+        if path.is_dir() {
+            return Ok(ImageSource::Directory(path.to_path_buf()));
+        }
+        let mut files = HashMap::new();
+        let mut links = HashMap::new();
+        let mut archive = Archive::new(File::open(path)?);
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(e) => return Err(Error::new_error(format!("{:?} is not an image archive, compressed archives must be decompressed first", path).as_str(), Box::from(e)))
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = match ImageSource::normalize(&entry.path()?) {
+                Some(name) => name,
+                None => continue
+            };
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    files.insert(name, (entry.raw_file_position(), entry.size()));
+                }
+                // `docker save` links the layers shared by several images
+                EntryType::Symlink | EntryType::Link => {
+                    if let Some(link) = entry.link_name()? {
+                        let target = match entry.header().entry_type() {
+                            EntryType::Symlink => name.parent().unwrap_or(Path::new("")).join(link),
+                            _ => link.into_owned()
+                        };
+                        if let Some(target) = ImageSource::normalize(&target) {
+                            links.insert(name, target);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (name, target) in links {
+            if let Some(file) = files.get(&target).cloned() {
+                files.insert(name, file);
+            }
+        }
+        Ok(ImageSource::Tarball { path: path.to_path_buf(), files })
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            ImageSource::Directory(path) => path.join(name).is_file(),
+            ImageSource::Tarball { files, .. } => files.contains_key(Path::new(name))
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Box<dyn Read + Send>, Error> {
+        match self {
+            ImageSource::Directory(path) => match File::open(path.join(name)) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(e) => Err(Error::new_error(format!("Could not open {:?} of the image", name).as_str(), Box::from(e)))
+            },
+            ImageSource::Tarball { path, files } => {
+                let (position, size) = match ImageSource::normalize(Path::new(name)).and_then(|name| files.get(&name)) {
+                    Some(file) => *file,
+                    None => return Err(Error::new(format!("The image has no file {:?}", name).as_str()))
+                };
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(position))?;
+                Ok(Box::new(file.take(size)))
+            }
+        }
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, name: &str) -> Result<T, Error> {
+        match serde_json::from_reader(BufReader::new(self.read(name)?)) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(Error::new_error(format!("Invalid {:?} in the image", name).as_str(), Box::from(e)))
+        }
+    }
+
+    /// Path relative to the root of the image, without `.` components
+    fn normalize(path: &Path) -> Option<PathBuf> {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(component) => normalized.push(component),
+                Component::CurDir | Component::RootDir => {}
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        return None;
+                    }
+                }
+                Component::Prefix(_) => return None
+            }
+        }
+        Some(normalized)
+    }
+
+}
+
+/// An image of the manifest written by `docker save`
+#[derive(Debug)]
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerImage {
+    /// File with the configuration of the image
+    config: String,
+    /// Names of the image, missing for untagged images
+    repo_tags: Option<Vec<String>>,
+    /// Files of the layers, from the bottom one
+    #[serde(default)]
+    layers: Vec<String>
+}
+
+/// The manifest of an image, or an index of the manifests of several images, told apart by their
+/// media type
+#[derive(Debug)]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    media_type: Option<String>,
+    /// Only in indexes
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    /// Only in manifests of images
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>
+}
+
+/// Reference to a blob of an OCI image layout
+#[derive(Debug)]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    /// Platform of the image, in indexes of multi-platform images
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>
+}
+
+#[derive(Debug)]
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String
+}
+
+/// Configuration of an image, of which devenv only needs the digests of the layers
+#[derive(Debug)]
+#[derive(Deserialize)]
+struct ImageConfig {
+    rootfs: RootFs
+}
+
+#[derive(Debug)]
+#[derive(Deserialize)]
+struct RootFs {
+    /// Digests of the uncompressed layers, from the bottom one
+    diff_ids: Vec<String>
+}
+
+/// Image in the OCI image layout (e.g. made by `skopeo copy` or `buildah push`) or made by
+/// `docker save`. Its layers are unpacked into a cache shared by all the DevEnvs, where they are
+/// named after the digest of their content, and stacked as the lower layers of the overlay.
+pub struct OciImage {
+    source: ImageSource,
+    /// Files of the layers in the image, from the bottom one
+    layers: Vec<String>,
+    /// Digests of the uncompressed layers, in the same order
+    diff_ids: Vec<String>
+}
+
+impl OciImage {
+
+    const CACHE_DIR: &'static str = "oci-layers";
+
+    const LAYOUT_FILE: &'static str = "oci-layout";
+
+    const INDEX_FILE: &'static str = "index.json";
+
+    const DOCKER_MANIFEST_FILE: &'static str = "manifest.json";
+
+    const REF_NAME_ANNOTATION: &'static str = "org.opencontainers.image.ref.name";
+
+    const INDEX_MEDIA_TYPES: &'static [&'static str] = &[
+        "application/vnd.oci.image.index.v1+json",
+        "application/vnd.docker.distribution.manifest.list.v2+json"
+    ];
+
+    // Prefix of the PAX records with the extended attributes of a file
+    const XATTR_PAX_PREFIX: &'static str = "SCHILY.xattr.";
+
+    const WHITEOUT_PREFIX: &'static str = ".wh.";
+
+    // Hides the content of its directory in the layers below
+    const OPAQUE_WHITEOUT: &'static str = ".wh..wh..opq";
+
+    /// Cache of the layers unpacked from images
+    pub fn layer_cache() -> Result<PathBuf, Error> {
+        Ok(data_dir()?.join(OciImage::CACHE_DIR))
+    }
+
+    /// Layers unpacked into the cache at `cache`, with their digests
+    pub fn cached_layers(cache: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+        let mut layers = vec![];
+        if !cache.is_dir() {
+            return Ok(layers);
+        }
+        for algorithm in fs::read_dir(cache)? {
+            let algorithm = algorithm?;
+            if !algorithm.file_type()?.is_dir() {
+                continue;
+            }
+            for layer in fs::read_dir(algorithm.path())? {
+                let layer = layer?;
+                let hex = layer.file_name().to_string_lossy().into_owned();
+                // Layers being unpacked
+                if hex.starts_with('.') {
+                    continue;
+                }
+                layers.push((format!("{}:{}", algorithm.file_name().to_string_lossy(), hex), layer.path()));
+            }
+        }
+        layers.sort();
+        Ok(layers)
+    }
+
+    /// Whether `path` is an image instead of a directory with a root filesystem
+    pub fn is_image(path: &Path) -> bool {
+        path.is_file() || path.join(OciImage::LAYOUT_FILE).is_file() || path.join(OciImage::DOCKER_MANIFEST_FILE).is_file()
+    }
+
+    /// Open the image at `path`. If it has several images, `tag` chooses one of them.
+    pub fn open(path: &Path, tag: Option<&str>) -> Result<OciImage, Error> {
+        let source = ImageSource::open(path)?;
+        if source.contains(OciImage::DOCKER_MANIFEST_FILE) {
+            OciImage::from_docker_manifest(source, tag)
+        }
+        else if source.contains(OciImage::INDEX_FILE) {
+            OciImage::from_index(source, tag)
+        }
+        else {
+            Err(Error::new(format!("{:?} is neither an OCI image layout nor an archive made by docker save", path).as_str()))
+        }
+    }
+
+    fn from_docker_manifest(source: ImageSource, tag: Option<&str>) -> Result<OciImage, Error> {
+        let images: Vec<DockerImage> = source.read_json(OciImage::DOCKER_MANIFEST_FILE)?;
+        let image = match tag {
+            Some(tag) => images.iter().find(|image| image.repo_tags.iter().flatten().any(|name| OciImage::matches(name, tag))),
+            None if images.len() > 1 => return Err(Error::new("The archive has several images, choose one with the tag of the image")),
+            None => images.first()
+        };
+        let image = match image {
+            Some(image) => image,
+            None => return Err(Error::new(format!("The archive has no image {}", tag.unwrap_or_default()).as_str()))
+        };
+        let config = source.read_json(&image.config)?;
+        OciImage::new(source, image.layers.clone(), config)
+    }
+
+    fn from_index(source: ImageSource, tag: Option<&str>) -> Result<OciImage, Error> {
+        let index = source.read_json(OciImage::INDEX_FILE)?;
+        let manifest = OciImage::select_manifest(&source, &index, tag)?;
+        let config = match &manifest.config {
+            Some(config) => source.read_json(&OciImage::blob_path(&config.digest)?)?,
+            None => return Err(Error::new("The image has no configuration"))
+        };
+        let layers = manifest.layers.iter().map(|layer| OciImage::blob_path(&layer.digest)).collect::<Result<_, _>>()?;
+        OciImage::new(source, layers, config)
+    }
+
+    fn new(source: ImageSource, layers: Vec<String>, config: ImageConfig) -> Result<OciImage, Error> {
+        let diff_ids = config.rootfs.diff_ids;
+        if diff_ids.len() != layers.len() {
+            return Err(Error::new(format!("The image has {} layers, but its configuration describes {}", layers.len(), diff_ids.len()).as_str()));
+        }
+        Ok(OciImage { source, layers, diff_ids })
+    }
+
+    /// Manifest of the image of an index chosen by its tag, or for the platform of the host if
+    /// the index has the manifests of several platforms
+    fn select_manifest(source: &ImageSource, index: &Manifest, tag: Option<&str>) -> Result<Manifest, Error> {
+        let manifests = &index.manifests;
+        let by_platform = manifests.iter().any(|manifest| manifest.platform.is_some());
+        let selected = match tag {
+            Some(tag) if !by_platform => manifests.iter().find(|manifest| {
+                manifest.annotations.get(OciImage::REF_NAME_ANNOTATION).is_some_and(|name| OciImage::matches(name, tag))
+            }),
+            _ if by_platform => manifests.iter().find(|manifest| {
+                manifest.platform.as_ref().is_some_and(|platform| platform.os == "linux" && platform.architecture == OciImage::architecture())
+            }),
+            _ if manifests.len() > 1 => return Err(Error::new("The image layout has several images, choose one with the tag of the image")),
+            _ => manifests.first()
+        };
+        let selected = match selected {
+            Some(selected) => selected,
+            None if by_platform => return Err(Error::new(format!("The image has no variant for linux/{}", OciImage::architecture()).as_str())),
+            None => return Err(Error::new(format!("The image layout has no image {}", tag.unwrap_or_default()).as_str()))
+        };
+        let manifest: Manifest = source.read_json(&OciImage::blob_path(&selected.digest)?)?;
+        let media_type = manifest.media_type.as_ref().or(selected.media_type.as_ref()).map(String::as_str).unwrap_or_default();
+        if OciImage::INDEX_MEDIA_TYPES.contains(&media_type) {
+            // The tag names the index, then the platform chooses the image
+            return OciImage::select_manifest(source, &manifest, None);
+        }
+        Ok(manifest)
+    }
+
+    /// Whether the name of an image (e.g. `docker.io/library/debian:bookworm`) has the tag `tag`
+    fn matches(name: &str, tag: &str) -> bool {
+        name == tag || name.ends_with(&format!(":{}", tag)) || name.ends_with(&format!("/{}", tag)) || (!tag.contains(':') && name.ends_with(&format!("{}:latest", tag)))
+    }
+
+    /// Name of the architecture of the host in OCI images
+    fn architecture() -> &'static str {
+        match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" => "ppc64le",
+            architecture => architecture
+        }
+    }
+
+    /// Path of a blob of an OCI image layout, which is named after its digest
+    fn blob_path(digest: &str) -> Result<String, Error> {
+        let (algorithm, hex) = OciImage::split_digest(digest)?;
+        Ok(format!("blobs/{}/{}", algorithm, hex))
+    }
+
+    fn split_digest(digest: &str) -> Result<(&str, &str), Error> {
+        let mut parts = digest.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(algorithm), Some(hex)) if !algorithm.is_empty() && !hex.is_empty() && algorithm.chars().all(|c| c.is_ascii_alphanumeric()) && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok((algorithm, hex)),
+            _ => Err(Error::new(format!("Invalid digest {:?}", digest).as_str()))
+        }
+    }
+
+    /// Unpack the layers missing from the cache at `cache`, and return the directories of all the
+    /// layers of the image, from the bottom one
+    pub fn unpack(&self, cache: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut directories = vec![];
+        for (layer, diff_id) in self.layers.iter().zip(&self.diff_ids) {
+            let (algorithm, hex) = OciImage::split_digest(diff_id)?;
+            let directory = cache.join(algorithm).join(hex);
+            if !directory.exists() {
+                info!("Unpacking the layer {}", diff_id);
+                fs::create_dir_all(directory.parent().unwrap())?;
+                // Another DevEnv may be unpacking the same layer
+                let staging = cache.join(algorithm).join(format!(".{}.{}.tmp", hex, process::id()));
+                if staging.exists() {
+                    fs::remove_dir_all(&staging)?;
+                }
+                let unpacked = Input::from_reader(self.source.read(layer)?).and_then(|input| OciImage::unpack_layer(input, &staging, diff_id));
+                if let Err(e) = unpacked {
+                    if let Err(e) = fs::remove_dir_all(&staging) {
+                        warn!("Could not remove {:?}: {}", staging, e);
+                    }
+                    return Err(e);
+                }
+                if let Err(e) = fs::rename(&staging, &directory) {
+                    if !directory.exists() {
+                        return Err(Error::from(e));
+                    }
+                    debug!("The layer {} was unpacked by another DevEnv", diff_id);
+                    fs::remove_dir_all(&staging)?;
+                }
+            }
+            directories.push(directory);
+        }
+        Ok(directories)
+    }
+
+    /// Unpack a layer into the directory `target`, turning the whiteouts of OCI images into the
+    /// ones of overlayfs. Fails if its content does not match `diff_id`, the digest of the
+    /// uncompressed layer.
+    fn unpack_layer(input: Input, target: &Path, diff_id: &str) -> Result<(), Error> {
+        let (algorithm, hex) = OciImage::split_digest(diff_id)?;
+        if algorithm != "sha256" {
+            return Err(Error::new(format!("The digest {} of the layer uses an unsupported algorithm", diff_id).as_str()));
+        }
+        let mut input = DigestReader { reader: input, digest: Sha256::new() };
+        fs::create_dir(target)?;
+        {
+            let mut archive = Archive::new(&mut input);
+            // Directories are updated once their content is written, as they might be read-only
+            let mut directories: Vec<(PathBuf, Header, Xattrs)> = vec![];
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let path = match ImageSource::normalize(&entry.path()?) {
+                    Some(path) => path,
+                    None => {
+                        warn!("Skipping {:?}, its path is outside the layer", entry.path()?);
+                        continue;
+                    }
+                };
+                let name = match path.file_name() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    // The root of the layer
+                    None => continue
+                };
+                // Never through a symlink, which could point outside of the layer
+                let destination = archive::entry_destination(target, &path)?;
+                let parent = target.join(path.parent().unwrap_or(Path::new("")));
+                fs::create_dir_all(&parent)?;
+                if name == OciImage::OPAQUE_WHITEOUT {
+                    set_opaque(&parent)?;
+                    continue;
+                }
+                if let Some(hidden) = name.strip_prefix(OciImage::WHITEOUT_PREFIX) {
+                    // Other names starting with .wh..wh. are metadata of aufs
+                    if !hidden.starts_with(OciImage::WHITEOUT_PREFIX) {
+                        let whiteout = parent.join(hidden);
+                        if let Ok(metadata) = fs::symlink_metadata(&whiteout) {
+                            if metadata.is_dir() {
+                                fs::remove_dir_all(&whiteout)?;
+                            }
+                            else {
+                                fs::remove_file(&whiteout)?;
+                            }
+                        }
+                        mknod(&whiteout, SFlag::S_IFCHR, Mode::empty(), 0)?;
+                    }
+                    continue;
+                }
+                let mut xattrs = vec![];
+                if let Some(extensions) = entry.pax_extensions()? {
+                    for extension in extensions {
+                        match extension {
+                            Ok(extension) => {
+                                if let Some(key) = extension.key().ok().and_then(|key| key.strip_prefix(OciImage::XATTR_PAX_PREFIX)) {
+                                    match CString::new(key) {
+                                        Ok(name) => xattrs.push((name, extension.value_bytes().to_vec())),
+                                        Err(_) => warn!("Skipping the extended attribute {:?} of {:?}, its name contains a nul byte", key, path)
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Skipping the extended attributes of {:?}: {}", path, e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                let link = match entry.header().entry_type() {
+                    EntryType::Link => match entry.link_name()?.and_then(|link| ImageSource::normalize(&link)) {
+                        Some(link) => Some(archive::entry_destination(target, &link)?),
+                        None => None
+                    },
+                    _ => None
+                };
+                if let Unpacked::Directory = archive::unpack_entry(&mut entry, &destination, link.as_deref(), &xattrs)? {
+                    directories.push((destination, entry.header().clone(), xattrs));
+                }
+            }
+            for (destination, header, xattrs) in directories.iter().rev() {
+                // A later entry may have replaced the directory with a symlink
+                if fs::symlink_metadata(destination)?.is_dir() {
+                    archive::set_attributes(destination, header, xattrs)?;
+                }
+            }
+        }
+        // The end of the archive may be followed by padding, which is part of the digest
+        io::copy(&mut input, &mut io::sink())?;
+        let digest = format!("{:x}", input.digest.finalize());
+        if digest != hex.to_ascii_lowercase() {
+            return Err(Error::new(format!("The layer {} is corrupted, its content has the digest sha256:{}", diff_id, digest).as_str()));
+        }
+        input.reader.finish()
+    }
+
+}
+
+/// Reads through `reader`, computing the digest of what is read
+struct DigestReader<R: Read> {
+    reader: R,
+    digest: Sha256
+}
+
+impl<R: Read> Read for DigestReader<R> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.digest.update(&buf[..read]);
+        Ok(read)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::{Cursor, Write};
+    use tar::Builder;
+
+    fn header(entry_type: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+        header.set_size(size);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    fn diff_id(layer: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(layer))
+    }
+
+    fn unpack(layer: &[u8], target: &Path, diff_id: &str) -> Result<(), Error> {
+        OciImage::unpack_layer(Input::from_reader(Cursor::new(layer.to_vec()))?, target, diff_id)
+    }
+
+    fn layer(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Regular, data.len() as u64), name, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn config(layers: &[&[u8]]) -> Vec<u8> {
+        let diff_ids: Vec<_> = layers.iter().map(|layer| format!("{:?}", diff_id(layer))).collect();
+        format!(r#"{{"architecture": "amd64", "os": "linux", "rootfs": {{"type": "layers", "diff_ids": [{}]}}}}"#, diff_ids.join(", ")).into_bytes()
+    }
+
+    /// Write a blob into the OCI image layout at `layout` and return its descriptor
+    fn blob(layout: &Path, media_type: &str, data: &[u8]) -> String {
+        let hex = format!("{:x}", Sha256::digest(data));
+        fs::create_dir_all(layout.join("blobs/sha256")).unwrap();
+        fs::write(layout.join("blobs/sha256").join(&hex), data).unwrap();
+        format!(r#"{{"mediaType": "{}", "digest": "sha256:{}", "size": {}}}"#, media_type, hex, data.len())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_layers(directories: &[PathBuf], name: &str) -> Vec<String> {
+        directories.iter().map(|directory| fs::read_to_string(directory.join(name)).unwrap_or_default()).collect()
+    }
+
+    #[test]
+    fn opens_a_docker_save_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = layer("file", b"base");
+        let top = layer("file", b"top");
+        let manifest = r#"[
+            {"Config": "1.json", "RepoTags": ["docker.io/library/app:1"], "Layers": ["base/layer.tar"]},
+            {"Config": "2.json", "RepoTags": null, "Layers": ["shared/layer.tar", "top/layer.tar"]},
+            {"Config": "3.json", "RepoTags": ["app:3"], "Layers": ["shared/layer.tar", "top/layer.tar"]}
+        ]"#;
+        let mut builder = Builder::new(vec![]);
+        let files: &[(&str, &[u8])] = &[
+            ("manifest.json", manifest.as_bytes()), ("1.json", &config(&[&base])), ("3.json", &config(&[&base, &top])),
+            ("base/layer.tar", &base), ("top/layer.tar", &top)
+        ];
+        for (name, data) in files {
+            builder.append_data(&mut header(EntryType::Regular, data.len() as u64), name, *data).unwrap();
+        }
+        // Layers shared by several images are links to the first copy
+        builder.append_link(&mut header(EntryType::Symlink, 0), "shared/layer.tar", "../base/layer.tar").unwrap();
+        let archive = dir.path().join("image.tar");
+        fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        let cache = dir.path().join("cache");
+        let layers = OciImage::open(&archive, Some("app:3")).unwrap().unpack(&cache).unwrap();
+        assert_eq!(read_layers(&layers, "file"), vec!["base", "top"]);
+        assert_eq!(layers[0], cache.join("sha256").join(&diff_id(&base)["sha256:".len()..]));
+        let layers = OciImage::open(&archive, Some("1")).unwrap().unpack(&cache).unwrap();
+        assert_eq!(read_layers(&layers, "file"), vec!["base"]);
+
+        let error = OciImage::open(&archive, None).err().unwrap();
+        assert!(error.message().contains("several images"), "{}", error);
+        let error = OciImage::open(&archive, Some("app:4")).err().unwrap();
+        assert!(error.message().contains("no image app:4"), "{}", error);
+    }
+
+    #[test]
+    fn opens_an_oci_image_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = dir.path().join("layout");
+        let mut manifests = vec![];
+        for architecture in &["not-this-one", OciImage::architecture()] {
+            let layer = layer("arch", architecture.as_bytes());
+            let manifest = format!(
+                r#"{{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.manifest.v1+json", "config": {}, "layers": [{}]}}"#,
+                blob(&layout, "application/vnd.oci.image.config.v1+json", &config(&[&layer])),
+                blob(&layout, "application/vnd.oci.image.layer.v1.tar+gzip", &gzip(&layer))
+            );
+            let descriptor = blob(&layout, "application/vnd.oci.image.manifest.v1+json", manifest.as_bytes());
+            manifests.push(format!(r#"{}, "platform": {{"os": "linux", "architecture": "{}"}}}}"#, descriptor.trim_end_matches('}'), architecture));
+        }
+        let index = format!(r#"{{"schemaVersion": 2, "manifests": [{}]}}"#, manifests.join(", "));
+        let index = blob(&layout, "application/vnd.oci.image.index.v1+json", index.as_bytes());
+        // The index of the layout references the index of the platforms
+        let annotated = format!(r#"{}, "annotations": {{"org.opencontainers.image.ref.name": "app:1"}}}}"#, index.trim_end_matches('}'));
+        fs::write(layout.join("index.json"), format!(r#"{{"schemaVersion": 2, "manifests": [{}]}}"#, annotated)).unwrap();
+        fs::write(layout.join("oci-layout"), r#"{"imageLayoutVersion": "1.0.0"}"#).unwrap();
+
+        assert!(OciImage::is_image(&layout));
+        let layers = OciImage::open(&layout, Some("app:1")).unwrap().unpack(&dir.path().join("cache")).unwrap();
+        assert_eq!(read_layers(&layers, "arch"), vec![OciImage::architecture()]);
+        let error = OciImage::open(&layout, Some("app:2")).err().unwrap();
+        assert!(error.message().contains("app:2"), "{}", error);
+
+        fs::write(layout.join("index.json"), r#"{"manifests": [{"digest": 1}]}"#).unwrap();
+        let error = OciImage::open(&layout, None).err().unwrap();
+        assert!(error.message().contains("Invalid \"index.json\""), "{}", error);
+    }
+
+    #[test]
+    fn unpack_layer_checks_the_diff_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Regular, 4), "file", &b"data"[..]).unwrap();
+        let layer = builder.into_inner().unwrap();
+
+        unpack(&layer, &dir.path().join("good"), &diff_id(&layer)).unwrap();
+        assert_eq!(fs::read(dir.path().join("good/file")).unwrap(), b"data");
+        let error = unpack(&layer, &dir.path().join("bad"), &diff_id(b"another layer")).unwrap_err();
+        assert!(error.message().contains("is corrupted"), "{}", error);
+    }
+
+    #[test]
+    fn unpack_layer_does_not_write_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("kept"), b"kept").unwrap();
+        let entries: &[(&str, &[u8])] = &[("escape/file", b"data"), ("escape/.wh.kept", b""), ("escape/.wh..wh..opq", b"")];
+        for (index, (name, data)) in entries.iter().enumerate() {
+            let mut builder = Builder::new(vec![]);
+            builder.append_link(&mut header(EntryType::Symlink, 0), "escape", &outside).unwrap();
+            builder.append_data(&mut header(EntryType::Regular, data.len() as u64), name, *data).unwrap();
+            let layer = builder.into_inner().unwrap();
+
+            let error = unpack(&layer, &dir.path().join(index.to_string()), &diff_id(&layer)).unwrap_err();
+            assert!(error.message().contains("inside the symlink"), "{}", error);
+        }
+        let names: Vec<_> = fs::read_dir(&outside).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, vec!["kept"]);
+        assert!(!crate::layers::is_opaque(&outside));
+    }
+}
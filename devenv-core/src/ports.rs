@@ -0,0 +1,294 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+// Port forwarding from the host into the network namespace of the container.
+//
+// The forwarder listens on the host and relays every connection to the loopback interface of the
+// container. Its threads are started before devenv unshares its namespaces, so they stay in the
+// namespaces of the host: after unsharing its PID namespace, devenv cannot start threads anymore.
+// The sockets inside the container are created by a connector thread that joins its network
+// namespace with `setns`, as sockets belong to the namespace they were created in.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use nix::sched::{setns, CloneFlags};
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+
+use crate::configuration::{Port, Protocol};
+
+/// Forwards ports of the host to the container
+pub struct PortForwarder {
+    // Receives the network namespace of the container, once it exists
+    namespace: Sender<File>
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Udp(UdpSocket)
+}
+
+// Socket created inside the container by the connector
+enum Inner {
+    Tcp(TcpStream),
+    Udp(UdpSocket)
+}
+
+struct ConnectRequest {
+    port: u16,
+    protocol: Protocol,
+    reply: Sender<io::Result<Inner>>
+}
+
+/// Creates sockets inside the network namespace of the container
+#[derive(Clone)]
+struct Connector {
+    requests: Sender<ConnectRequest>
+}
+
+impl PortForwarder {
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // A UDP client that sends nothing for this long is forgotten
+    const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    /// Listen on the host ports and start forwarding them. Must be run BEFORE unsharing the
+    /// namespaces, and with the signals that devenv handles blocked, as the threads inherit the
+    /// signal mask. Connections wait until `enter` gives the namespace of the container.
+    pub fn start(ports: &[Port]) -> Result<PortForwarder, Error> {
+        let mut listeners = vec![];
+        for port in ports {
+            let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port.host));
+            let protocol = port.protocol.unwrap_or(Protocol::Tcp);
+            let listener = match protocol {
+                Protocol::Tcp => TcpListener::bind(address).map(Listener::Tcp),
+                Protocol::Udp => UdpSocket::bind(address).map(Listener::Udp)
+            };
+            match listener {
+                Ok(listener) => listeners.push((port.container.unwrap_or(port.host), listener)),
+                Err(e) => {
+                    error!("Could not listen on {:?} port {}", protocol, port.host);
+                    return Err(Error::from(e));
+                }
+            }
+        }
+        let (namespace, namespace_receiver) = mpsc::channel();
+        let (requests, request_receiver) = mpsc::channel();
+        PortForwarder::spawn("port connector", move || Connector::serve(namespace_receiver, request_receiver))?;
+        let connector = Connector { requests };
+        for (port, listener) in listeners {
+            let connector = connector.clone();
+            match listener {
+                Listener::Tcp(listener) => PortForwarder::spawn("port forwarder", move || PortForwarder::forward_tcp(listener, port, connector))?,
+                Listener::Udp(socket) => PortForwarder::spawn("port forwarder", move || PortForwarder::forward_udp(socket, port, connector))?
+            };
+        }
+        Ok(PortForwarder { namespace })
+    }
+
+    /// Start forwarding into the network namespace `namespace` (e.g. /proc/<pid>/ns/net)
+    pub fn enter(&self, namespace: File) -> Result<(), Error> {
+        match self.namespace.send(namespace) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::new("The port forwarder is not running"))
+        }
+    }
+
+    fn forward_tcp(listener: TcpListener, port: u16, connector: Connector) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Error accepting a connection for port {}: {}", port, e);
+                    continue;
+                }
+            };
+            let connector = connector.clone();
+            let spawned = PortForwarder::spawn("port connection", move || {
+                let inner = match connector.connect(port, Protocol::Tcp) {
+                    Ok(Inner::Tcp(inner)) => inner,
+                    Ok(Inner::Udp(_)) => return,
+                    Err(e) => {
+                        debug!("Could not connect to port {} of the container: {}", port, e);
+                        return;
+                    }
+                };
+                PortForwarder::relay_tcp(stream, inner);
+            });
+            if let Err(e) = spawned {
+                warn!("Could not forward a connection to port {}: {}", port, e);
+            }
+        }
+    }
+
+    /// Copy the data in both directions until both sides are closed
+    fn relay_tcp(outer: TcpStream, inner: TcpStream) {
+        let (outer_reader, inner_reader) = match (outer.try_clone(), inner.try_clone()) {
+            (Ok(outer_reader), Ok(inner_reader)) => (outer_reader, inner_reader),
+            _ => return
+        };
+        let upstream = PortForwarder::spawn("port connection", move || PortForwarder::copy(outer_reader, inner));
+        PortForwarder::copy(inner_reader, outer);
+        if let Ok(upstream) = upstream {
+            let _ = upstream.join();
+        }
+    }
+
+    fn copy(mut from: TcpStream, mut to: TcpStream) {
+        let mut buffer = vec![0u8; PortForwarder::BUFFER_SIZE];
+        loop {
+            match from.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if to.write_all(&buffer[..n]).is_err() {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break
+            }
+        }
+        let _ = to.shutdown(Shutdown::Write);
+    }
+
+    /// Relay the datagrams of each client through its own socket inside the container, so the
+    /// replies can be sent back to it
+    fn forward_udp(socket: UdpSocket, port: u16, connector: Connector) {
+        let clients: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut buffer = vec![0u8; PortForwarder::BUFFER_SIZE];
+        loop {
+            let (length, client) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Error receiving a datagram for port {}: {}", port, e);
+                    return;
+                }
+            };
+            let known = clients.lock().unwrap().get(&client).cloned();
+            let inner = match known {
+                Some(inner) => inner,
+                None => match PortForwarder::add_udp_client(&socket, port, client, &connector, &clients) {
+                    Ok(inner) => inner,
+                    Err(e) => {
+                        debug!("Could not forward a datagram to port {} of the container: {}", port, e);
+                        continue;
+                    }
+                }
+            };
+            if let Err(e) = inner.send(&buffer[..length]) {
+                debug!("Could not forward a datagram to port {} of the container: {}", port, e);
+            }
+        }
+    }
+
+    fn add_udp_client(socket: &UdpSocket, port: u16, client: SocketAddr, connector: &Connector, clients: &Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>) -> Result<Arc<UdpSocket>, Error> {
+        let inner = match connector.connect(port, Protocol::Udp)? {
+            Inner::Udp(inner) => Arc::new(inner),
+            Inner::Tcp(_) => return Err(Error::new("Expected a UDP socket"))
+        };
+        inner.set_read_timeout(Some(PortForwarder::UDP_IDLE_TIMEOUT))?;
+        let outer = socket.try_clone()?;
+        let replies = inner.clone();
+        let thread_clients = clients.clone();
+        clients.lock().unwrap().insert(client, inner.clone());
+        let spawned = PortForwarder::spawn("port connection", move || {
+            let mut buffer = vec![0u8; PortForwarder::BUFFER_SIZE];
+            loop {
+                match replies.recv(&mut buffer) {
+                    Ok(length) => {
+                        let _ = outer.send_to(&buffer[..length], client);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // Idle, or the port is closed inside the container
+                    Err(_) => break
+                }
+            }
+            thread_clients.lock().unwrap().remove(&client);
+        });
+        if let Err(e) = spawned {
+            clients.lock().unwrap().remove(&client);
+            return Err(e);
+        }
+        Ok(inner)
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> Result<thread::JoinHandle<()>, Error> {
+        match thread::Builder::new().name(name.to_owned()).spawn(f) {
+            Ok(handle) => Ok(handle),
+            Err(e) => {
+                error!("Could not start the {} thread", name);
+                Err(Error::from(e))
+            }
+        }
+    }
+
+}
+
+impl Connector {
+
+    fn connect(&self, port: u16, protocol: Protocol) -> Result<Inner, Error> {
+        let (reply, receiver) = mpsc::channel();
+        if self.requests.send(ConnectRequest { port, protocol, reply }).is_err() {
+            return Err(Error::new("The port connector is not running"));
+        }
+        match receiver.recv() {
+            Ok(inner) => Ok(inner?),
+            Err(_) => Err(Error::new("The port connector is not running"))
+        }
+    }
+
+    /// Join the network namespace of the container once it is received, and create the sockets
+    /// requested. Must run in a thread of its own, as the namespace is only changed for it.
+    fn serve(namespace: Receiver<File>, requests: Receiver<ConnectRequest>) {
+        let namespace = match namespace.recv() {
+            Ok(namespace) => namespace,
+            // The container was not created
+            Err(_) => return
+        };
+        if let Err(e) = setns(namespace.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+            error!("Could not join the network namespace of the container: {}", e);
+            return;
+        }
+        drop(namespace);
+        for request in requests {
+            let address = SocketAddr::from((Ipv4Addr::LOCALHOST, request.port));
+            let inner = match request.protocol {
+                Protocol::Tcp => TcpStream::connect_timeout(&address, PortForwarder::CONNECT_TIMEOUT).map(Inner::Tcp),
+                Protocol::Udp => UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).and_then(|socket| socket.connect(address).map(|_| Inner::Udp(socket)))
+            };
+            let _ = request.reply.send(inner);
+        }
+    }
+
+}
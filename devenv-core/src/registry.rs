@@ -0,0 +1,118 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use serde_derive::{Serialize, Deserialize};
+use log::{debug, warn};
+use devenv_common::error::Error;
+
+use crate::store::data_dir;
+
+/// A DevEnv of the user, as recorded when it was last created
+#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct Environment {
+    /// Directory of the DevEnv, e.g. `/home/user/project/.devenv`
+    pub target: PathBuf,
+    /// Image of the DevEnv as referenced in its configuration
+    pub image: Vec<String>,
+    /// Layers of the image, from the bottom one
+    pub layers: Vec<PathBuf>,
+    /// Seconds since the Unix epoch
+    pub used: u64
+}
+
+/// DevEnvs of the user, so it is known which images and layers are in use. A DevEnv is
+/// registered every time it is created, and forgotten when it is deleted.
+///
+/// Each DevEnv is a file named after the hash of its directory, so DevEnvs can be registered
+/// concurrently.
+pub struct Registry {
+    dir: PathBuf
+}
+
+impl Registry {
+
+    const ENVIRONMENTS_DIR: &'static str = "environments";
+
+    pub fn new() -> Result<Registry, Error> {
+        Ok(Registry {
+            dir: data_dir()?.join(Registry::ENVIRONMENTS_DIR)
+        })
+    }
+
+    pub fn register(&self, environment: &Environment) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+        let contents = match toml::to_string(environment) {
+            Ok(contents) => contents,
+            Err(e) => return Err(Error::new_error("Could not serialize the DevEnv", Box::from(e)))
+        };
+        let path = self.path(&environment.target);
+        // Renamed into place, so the file is never read half written
+        let staging = self.dir.join(format!(".{}.tmp", process::id()));
+        fs::write(&staging, contents)?;
+        fs::rename(&staging, &path)?;
+        debug!("Registered the DevEnv {:?}", environment.target);
+        Ok(())
+    }
+
+    pub fn unregister(&self, target: &Path) -> Result<(), Error> {
+        match fs::remove_file(self.path(target)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::from(e)),
+            _ => Ok(())
+        }
+    }
+
+    /// Registered DevEnvs, including the ones whose directory was removed without devenv
+    pub fn list(&self) -> Result<Vec<Environment>, Error> {
+        let mut environments = vec![];
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(environments),
+            Err(e) => return Err(Error::from(e))
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            let contents = fs::read_to_string(&path)?;
+            match toml::from_str::<Environment>(contents.as_str()) {
+                Ok(environment) => environments.push(environment),
+                Err(e) => warn!("Skipping the invalid DevEnv {:?}: {}", path, e)
+            }
+        }
+        environments.sort_by(|a, b| a.target.cmp(&b.target));
+        Ok(environments)
+    }
+
+    fn path(&self, target: &Path) -> PathBuf {
+        let hash = blake2b_simd::Params::new().hash_length(16).hash(target.as_os_str().as_bytes());
+        self.dir.join(format!("{}.toml", hash.to_hex()))
+    }
+
+}
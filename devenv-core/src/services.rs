@@ -0,0 +1,482 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fmt;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, OFlag};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::stat::Mode;
+use nix::unistd::Pid;
+use serde_derive::{Serialize, Deserialize};
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+
+use crate::configuration::{Readiness, RestartPolicy, Service};
+use crate::container::TaskResult;
+use crate::init::{Command, Init};
+
+/// Request about the services of the container
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ServiceCommand {
+    /// Start services, and wait until they are ready
+    Start(Vec<Service>),
+    Status,
+    /// Restart a service, or start it again if it stopped
+    Restart(String)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ServiceState {
+    /// Started, and waiting for its readiness check
+    Starting,
+    Running,
+    /// Exited, and waiting to be restarted
+    BackingOff,
+    /// Exited, and not restarted
+    Stopped
+}
+
+impl fmt::Display for ServiceState {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            ServiceState::Starting => "starting",
+            ServiceState::Running => "running",
+            ServiceState::BackingOff => "backing-off",
+            ServiceState::Stopped => "stopped"
+        };
+        f.write_str(state)
+    }
+
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: ServiceState,
+    /// PID of the service inside the container, while it runs
+    pub pid: Option<i32>,
+    /// Times the service was started again
+    pub restarts: u32,
+    /// Exit status of the last run
+    pub last_status: Option<i32>
+}
+
+// Asked to the thread supervising a service
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Request {
+    Restart,
+    Stop
+}
+
+struct Supervised {
+    service: Service,
+    status: ServiceStatus,
+    request: Option<Request>
+}
+
+/// Starts the services from the init of the container, and keeps them running. Each service is
+/// supervised by a thread that restarts it according to its policy, waiting longer after each
+/// consecutive failure. The output of a service goes to its log file, along with the notes of
+/// the supervisor.
+pub struct Supervisor {
+    init: Init,
+    // Opened before chrooting, as the log files are outside the container
+    logs: File,
+    services: Mutex<Vec<Supervised>>,
+    // Notified when a service changes its state or gets a request
+    changed: Condvar
+}
+
+impl Supervisor {
+
+    /// Directory of the log files, inside the destination of the DevEnv
+    pub const LOG_DIR: &'static str = "services";
+
+    const SHELL: &'static str = "/bin/sh";
+
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    // A service that ran for this long is not failing repeatedly, so its backoff starts over
+    const BACKOFF_RESET: Duration = Duration::from_secs(30);
+
+    const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    // Seconds a service has to get ready, unless its readiness check says otherwise
+    const DEFAULT_READY_TIMEOUT: u64 = 60;
+
+    // Time a readiness command has to finish
+    const READY_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Log file of a service, for a DevEnv whose destination is `target`
+    pub fn log_file(target: &Path, name: &str) -> Result<PathBuf, Error> {
+        Supervisor::check_name(name)?;
+        Ok(target.join(Supervisor::LOG_DIR).join(format!("{}.log", name)))
+    }
+
+    /// Fail unless `name` can name a service, whose log file must stay in the log directory
+    pub fn check_name(name: &str) -> Result<(), Error> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(Error::new(format!("Invalid service name {:?}", name).as_str()));
+        }
+        Ok(())
+    }
+
+    /// Open the directory of the log files. Must be run BEFORE chrooting.
+    pub fn open_logs(target: &Path) -> Result<File, Error> {
+        let dir = target.join(Supervisor::LOG_DIR);
+        fs::create_dir_all(&dir)?;
+        let fd = open(&dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn new(init: Init, logs: File) -> Arc<Supervisor> {
+        Arc::new(Supervisor {
+            init,
+            logs,
+            services: Mutex::new(vec![]),
+            changed: Condvar::new()
+        })
+    }
+
+    pub fn handle(self: &Arc<Self>, command: ServiceCommand) -> TaskResult {
+        let result = match command {
+            ServiceCommand::Start(services) => self.start(services),
+            ServiceCommand::Status => Ok(()),
+            ServiceCommand::Restart(name) => self.restart(&name)
+        };
+        match result {
+            Ok(()) => TaskResult::Services(self.status()),
+            Err(e) => {
+                error!("{}", e);
+                TaskResult::from(e)
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<ServiceStatus> {
+        self.services.lock().unwrap().iter().map(|supervised| supervised.status.clone()).collect()
+    }
+
+    /// Start supervising `services`, and wait until each of them is ready or failed to start
+    fn start(self: &Arc<Self>, services: Vec<Service>) -> Result<(), Error> {
+        let names: Vec<String> = services.iter().map(|service| service.name.clone()).collect();
+        {
+            let mut supervised = self.services.lock().unwrap();
+            // Checked before adding any of them, so a rejected batch starts nothing
+            for (index, name) in names.iter().enumerate() {
+                Supervisor::check_name(name)?;
+                if names[..index].contains(name) {
+                    return Err(Error::new(format!("The service {} is declared more than once", name).as_str()));
+                }
+                if supervised.iter().any(|other| &other.service.name == name) {
+                    return Err(Error::new(format!("The service {} is already running", name).as_str()));
+                }
+            }
+            for service in services {
+                let status = ServiceStatus { name: service.name.clone(), state: ServiceState::Starting, pid: None, restarts: 0, last_status: None };
+                supervised.push(Supervised { service, status, request: None });
+            }
+        }
+        for name in &names {
+            let supervisor = self.clone();
+            let thread_name = name.clone();
+            let spawned = thread::Builder::new().name(format!("service {}", name)).spawn(move || supervisor.supervise(&thread_name));
+            if let Err(e) = spawned {
+                error!("Could not start the thread of service {}", name);
+                return Err(Error::from(e));
+            }
+        }
+        let mut supervised = self.services.lock().unwrap();
+        while supervised.iter().any(|service| names.contains(&service.status.name) && service.status.state == ServiceState::Starting) {
+            supervised = self.changed.wait(supervised).unwrap();
+        }
+        Ok(())
+    }
+
+    fn restart(&self, name: &str) -> Result<(), Error> {
+        let mut services = self.services.lock().unwrap();
+        let supervised = match services.iter_mut().find(|supervised| supervised.service.name == name) {
+            Some(supervised) => supervised,
+            None => return Err(Error::new(format!("Unknown service {}", name).as_str()))
+        };
+        debug!("Restarting service {}", name);
+        supervised.request = Some(Request::Restart);
+        if let Some(pid) = supervised.status.pid {
+            Supervisor::terminate(Pid::from_raw(pid), Signal::SIGTERM);
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Stop every service, killing the ones still running after `timeout`
+    pub fn stop_all(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut services = self.services.lock().unwrap();
+        for supervised in services.iter_mut() {
+            supervised.request = Some(Request::Stop);
+            if let Some(pid) = supervised.status.pid {
+                Supervisor::terminate(Pid::from_raw(pid), Signal::SIGTERM);
+            }
+        }
+        self.changed.notify_all();
+        while services.iter().any(|supervised| supervised.status.state != ServiceState::Stopped) {
+            let now = Instant::now();
+            if now >= deadline {
+                for pid in services.iter().filter_map(|supervised| supervised.status.pid) {
+                    warn!("Killing service with pid {}", pid);
+                    Supervisor::terminate(Pid::from_raw(pid), Signal::SIGKILL);
+                }
+                return;
+            }
+            services = self.changed.wait_timeout(services, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Run a service until it is asked to stop
+    fn supervise(&self, name: &str) {
+        let service = match self.services.lock().unwrap().iter().find(|supervised| supervised.service.name == name) {
+            Some(supervised) => supervised.service.clone(),
+            None => return
+        };
+        let policy = service.restart.unwrap_or(RestartPolicy::OnFailure);
+        let mut failures: u32 = 0;
+        loop {
+            let started = Instant::now();
+            let status = self.run(&service);
+            self.note(name, format!("exited with status {}", status).as_str());
+            let mut services = self.services.lock().unwrap();
+            let supervised = Supervisor::find(&mut services, name);
+            supervised.status.pid = None;
+            supervised.status.last_status = Some(status);
+            if started.elapsed() >= Supervisor::BACKOFF_RESET {
+                failures = 0;
+            }
+            let request = supervised.request.take();
+            let restart = match request {
+                Some(Request::Stop) => false,
+                Some(Request::Restart) => true,
+                None => match policy {
+                    RestartPolicy::Always => true,
+                    RestartPolicy::OnFailure => status != 0,
+                    RestartPolicy::Never => false
+                }
+            };
+            let backoff = if !restart {
+                supervised.status.state = ServiceState::Stopped;
+                None
+            }
+            else if request.is_none() {
+                let backoff = Supervisor::INITIAL_BACKOFF.checked_mul(1 << failures.min(16)).unwrap_or(Supervisor::MAX_BACKOFF).min(Supervisor::MAX_BACKOFF);
+                failures += 1;
+                supervised.status.state = ServiceState::BackingOff;
+                Some(backoff)
+            }
+            else {
+                failures = 0;
+                Some(Duration::from_secs(0))
+            };
+            self.changed.notify_all();
+            drop(services);
+            match backoff {
+                Some(backoff) if backoff.as_secs() > 0 => self.note(name, format!("restarting in {}s", backoff.as_secs()).as_str()),
+                Some(_) => self.note(name, "restarting"),
+                None => {}
+            }
+            match self.wait_for_request(name, backoff.map(|backoff| Instant::now() + backoff)) {
+                Some(Request::Stop) => {
+                    let mut services = self.services.lock().unwrap();
+                    Supervisor::find(&mut services, name).status.state = ServiceState::Stopped;
+                    self.changed.notify_all();
+                    return;
+                }
+                Some(Request::Restart) => failures = 0,
+                None => {}
+            }
+            let mut services = self.services.lock().unwrap();
+            let supervised = Supervisor::find(&mut services, name);
+            supervised.status.restarts += 1;
+            supervised.status.state = ServiceState::Starting;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Wait until a request for the service arrives, or until `deadline`. Without a deadline,
+    /// waits for a request.
+    fn wait_for_request(&self, name: &str, deadline: Option<Instant>) -> Option<Request> {
+        let mut services = self.services.lock().unwrap();
+        loop {
+            if let Some(request) = Supervisor::find(&mut services, name).request.take() {
+                return Some(request);
+            }
+            services = match deadline {
+                None => self.changed.wait(services).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.changed.wait_timeout(services, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Run the service once, and return its exit status
+    fn run(&self, service: &Service) -> i32 {
+        let pid = match self.spawn(service, &service.command) {
+            Ok(pid) => pid,
+            Err(e) => {
+                self.note(&service.name, format!("could not be started: {}", e).as_str());
+                return 127;
+            }
+        };
+        debug!("Started service {} with pid {}", service.name, pid);
+        {
+            let mut services = self.services.lock().unwrap();
+            let supervised = Supervisor::find(&mut services, &service.name);
+            supervised.status.pid = Some(pid.as_raw());
+            if service.ready.is_none() {
+                supervised.status.state = ServiceState::Running;
+                self.changed.notify_all();
+            }
+        }
+        if let Some(ready) = &service.ready {
+            if let Some(status) = self.wait_until_ready(service, ready, pid) {
+                return status;
+            }
+        }
+        self.init.wait_process(pid, None).unwrap_or(-1)
+    }
+
+    /// Run the readiness check until it passes. If the service exits first, returns its status.
+    /// A service that does not get ready in time is terminated.
+    fn wait_until_ready(&self, service: &Service, ready: &Readiness, pid: Pid) -> Option<i32> {
+        let timeout = Duration::from_secs(ready.timeout.unwrap_or(Supervisor::DEFAULT_READY_TIMEOUT));
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.init.wait_process(pid, Some(Supervisor::READY_POLL_INTERVAL)) {
+                return Some(status);
+            }
+            if self.services.lock().unwrap().iter().any(|supervised| supervised.service.name == service.name && supervised.request.is_some()) {
+                return None;
+            }
+            if self.is_ready(service, ready) {
+                self.note(&service.name, "is ready");
+                let mut services = self.services.lock().unwrap();
+                Supervisor::find(&mut services, &service.name).status.state = ServiceState::Running;
+                self.changed.notify_all();
+                return None;
+            }
+            if Instant::now() >= deadline {
+                self.note(&service.name, format!("was not ready after {:?}, terminating it", timeout).as_str());
+                Supervisor::terminate(pid, Signal::SIGTERM);
+                return None;
+            }
+        }
+    }
+
+    fn is_ready(&self, service: &Service, ready: &Readiness) -> bool {
+        if let Some(port) = ready.port {
+            let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+            if TcpStream::connect_timeout(&address, Supervisor::READY_POLL_INTERVAL).is_err() {
+                return false;
+            }
+        }
+        if let Some(command) = &ready.command {
+            let pid = match self.spawn(service, command) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    warn!("Could not run the readiness check of service {}: {}", service.name, e);
+                    return false;
+                }
+            };
+            match self.init.wait_process(pid, Some(Supervisor::READY_COMMAND_TIMEOUT)) {
+                Some(status) => return status == 0,
+                None => {
+                    Supervisor::terminate(pid, Signal::SIGKILL);
+                    self.init.wait_process(pid, None);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Run a command line in the background with the environment of the service, writing its
+    /// output to the log file of the service
+    fn spawn(&self, service: &Service, script: &str) -> Result<Pid, Error> {
+        let log = self.open_log(&service.name)?;
+        let shell = CString::new(Supervisor::SHELL).unwrap();
+        let flag = CString::new("-c").unwrap();
+        let script = match CString::new(script) {
+            Ok(script) => script,
+            Err(_) => return Err(Error::new("The command of the service contains a NUL byte"))
+        };
+        let args: Vec<&CStr> = vec![&shell, &flag, &script];
+        let env: Vec<(String, String)> = service.env.iter().flatten().map(|(key, value)| (key.clone(), value.clone())).collect();
+        let command = Command { filename: &shell, args: &args, env: &env, workdir: service.workdir.as_deref() };
+        // The log is closed on exec after being duplicated as the stdout and stderr of the service
+        self.init.start_process(&command, Some((log.as_raw_fd(), log.as_raw_fd())))
+    }
+
+    fn open_log(&self, name: &str) -> Result<File, Error> {
+        Supervisor::check_name(name)?;
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND | OFlag::O_CLOEXEC;
+        let fd = openat(self.logs.as_raw_fd(), format!("{}.log", name).as_str(), flags, Mode::from_bits_truncate(0o644))?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Write a note of the supervisor to the log file of a service
+    fn note(&self, name: &str, message: &str) {
+        debug!("Service {} {}", name, message);
+        let written = self.open_log(name).and_then(|mut log| Ok(writeln!(log, "[devenv] service {} {}", name, message)?));
+        if let Err(e) = written {
+            warn!("Could not write to the log of service {}: {}", name, e);
+        }
+    }
+
+    /// Send a signal to the process group of a service
+    fn terminate(pid: Pid, signal: Signal) {
+        match killpg(pid, signal) {
+            Ok(_) | Err(nix::Error::Sys(Errno::ESRCH)) => {}
+            Err(e) => warn!("Could not send {} to service {}: {}", signal, pid, e)
+        }
+    }
+
+    fn find<'a>(services: &'a mut MutexGuard<Vec<Supervised>>, name: &str) -> &'a mut Supervised {
+        services.iter_mut().find(|supervised| supervised.service.name == name).unwrap()
+    }
+
+}
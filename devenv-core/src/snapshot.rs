@@ -0,0 +1,184 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_derive::{Serialize, Deserialize};
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+
+use crate::filesystem::Filesystem;
+use crate::layers::copy_layer;
+
+/// Saved copy of the upper layer of a DevEnv
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    // Seconds since the Unix epoch
+    pub created: u64
+}
+
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+struct SnapshotInfo {
+    created: u64
+}
+
+/// Snapshots of a DevEnv, kept next to its layers so restoring one is a rename in the same
+/// filesystem.
+///
+/// Each snapshot is a directory with the copy of the upper layer and a `snapshot.toml` file.
+/// Snapshots are copied into a hidden directory first, so a snapshot that exists is complete.
+pub struct SnapshotStore {
+    dir: PathBuf
+}
+
+impl SnapshotStore {
+
+    const SNAPSHOTS_DIR: &'static str = "snapshots";
+
+    const UPPER_DIR: &'static str = "upper";
+
+    const INFO_FILE: &'static str = "snapshot.toml";
+
+    // Where a snapshot is copied before it replaces the upper layer
+    const RESTORE_DIR: &'static str = ".upper.restore";
+
+    pub fn new(target: &Path) -> SnapshotStore {
+        SnapshotStore {
+            dir: target.join(SnapshotStore::SNAPSHOTS_DIR)
+        }
+    }
+
+    /// Copy the upper layer of `fs` into a new snapshot
+    pub fn create(&self, name: &str, fs: &Filesystem) -> Result<Snapshot, Error> {
+        let path = self.path(name)?;
+        if path.exists() {
+            return Err(Error::new(format!("The snapshot {} already exists", name).as_str()));
+        }
+        let upper = fs.upper_path();
+        if !upper.is_dir() {
+            return Err(Error::new("The DevEnv has not been created yet"));
+        }
+        fs::create_dir_all(&self.dir)?;
+        let staging = self.dir.join(format!(".{}.tmp", name));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir(&staging)?;
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        debug!("Copying {:?} to {:?}", upper, staging);
+        if let Err(e) = SnapshotStore::fill(&staging, &upper, created) {
+            error!("Could not copy the upper layer to the snapshot {}", name);
+            if let Err(e) = fs::remove_dir_all(&staging) {
+                warn!("Could not remove {:?}: {}", staging, e);
+            }
+            return Err(e);
+        }
+        fs::rename(&staging, &path)?;
+        Ok(Snapshot { name: name.to_owned(), created })
+    }
+
+    fn fill(staging: &Path, upper: &Path, created: u64) -> Result<(), Error> {
+        copy_layer(upper, &staging.join(SnapshotStore::UPPER_DIR))?;
+        let contents = match toml::to_string(&SnapshotInfo { created }) {
+            Ok(contents) => contents,
+            Err(e) => return Err(Error::new_error("Could not serialize the snapshot information", Box::from(e)))
+        };
+        fs::write(staging.join(SnapshotStore::INFO_FILE), contents)?;
+        Ok(())
+    }
+
+    /// Snapshots sorted by name
+    pub fn list(&self) -> Result<Vec<Snapshot>, Error> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut snapshots = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Snapshots being created or deleted
+            if name.starts_with('.') || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let info = entry.path().join(SnapshotStore::INFO_FILE);
+            let created = match fs::read_to_string(&info).map(|contents| toml::from_str::<SnapshotInfo>(contents.as_str())) {
+                Ok(Ok(info)) => info.created,
+                _ => {
+                    error!("Could not read {:?}", info);
+                    0
+                }
+            };
+            snapshots.push(Snapshot { name, created });
+        }
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(snapshots)
+    }
+
+    /// Replace the upper layer of `fs` with a copy of a snapshot. The container must be stopped.
+    pub fn restore(&self, name: &str, fs: &Filesystem) -> Result<(), Error> {
+        let snapshot = self.path(name)?.join(SnapshotStore::UPPER_DIR);
+        if !snapshot.is_dir() {
+            return Err(Error::new(format!("The snapshot {} does not exist", name).as_str()));
+        }
+        // Next to the upper layer, to replace it with a rename
+        let staging = fs.target_path().join(SnapshotStore::RESTORE_DIR);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        debug!("Copying {:?} to {:?}", snapshot, staging);
+        if let Err(e) = copy_layer(&snapshot, &staging) {
+            error!("Could not copy the snapshot {}", name);
+            if let Err(e) = fs::remove_dir_all(&staging) {
+                warn!("Could not remove {:?}: {}", staging, e);
+            }
+            return Err(e);
+        }
+        fs.replace_upper(&staging)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), Error> {
+        let path = self.path(name)?;
+        if !path.exists() {
+            return Err(Error::new(format!("The snapshot {} does not exist", name).as_str()));
+        }
+        // Hide it first, so a partially deleted snapshot is not listed
+        let trash = self.dir.join(format!(".{}.tmp", name));
+        if trash.exists() {
+            fs::remove_dir_all(&trash)?;
+        }
+        fs::rename(&path, &trash)?;
+        fs::remove_dir_all(&trash)?;
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(Error::new(format!("Invalid snapshot name {:?}", name).as_str()));
+        }
+        Ok(self.dir.join(name))
+    }
+
+}
@@ -0,0 +1,535 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use directories::ProjectDirs;
+use serde::de::DeserializeOwned;
+use serde_derive::{Serialize, Deserialize};
+use log::{debug, error, warn};
+use devenv_common::error::Error;
+
+use crate::layers::{copy_upper_as_lower, disk_usage, RootView};
+use crate::oci::OciImage;
+use crate::registry::Environment;
+
+// Directory with the root of a layer or an image
+const ROOT_DIR: &str = "rootfs";
+
+/// Directory where devenv keeps the data shared by the DevEnvs of the user
+pub fn data_dir() -> Result<PathBuf, Error> {
+    match ProjectDirs::from("", "", "devenv") {
+        Some(dirs) => Ok(dirs.data_dir().to_path_buf()),
+        None => Err(Error::new("Could not find the data directory of the user"))
+    }
+}
+
+/// Seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+/// Whether `name` can name a layer or an image. Names are directories of the stores, and hidden
+/// directories are the entries being created.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.starts_with('.')
+}
+
+/// Create the entry `name` of the store at `dir`, with the root written by `fill` and the
+/// information it returns, which is saved to `info_file`. The entry is made in a hidden directory
+/// first, so an entry that exists is complete. An existing entry is replaced.
+fn add_entry<T: serde::Serialize>(dir: &Path, name: &str, info_file: &str, fill: impl FnOnce(&Path) -> Result<T, Error>) -> Result<T, Error> {
+    fs::create_dir_all(dir)?;
+    let staging = dir.join(format!(".{}.tmp", name));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+    let info = match fill_entry(&staging, info_file, fill) {
+        Ok(info) => info,
+        Err(e) => {
+            if let Err(e) = fs::remove_dir_all(&staging) {
+                warn!("Could not remove {:?}: {}", staging, e);
+            }
+            return Err(e);
+        }
+    };
+    let path = dir.join(name);
+    if path.exists() {
+        // Moved aside first, so the entry is never missing
+        let old = dir.join(format!(".{}.old", name));
+        fs::rename(&path, &old)?;
+        fs::rename(&staging, &path)?;
+        fs::remove_dir_all(&old)?;
+    }
+    else {
+        fs::rename(&staging, &path)?;
+    }
+    Ok(info)
+}
+
+fn fill_entry<T: serde::Serialize>(staging: &Path, info_file: &str, fill: impl FnOnce(&Path) -> Result<T, Error>) -> Result<T, Error> {
+    let info = fill(&staging.join(ROOT_DIR))?;
+    let contents = match toml::to_string(&info) {
+        Ok(contents) => contents,
+        Err(e) => return Err(Error::new_error("Could not serialize the information of the entry", Box::from(e)))
+    };
+    fs::write(staging.join(info_file), contents)?;
+    Ok(info)
+}
+
+fn read_info<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let contents = fs::read_to_string(path)?;
+    match toml::from_str(contents.as_str()) {
+        Ok(info) => Ok(info),
+        Err(e) => Err(Error::new_error(format!("Invalid information file {:?}", path).as_str(), Box::from(e)))
+    }
+}
+
+/// Names of the entries of the store at `dir`
+fn entry_names(dir: &Path) -> Result<Vec<String>, Error> {
+    let mut names = vec![];
+    if !dir.is_dir() {
+        return Ok(names);
+    }
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_valid_name(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Remove the directory `path` of an entry. It is hidden first, so an entry that is partly
+/// removed is not taken for a complete one.
+fn remove_entry(path: &Path) -> Result<(), Error> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let removed = path.with_file_name(format!(".{}.old", name));
+    fs::rename(path, &removed)?;
+    fs::remove_dir_all(&removed)?;
+    Ok(())
+}
+
+/// Read-only layer made from the changes of a DevEnv with `devenv commit`
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    /// Directory used as a lower layer of the overlay
+    pub path: PathBuf,
+    /// Layers the DevEnv was using when the layer was committed, from the bottom one. The
+    /// layer only makes sense on top of them.
+    pub parents: Vec<PathBuf>,
+    /// Hash of the configuration of the DevEnv
+    pub config_hash: String,
+    /// Seconds since the Unix epoch
+    pub created: u64
+}
+
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+struct LayerInfo {
+    parents: Vec<PathBuf>,
+    config_hash: String,
+    created: u64
+}
+
+/// Layers committed by the user, kept in its data directory (e.g. `~/.local/share/devenv`) so
+/// they can be used by the other DevEnvs.
+///
+/// Each layer is a directory with the root of the layer and a `layer.toml` file.
+pub struct LayerStore {
+    dir: PathBuf
+}
+
+impl LayerStore {
+
+    const LAYERS_DIR: &'static str = "layers";
+
+    const INFO_FILE: &'static str = "layer.toml";
+
+    pub fn new() -> Result<LayerStore, Error> {
+        Ok(LayerStore {
+            dir: data_dir()?.join(LayerStore::LAYERS_DIR)
+        })
+    }
+
+    /// Whether a reference to an image or a layer in the configuration names a committed layer
+    /// instead of being a path
+    pub fn is_layer_name(reference: &str) -> bool {
+        !reference.contains('/')
+    }
+
+    /// Turn the upper layer at `upper` into a new layer. `parents` are the layers below it.
+    pub fn commit(&self, name: &str, upper: &Path, parents: &[PathBuf], config_hash: &str) -> Result<Layer, Error> {
+        if !upper.is_dir() {
+            return Err(Error::new("The DevEnv has not been created yet"));
+        }
+        debug!("Copying {:?} to the layer {}", upper, name);
+        self.add(name, parents, config_hash, |root| copy_upper_as_lower(upper, root))
+    }
+
+    /// Add a layer whose root is written by `fill`, which gets a directory that does not exist yet
+    pub fn add(&self, name: &str, parents: &[PathBuf], config_hash: &str, fill: impl FnOnce(&Path) -> Result<(), Error>) -> Result<Layer, Error> {
+        let path = self.path(name)?;
+        if path.exists() {
+            return Err(Error::new(format!("The layer {} already exists", name).as_str()));
+        }
+        let info = LayerInfo {
+            parents: parents.to_vec(),
+            config_hash: config_hash.to_owned(),
+            created: now()
+        };
+        let info = match add_entry(&self.dir, name, LayerStore::INFO_FILE, |root| fill(root).map(|_| info)) {
+            Ok(info) => info,
+            Err(e) => {
+                error!("Could not create the layer {}", name);
+                return Err(e);
+            }
+        };
+        Ok(LayerStore::layer(name, &path, info))
+    }
+
+    pub fn get(&self, name: &str) -> Result<Layer, Error> {
+        let path = self.path(name)?;
+        let info_path = path.join(LayerStore::INFO_FILE);
+        if !info_path.exists() {
+            return Err(Error::new(format!("The layer {} does not exist", name).as_str()));
+        }
+        Ok(LayerStore::layer(name, &path, read_info(&info_path)?))
+    }
+
+    pub fn list(&self) -> Result<Vec<Layer>, Error> {
+        let mut layers = vec![];
+        for name in entry_names(&self.dir)? {
+            match self.get(&name) {
+                Ok(layer) => layers.push(layer),
+                Err(e) => warn!("Skipping the layer {}: {}", name, e)
+            }
+        }
+        Ok(layers)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let layer = self.get(name)?;
+        remove_entry(layer.path.parent().unwrap())
+    }
+
+    /// Layers to stack for a committed layer, from the bottom one: its parents, then the layer
+    pub fn resolve(&self, name: &str) -> Result<Vec<PathBuf>, Error> {
+        let layer = self.get(name)?;
+        let mut layers = layer.parents;
+        layers.push(layer.path);
+        Ok(layers)
+    }
+
+    fn layer(name: &str, path: &Path, info: LayerInfo) -> Layer {
+        Layer {
+            name: name.to_owned(),
+            path: path.join(ROOT_DIR),
+            parents: info.parents,
+            config_hash: info.config_hash,
+            created: info.created
+        }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if !is_valid_name(name) {
+            return Err(Error::new(format!("Invalid layer name {:?}", name).as_str()));
+        }
+        Ok(self.dir.join(name))
+    }
+
+}
+
+/// Root filesystem built with `devenv image build`
+#[derive(Debug, Clone)]
+pub struct StoredImage {
+    pub name: String,
+    /// Directory used as the bottom layer of the overlay
+    pub path: PathBuf,
+    /// Release of the distribution, e.g. `bookworm`
+    pub suite: String,
+    /// Archive the packages were downloaded from
+    pub mirror: String,
+    pub architecture: String,
+    /// Installed packages, as `name=version`
+    pub packages: Vec<String>,
+    /// Seconds since the Unix epoch
+    pub created: u64
+}
+
+/// How an image was built, saved along with its root
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub suite: String,
+    pub mirror: String,
+    pub architecture: String,
+    pub packages: Vec<String>,
+    pub created: u64
+}
+
+/// Images built by the user, kept in its data directory next to the layers, so any configuration
+/// can use them by name.
+///
+/// Each image is a directory with its root filesystem and an `image.toml` file.
+pub struct ImageStore {
+    dir: PathBuf
+}
+
+impl ImageStore {
+
+    const IMAGES_DIR: &'static str = "images";
+
+    const INFO_FILE: &'static str = "image.toml";
+
+    pub fn new() -> Result<ImageStore, Error> {
+        Ok(ImageStore {
+            dir: data_dir()?.join(ImageStore::IMAGES_DIR)
+        })
+    }
+
+    /// Add an image whose root is written by `build`, which gets a directory that does not exist
+    /// yet. An image with the same name is replaced once the new one is built.
+    pub fn add(&self, name: &str, build: impl FnOnce(&Path) -> Result<ImageInfo, Error>) -> Result<StoredImage, Error> {
+        let path = self.path(name)?;
+        let info = add_entry(&self.dir, name, ImageStore::INFO_FILE, |root| {
+            let mut info = build(root)?;
+            info.created = now();
+            Ok(info)
+        });
+        match info {
+            Ok(info) => Ok(ImageStore::image(name, &path, info)),
+            Err(e) => {
+                error!("Could not build the image {}", name);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<StoredImage, Error> {
+        let path = self.path(name)?;
+        let info_path = path.join(ImageStore::INFO_FILE);
+        if !info_path.exists() {
+            return Err(Error::new(format!("The image {} does not exist, build it with `devenv image build`", name).as_str()));
+        }
+        Ok(ImageStore::image(name, &path, read_info(&info_path)?))
+    }
+
+    pub fn list(&self) -> Result<Vec<StoredImage>, Error> {
+        let mut images = vec![];
+        for name in entry_names(&self.dir)? {
+            match self.get(&name) {
+                Ok(image) => images.push(image),
+                Err(e) => warn!("Skipping the image {}: {}", name, e)
+            }
+        }
+        Ok(images)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let image = self.get(name)?;
+        remove_entry(image.path.parent().unwrap())
+    }
+
+    fn image(name: &str, path: &Path, info: ImageInfo) -> StoredImage {
+        StoredImage {
+            name: name.to_owned(),
+            path: path.join(ROOT_DIR),
+            suite: info.suite,
+            mirror: info.mirror,
+            architecture: info.architecture,
+            packages: info.packages,
+            created: info.created
+        }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if !is_valid_name(name) {
+            return Err(Error::new(format!("Invalid image name {:?}", name).as_str()));
+        }
+        Ok(self.dir.join(name))
+    }
+
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    /// Built with `devenv image build`
+    Image,
+    /// Committed with `devenv commit`, or imported
+    Layer,
+    /// Unpacked from an OCI image or an archive made by `docker save`
+    OciLayer
+}
+
+impl fmt::Display for EntryKind {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::Image => write!(f, "image"),
+            EntryKind::Layer => write!(f, "layer"),
+            EntryKind::OciLayer => write!(f, "oci-layer")
+        }
+    }
+
+}
+
+/// An image or a layer kept in the data directory of the user
+#[derive(Debug, Clone)]
+pub struct StoreEntry {
+    pub kind: EntryKind,
+    /// Name of the image or the layer, or the digest of a layer of an OCI image
+    pub name: String,
+    /// Directory stacked as a layer of the overlay
+    pub path: PathBuf,
+    /// Layers it is stacked on, from the bottom one
+    pub parents: Vec<PathBuf>,
+    /// Seconds since the Unix epoch
+    pub created: u64
+}
+
+/// What is known about an entry of the stores
+#[derive(Debug)]
+pub struct EntryDetails {
+    pub entry: StoreEntry,
+    /// `PRETTY_NAME` of the os-release file of the entry, along with the layers below it
+    pub os_release: Option<String>,
+    /// Bytes used by the entry
+    pub size: u64,
+    /// Bytes used by the entry and the layers below it that are kept in the stores
+    pub total_size: u64,
+    /// Directories of the registered DevEnvs that use the entry
+    pub environments: Vec<PathBuf>,
+    /// How the entry was bootstrapped, for built images
+    pub image: Option<StoredImage>
+}
+
+/// All the images and layers of the user: the built images, the committed layers and the
+/// cache of the layers of OCI images
+pub struct Stores {
+    images: ImageStore,
+    layers: LayerStore,
+    oci_cache: PathBuf
+}
+
+impl Stores {
+
+    pub fn new() -> Result<Stores, Error> {
+        Ok(Stores {
+            images: ImageStore::new()?,
+            layers: LayerStore::new()?,
+            oci_cache: OciImage::layer_cache()?
+        })
+    }
+
+    pub fn entries(&self) -> Result<Vec<StoreEntry>, Error> {
+        let mut entries: Vec<StoreEntry> = vec![];
+        for image in self.images.list()? {
+            entries.push(StoreEntry { kind: EntryKind::Image, name: image.name, path: image.path, parents: vec![], created: image.created });
+        }
+        for layer in self.layers.list()? {
+            entries.push(StoreEntry { kind: EntryKind::Layer, name: layer.name, path: layer.path, parents: layer.parents, created: layer.created });
+        }
+        for (digest, path) in OciImage::cached_layers(&self.oci_cache)? {
+            let created = fs::metadata(&path)?.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs())
+                .unwrap_or(0);
+            entries.push(StoreEntry { kind: EntryKind::OciLayer, name: digest, path, parents: vec![], created });
+        }
+        Ok(entries)
+    }
+
+    /// Find an entry by its name, or by the digest or a prefix of the digest of an OCI layer.
+    /// Images are looked for first, unless `kind` is given.
+    pub fn find(&self, name: &str, kind: Option<EntryKind>) -> Result<StoreEntry, Error> {
+        let entries = self.entries()?;
+        let matches: Vec<&StoreEntry> = entries.iter()
+            .filter(|entry| kind.is_none_or(|kind| entry.kind == kind))
+            .filter(|entry| match entry.kind {
+                EntryKind::OciLayer => entry.name == name || entry.name.split(':').nth(1).is_some_and(|hex| hex.starts_with(name)),
+                _ => entry.name == name
+            })
+            .collect();
+        match matches.as_slice() {
+            [] => Err(Error::new(format!("There is no image or layer {}", name).as_str())),
+            [entry] => Ok((*entry).clone()),
+            [entry, ..] if entry.name == name => Ok((*entry).clone()),
+            _ => Err(Error::new(format!("Several layers match {}", name).as_str()))
+        }
+    }
+
+    pub fn remove(&self, entry: &StoreEntry) -> Result<(), Error> {
+        match entry.kind {
+            EntryKind::Image => self.images.remove(&entry.name),
+            EntryKind::Layer => self.layers.remove(&entry.name),
+            EntryKind::OciLayer => remove_entry(&entry.path)
+        }
+    }
+
+    /// Gather the details of an entry. `environments` are the registered DevEnvs, and `entries`
+    /// all the entries, to add up the size of the ones below.
+    pub fn details(&self, entry: &StoreEntry, environments: &[Environment], entries: &[StoreEntry]) -> Result<EntryDetails, Error> {
+        let size = disk_usage(&entry.path)?;
+        let mut total_size = size;
+        for parent in &entry.parents {
+            if entries.iter().any(|other| other.path == *parent) {
+                total_size += disk_usage(parent)?;
+            }
+        }
+        let mut chain = entry.parents.clone();
+        chain.push(entry.path.clone());
+        let image = match entry.kind {
+            EntryKind::Image => Some(self.images.get(&entry.name)?),
+            _ => None
+        };
+        Ok(EntryDetails {
+            entry: entry.clone(),
+            os_release: Stores::os_release(&chain),
+            size,
+            total_size,
+            environments: environments.iter().filter(|environment| environment.layers.contains(&entry.path)).map(|environment| environment.target.clone()).collect(),
+            image
+        })
+    }
+
+    /// Pretty name of the system of the layers `chain`, from the bottom one
+    fn os_release(chain: &[PathBuf]) -> Option<String> {
+        let (upper, lowers) = chain.split_last()?;
+        let view = RootView::Layers { upper: upper.clone(), lowers: lowers.iter().rev().cloned().collect() };
+        let contents = ["/etc/os-release", "/usr/lib/os-release"].iter()
+            .filter_map(|path| view.resolve(Path::new(path), true).ok())
+            .filter_map(|path| view.lookup(&path))
+            .find_map(|path| fs::read_to_string(path).ok())?;
+        contents.lines()
+            .filter_map(|line| line.strip_prefix("PRETTY_NAME="))
+            .map(|value| value.trim().trim_matches('"').trim_matches('\'').to_owned())
+            .next()
+    }
+
+}
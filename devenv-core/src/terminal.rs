@@ -0,0 +1,139 @@
+/**
+ * The MIT License
+ * Copyright (c) 2020 Guillem Castro
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+use std::os::unix::io::RawFd;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{openpty, Winsize};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::unistd::{read, write};
+use log::warn;
+use devenv_common::error::Error;
+
+/// Whether devenv runs in a terminal, in which case the foreground command of the container gets
+/// a terminal of its own
+pub fn is_interactive() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::isatty(1) == 1 }
+}
+
+/// Pseudo-terminal of the foreground command, with the size of the terminal of devenv. Returns
+/// the master and the slave, which are not inherited by the commands executed afterwards.
+pub fn open_pty() -> Result<(RawFd, RawFd), Error> {
+    let size = window_size(0);
+    let pty = openpty(size.as_ref(), None)?;
+    for fd in &[pty.master, pty.slave] {
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    Ok((pty.master, pty.slave))
+}
+
+fn window_size(fd: RawFd) -> Option<Winsize> {
+    let mut size = Winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+        0 => Some(size),
+        _ => None
+    }
+}
+
+/// Give the pseudo-terminal `master` the size of the terminal of devenv, which sends SIGWINCH to
+/// its foreground process group
+pub fn resize(master: RawFd) {
+    if let Some(size) = window_size(0) {
+        if unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &size) } < 0 {
+            warn!("Could not resize the terminal: {}", Errno::last());
+        }
+    }
+}
+
+/// Copy the input of devenv to the pseudo-terminal `master`, and its output to the output of
+/// devenv, until every process using the terminal is gone
+pub fn relay(master: RawFd) -> Result<(), Error> {
+    let mut buffer = [0u8; 4096];
+    let mut input_open = true;
+    loop {
+        let mut fds = [PollFd::new(master, PollFlags::POLLIN), PollFd::new(if input_open { 0 } else { -1 }, PollFlags::POLLIN)];
+        match poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => return Err(Error::from(e))
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|events| !events.is_empty());
+        if ready(&fds[0]) {
+            match read(master, &mut buffer) {
+                Ok(0) | Err(nix::Error::Sys(Errno::EIO)) => return Ok(()),
+                Ok(length) => write_all(1, &buffer[..length])?,
+                Err(nix::Error::Sys(Errno::EINTR)) | Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+                Err(e) => return Err(Error::from(e))
+            }
+        }
+        if ready(&fds[1]) {
+            match read(0, &mut buffer) {
+                Ok(0) => input_open = false,
+                Ok(length) => write_all(master, &buffer[..length])?,
+                Err(nix::Error::Sys(Errno::EINTR)) | Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+                Err(_) => input_open = false
+            }
+        }
+    }
+}
+
+fn write_all(fd: RawFd, mut data: &[u8]) -> Result<(), Error> {
+    while !data.is_empty() {
+        match write(fd, data) {
+            Ok(written) => data = &data[written..],
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(e) => return Err(Error::from(e))
+        }
+    }
+    Ok(())
+}
+
+/// The terminal of devenv in raw mode, so the keys reach the terminal of the foreground command
+/// as they are typed. Its previous mode is restored when dropped.
+pub struct RawMode {
+    original: Termios
+}
+
+impl RawMode {
+
+    pub fn enable() -> Result<RawMode, Error> {
+        let original = tcgetattr(0)?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(0, SetArg::TCSADRAIN, &raw)?;
+        Ok(RawMode { original })
+    }
+
+}
+
+impl Drop for RawMode {
+
+    fn drop(&mut self) {
+        if let Err(e) = tcsetattr(0, SetArg::TCSADRAIN, &self.original) {
+            warn!("Could not restore the terminal: {}", e);
+        }
+    }
+
+}
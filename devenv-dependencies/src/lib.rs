@@ -30,13 +30,16 @@ use provider::DependencyProvider;
 pub mod provider;
 mod apt;
 
-pub fn resolve_dependencies(dependencies: Vec<Dependency>) -> Result<(), Error> {
+/// Resolve the given dependencies, returning the matches found for each one of them
+pub fn resolve_dependencies(dependencies: Vec<Dependency>) -> Result<Vec<Dependency>, Error> {
     let apt = apt::APTProvider::new();
+    let mut resolved: Vec<Dependency> = vec![];
     debug!("Dependencies to resolve: {:?}", dependencies);
     for dependency in dependencies {
         debug!("{} {} {}", dependency.provider().unwrap(), dependency.package().unwrap(), dependency.version().unwrap());
         let deps = apt.search(&dependency)?;
         debug!("{:?}", deps);
+        resolved.extend(deps);
     }
-    Ok(())
+    Ok(resolved)
 }
\ No newline at end of file